@@ -0,0 +1,255 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+use std::{fmt, fs, io};
+
+use ipnet::IpNet;
+use libp2p::{multiaddr::Protocol, relay::RateLimiter, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// Access rules of the relay server, loaded from a TOML file.
+///
+/// `destination` rules apply to reservation requests, and to the destination of every circuit
+/// so that a reloaded policy also applies to the reservations made before.
+/// `source` rules apply to the peers opening circuits.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessPolicy {
+    pub source: Rules,
+    pub destination: Rules,
+}
+
+/// Deny entries always win, a non-empty allow list denies every peer it does not match.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rules {
+    pub allow_peers: Vec<PeerId>,
+    pub deny_peers: Vec<PeerId>,
+    pub allow_cidrs: Vec<IpNet>,
+    pub deny_cidrs: Vec<IpNet>,
+}
+
+impl Rules {
+    pub fn allows(&self, peer: &PeerId, ip: Option<IpAddr>) -> bool {
+        let in_cidrs = |cidrs: &[IpNet]| ip.is_some_and(|ip| cidrs.iter().any(|n| n.contains(&ip)));
+
+        if self.deny_peers.contains(peer) || in_cidrs(&self.deny_cidrs) {
+            return false;
+        }
+
+        if self.allow_peers.is_empty() && self.allow_cidrs.is_empty() {
+            return true;
+        }
+
+        self.allow_peers.contains(peer) || in_cidrs(&self.allow_cidrs)
+    }
+}
+
+impl AccessPolicy {
+    pub fn load(path: &Path) -> Result<Self, AclError> {
+        let content = fs::read_to_string(path).map_err(AclError::Io)?;
+        toml::from_str(&content).map_err(|e| AclError::Parse(e.to_string()))
+    }
+}
+
+#[derive(Debug)]
+pub enum AclError {
+    Io(io::Error),
+    Parse(String),
+}
+
+impl fmt::Display for AclError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AclError::Io(e) => write!(f, "read relay acl: {e}"),
+            AclError::Parse(e) => write!(f, "parse relay acl: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for AclError {}
+
+/// The access policy of a running relay, shared with the limiters handed to `relay::Config`.
+#[derive(Clone)]
+pub struct RelayAcl {
+    path: PathBuf,
+    policy: Arc<RwLock<AccessPolicy>>,
+}
+
+impl RelayAcl {
+    pub fn load(path: PathBuf) -> Result<Self, AclError> {
+        let policy = AccessPolicy::load(&path)?;
+        Ok(RelayAcl {
+            path,
+            policy: Arc::new(RwLock::new(policy)),
+        })
+    }
+
+    /// Re-read the policy file, the current rules are kept if it is invalid.
+    pub fn reload(&self) -> Result<(), AclError> {
+        let policy = AccessPolicy::load(&self.path)?;
+        *self.policy.write().expect("acl lock poisoned") = policy;
+        info!(path = ?self.path, "relay acl reloaded");
+        Ok(())
+    }
+
+    /// Limiter checking reservation requests against the `destination` rules.
+    pub fn reservation_limiter(&self) -> Box<dyn RateLimiter> {
+        Box::new(AclLimiter {
+            policy: self.policy.clone(),
+            kind: RequestKind::Reservation,
+        })
+    }
+
+    /// Limiter checking circuit requests against the `source` rules.
+    pub fn circuit_limiter(&self) -> Box<dyn RateLimiter> {
+        Box::new(AclLimiter {
+            policy: self.policy.clone(),
+            kind: RequestKind::Circuit,
+        })
+    }
+
+    /// Whether the `destination` rules allow a circuit to `peer`, connected to the relay at
+    /// `addr`.
+    pub fn allows_destination(&self, peer: PeerId, addr: &Multiaddr) -> bool {
+        allows(&self.policy, RequestKind::CircuitDestination, peer, addr)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum RequestKind {
+    Reservation,
+    Circuit,
+    CircuitDestination,
+}
+
+fn allows(
+    policy: &RwLock<AccessPolicy>,
+    kind: RequestKind,
+    peer: PeerId,
+    addr: &Multiaddr,
+) -> bool {
+    let ip = addr.iter().find_map(|p| match p {
+        Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+        Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+        _ => None,
+    });
+
+    let policy = policy.read().expect("acl lock poisoned");
+    let allowed = match kind {
+        RequestKind::Reservation | RequestKind::CircuitDestination => {
+            policy.destination.allows(&peer, ip)
+        }
+        RequestKind::Circuit => policy.source.allows(&peer, ip),
+    };
+
+    if !allowed {
+        warn!(?peer, ?ip, ?kind, "relay request denied by acl");
+    }
+
+    allowed
+}
+
+/// Rejecting a request from a [`RateLimiter`] makes the relay deny it. A limiter only sees the
+/// requesting peer, so the destination of circuits is checked by the relay behaviour instead.
+struct AclLimiter {
+    policy: Arc<RwLock<AccessPolicy>>,
+    kind: RequestKind,
+}
+
+impl RateLimiter for AclLimiter {
+    fn try_next(&mut self, peer: PeerId, addr: &Multiaddr, _now: Instant) -> bool {
+        allows(&self.policy, self.kind, peer, addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(ip: &str) -> Multiaddr {
+        Multiaddr::from(ip.parse::<IpAddr>().unwrap()).with(Protocol::Tcp(4001))
+    }
+
+    /// A policy file in a new directory, removed with its content when dropped.
+    fn temp_policy(content: &str) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("acl.toml");
+        fs::write(&path, content).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn empty_rules_allow_everyone() {
+        let rules = Rules::default();
+        assert!(rules.allows(&PeerId::random(), None));
+        assert!(rules.allows(&PeerId::random(), Some("192.0.2.1".parse().unwrap())));
+    }
+
+    #[test]
+    fn allow_list_denies_everyone_else() {
+        let allowed = PeerId::random();
+        let rules = Rules {
+            allow_peers: vec![allowed],
+            allow_cidrs: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Default::default()
+        };
+
+        assert!(rules.allows(&allowed, None));
+        assert!(rules.allows(&PeerId::random(), Some("10.1.2.3".parse().unwrap())));
+        assert!(!rules.allows(&PeerId::random(), Some("192.0.2.1".parse().unwrap())));
+        assert!(!rules.allows(&PeerId::random(), None));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let peer = PeerId::random();
+        let rules = Rules {
+            allow_peers: vec![peer],
+            deny_cidrs: vec!["192.0.2.0/24".parse().unwrap()],
+            ..Default::default()
+        };
+        assert!(rules.allows(&peer, Some("198.51.100.1".parse().unwrap())));
+        assert!(!rules.allows(&peer, Some("192.0.2.1".parse().unwrap())));
+
+        let rules = Rules {
+            allow_cidrs: vec!["192.0.2.0/24".parse().unwrap()],
+            deny_peers: vec![peer],
+            ..Default::default()
+        };
+        assert!(!rules.allows(&peer, Some("192.0.2.1".parse().unwrap())));
+    }
+
+    #[test]
+    fn limiters_check_their_own_rules() {
+        let denied = PeerId::random();
+        let (_dir, policy) = temp_policy(&format!("[destination]\ndeny_peers = [\"{denied}\"]\n"));
+        let acl = RelayAcl::load(policy.clone()).unwrap();
+
+        let now = Instant::now();
+        let mut reservations = acl.reservation_limiter();
+        let mut circuits = acl.circuit_limiter();
+        assert!(!reservations.try_next(denied, &addr("192.0.2.1"), now));
+        assert!(circuits.try_next(denied, &addr("192.0.2.1"), now));
+        assert!(!acl.allows_destination(denied, &addr("192.0.2.1")));
+        assert!(acl.allows_destination(PeerId::random(), &addr("192.0.2.1")));
+    }
+
+    #[test]
+    fn failed_reload_keeps_the_current_rules() {
+        let denied = PeerId::random();
+        let (_dir, policy) = temp_policy(&format!("[source]\ndeny_peers = [\"{denied}\"]\n"));
+        let acl = RelayAcl::load(policy.clone()).unwrap();
+        let mut circuits = acl.circuit_limiter();
+
+        fs::write(&policy, "[source]\nunknown_field = true\n").unwrap();
+        assert!(matches!(acl.reload(), Err(AclError::Parse(_))));
+        assert!(!circuits.try_next(denied, &addr("192.0.2.1"), Instant::now()));
+
+        fs::write(&policy, "").unwrap();
+        acl.reload().unwrap();
+        assert!(circuits.try_next(denied, &addr("192.0.2.1"), Instant::now()));
+    }
+}
@@ -1,17 +1,18 @@
 use libp2p::{
-    identify, ping, relay,
+    identify, ping,
     swarm::{behaviour::toggle::Toggle, NetworkBehaviour},
 };
 
-mod autonat;
-mod direct_client;
+pub(crate) mod autonat;
+pub(crate) mod direct_client;
 mod kad;
+pub(crate) mod relay;
 
 #[derive(NetworkBehaviour)]
 pub struct Behaviour {
     pub kad: Toggle<kad::Behaviour>,
     pub relay: Toggle<relay::Behaviour>,
-    pub relay_client: relay::client::Behaviour,
+    pub relay_client: libp2p::relay::client::Behaviour,
     pub dcutr: Toggle<direct_client::Behaviour>,
     pub autonat: autonat::Behaviour,
     pub ping: ping::Behaviour,
@@ -10,15 +10,25 @@ use libp2p::{
     Multiaddr, PeerId,
 };
 
-use crate::is_holepunch_direct_addr;
+use crate::transport::HolePunchAddrs;
 
 pub struct Behaviour {
     inner: autonat::Behaviour,
+    holepunch: HolePunchAddrs,
 }
 
-impl From<autonat::Behaviour> for Behaviour {
-    fn from(value: autonat::Behaviour) -> Self {
-        Behaviour { inner: value }
+impl Behaviour {
+    pub fn new(inner: autonat::Behaviour, holepunch: HolePunchAddrs) -> Self {
+        Behaviour { inner, holepunch }
+    }
+
+    pub fn nat_status(&self) -> autonat::NatStatus {
+        self.inner.nat_status()
+    }
+
+    /// Probe `addr` too, which autonat confirms as external address if it is reachable.
+    pub fn probe_address(&mut self, addr: Multiaddr) {
+        self.inner.probe_address(addr)
     }
 }
 
@@ -79,7 +89,8 @@ impl NetworkBehaviour for Behaviour {
 
     fn on_swarm_event(&mut self, event: FromSwarm) {
         if let FromSwarm::NewExternalAddrCandidate(addr) = event {
-            if is_holepunch_direct_addr(addr.addr) {
+            // the hole punch listener only accepts connections from peers it dials itself.
+            if self.holepunch.is_holepunch_addr(addr.addr) {
                 return;
             }
         }
@@ -1,31 +1,136 @@
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime};
 
 use libp2p::{
     core::Endpoint,
     dcutr,
     swarm::{
-        ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler, THandlerInEvent,
-        THandlerOutEvent, ToSwarm,
+        ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, NewExternalAddrCandidate,
+        THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
     },
     Multiaddr, PeerId,
 };
 use tracing::info;
 
-use crate::is_holepunch_direct_addr;
+use crate::hole_punch::{DialAttempt, HolePunchReport};
+use crate::is_quic_addr;
+use crate::transport::HolePunchAddrs;
+
+/// Candidates kept for the reports, as many as DCUtR sends.
+const MAX_CANDIDATES: usize = 20;
+/// Finished reports kept, the oldest are dropped first.
+const MAX_REPORTS: usize = 64;
+
+#[derive(Debug)]
+pub enum Event {
+    HolePunch(dcutr::Event),
+    /// The report of a hole punch whose outcome is known, or whose relayed connection closed.
+    Report(Box<HolePunchReport>),
+}
 
 pub struct Behaviour {
     inner: dcutr::Behaviour,
+    holepunch: HolePunchAddrs,
+    /// Dials of the hole punch attempts, whose TCP addresses must be dialed from the hole punch
+    /// listener.
+    punch_dials: HashSet<ConnectionId>,
+    /// The candidates passed to DCUtR, the most recent first.
+    candidates: VecDeque<Multiaddr>,
+    /// The reports of the relayed connections not upgraded yet, by peer.
+    reports: HashMap<PeerId, HolePunchReport>,
+    /// Hole punch dials in progress.
+    pending_dials: HashMap<ConnectionId, (PeerId, Instant)>,
+    /// The open connections established by a hole punch.
+    punched: HashSet<ConnectionId>,
+    finished: VecDeque<HolePunchReport>,
+    events: VecDeque<Event>,
 }
 
-impl From<dcutr::Behaviour> for Behaviour {
-    fn from(value: dcutr::Behaviour) -> Self {
-        Behaviour { inner: value }
+impl Behaviour {
+    pub fn new(inner: dcutr::Behaviour, holepunch: HolePunchAddrs) -> Self {
+        Behaviour {
+            inner,
+            holepunch,
+            punch_dials: Default::default(),
+            candidates: Default::default(),
+            reports: Default::default(),
+            pending_dials: Default::default(),
+            punched: Default::default(),
+            finished: Default::default(),
+            events: Default::default(),
+        }
+    }
+
+    /// The finished reports, oldest first, followed by those in progress.
+    pub fn reports(&self) -> impl Iterator<Item = &HolePunchReport> {
+        self.finished.iter().chain(self.reports.values())
+    }
+
+    /// Whether a connection was established by a hole punch.
+    pub fn is_hole_punched(&self, connection_id: ConnectionId) -> bool {
+        self.punched.contains(&connection_id)
+    }
+
+    /// Send `addrs` as candidates too, the most likely first, e.g. the ports a symmetric NAT is
+    /// predicted to map next or a port mapped by the router. The remote tries them all when
+    /// dialing this node.
+    pub fn add_candidates(&mut self, addrs: &[Multiaddr]) {
+        // DCUtR sends its most recent candidates first.
+        for addr in addrs.iter().rev() {
+            if !self.holepunch.allows_candidate(addr) {
+                continue;
+            }
+            self.add_candidate(addr);
+            self.inner
+                .on_swarm_event(FromSwarm::NewExternalAddrCandidate(
+                    NewExternalAddrCandidate { addr },
+                ));
+        }
+    }
+
+    fn add_candidate(&mut self, addr: &Multiaddr) {
+        self.candidates.retain(|a| a != addr);
+        self.candidates.push_front(addr.clone());
+        self.candidates.truncate(MAX_CANDIDATES);
+    }
+
+    fn on_dial_done(&mut self, connection_id: ConnectionId, error: Option<String>) {
+        let Some((peer, started)) = self.pending_dials.remove(&connection_id) else {
+            return;
+        };
+        let Some(report) = self.reports.get_mut(&peer) else {
+            return;
+        };
+        if let Some(dial) = report
+            .dials
+            .iter_mut()
+            .find(|d| d.connection_id == connection_id)
+        {
+            dial.duration = Some(started.elapsed());
+            dial.error = error;
+        }
+    }
+
+    /// Move the report of `peer` to the finished ones.
+    fn finish(&mut self, peer: PeerId, outcome: Option<Result<ConnectionId, String>>) {
+        let Some(mut report) = self.reports.remove(&peer) else {
+            return;
+        };
+        report.outcome = outcome;
+        self.pending_dials.retain(|_, (p, _)| *p != peer);
+
+        if self.finished.len() == MAX_REPORTS {
+            self.finished.pop_front();
+        }
+        self.finished.push_back(report.clone());
+        self.events.push_back(Event::Report(Box::new(report)));
     }
 }
 
 impl NetworkBehaviour for Behaviour {
     type ConnectionHandler = <dcutr::Behaviour as NetworkBehaviour>::ConnectionHandler;
-    type ToSwarm = <dcutr::Behaviour as NetworkBehaviour>::ToSwarm;
+    type ToSwarm = Event;
 
     fn handle_pending_inbound_connection(
         &mut self,
@@ -59,6 +164,28 @@ impl NetworkBehaviour for Behaviour {
         addresses: &[Multiaddr],
         effective_role: Endpoint,
     ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        if self.punch_dials.remove(&connection_id) {
+            self.holepunch.add_dial_targets(addresses);
+
+            if let Some(report) = maybe_peer.and_then(|peer| self.reports.get_mut(&peer)) {
+                for addr in addresses {
+                    if !report.remote_addrs.contains(addr) {
+                        report.remote_addrs.push(addr.clone());
+                    }
+                }
+                report.dials.push(DialAttempt {
+                    connection_id,
+                    addrs: addresses.to_vec(),
+                    as_listener: effective_role == Endpoint::Listener,
+                    started_at: SystemTime::now(),
+                    duration: None,
+                    error: None,
+                });
+                self.pending_dials
+                    .insert(connection_id, (report.remote_peer_id, Instant::now()));
+            }
+        }
+
         self.inner.handle_pending_outbound_connection(
             connection_id,
             maybe_peer,
@@ -79,12 +206,58 @@ impl NetworkBehaviour for Behaviour {
     }
 
     fn on_swarm_event(&mut self, event: FromSwarm) {
-        if let FromSwarm::NewExternalAddrCandidate(addr) = event {
-            if !is_holepunch_direct_addr(addr.addr) {
-                return;
-            }
+        match event {
+            FromSwarm::NewExternalAddrCandidate(addr) => {
+                // QUIC reuses its listening socket for every dial, so its observed addresses are
+                // suitable for hole punching as they are.
+                if !self.holepunch.is_holepunch_addr(addr.addr) && !is_quic_addr(addr.addr) {
+                    return;
+                }
+                if !self.holepunch.allows_candidate(addr.addr) {
+                    info!(?addr.addr, "ignoring a non-public candidate for direct connection");
+                    return;
+                }
 
-            info!(?addr.addr, "new candidate for direct connection");
+                info!(?addr.addr, "new candidate for direct connection");
+                self.add_candidate(addr.addr);
+            }
+            FromSwarm::ConnectionEstablished(established) if established.endpoint.is_relayed() => {
+                // a new relayed connection replaces the previous attempt.
+                self.finish(established.peer_id, None);
+                self.reports.insert(
+                    established.peer_id,
+                    HolePunchReport {
+                        remote_peer_id: established.peer_id,
+                        relayed_connection_id: established.connection_id,
+                        started_at: SystemTime::now(),
+                        local_addrs: self.candidates.iter().cloned().collect(),
+                        remote_addrs: Vec::new(),
+                        dials: Vec::new(),
+                        outcome: None,
+                    },
+                );
+            }
+            FromSwarm::ConnectionEstablished(established)
+                if self.pending_dials.contains_key(&established.connection_id) =>
+            {
+                self.punched.insert(established.connection_id);
+                self.on_dial_done(established.connection_id, None);
+                self.finish(established.peer_id, Some(Ok(established.connection_id)));
+            }
+            FromSwarm::DialFailure(failure) => {
+                self.on_dial_done(failure.connection_id, Some(failure.error.to_string()));
+            }
+            FromSwarm::ConnectionClosed(closed) => {
+                self.punched.remove(&closed.connection_id);
+                let closes_attempt = self
+                    .reports
+                    .get(&closed.peer_id)
+                    .is_some_and(|r| r.relayed_connection_id == closed.connection_id);
+                if closes_attempt {
+                    self.finish(closed.peer_id, None);
+                }
+            }
+            _ => {}
         }
 
         self.inner.on_swarm_event(event)
@@ -104,6 +277,26 @@ impl NetworkBehaviour for Behaviour {
         &mut self,
         cx: &mut Context<'_>,
     ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
-        self.inner.poll(cx)
+        if let Some(event) = self.events.pop_front() {
+            return Poll::Ready(ToSwarm::GenerateEvent(event));
+        }
+
+        match self.inner.poll(cx) {
+            Poll::Ready(ToSwarm::GenerateEvent(event)) => {
+                let outcome = match &event.result {
+                    Ok(connection_id) => Ok(*connection_id),
+                    Err(e) => Err(e.to_string()),
+                };
+                self.finish(event.remote_peer_id, Some(outcome));
+                Poll::Ready(ToSwarm::GenerateEvent(Event::HolePunch(event)))
+            }
+            Poll::Ready(event) => {
+                if let ToSwarm::Dial { opts } = &event {
+                    self.punch_dials.insert(opts.connection_id());
+                }
+                Poll::Ready(event.map_out(Event::HolePunch))
+            }
+            Poll::Pending => Poll::Pending,
+        }
     }
 }
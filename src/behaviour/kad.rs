@@ -4,7 +4,6 @@ use either::Either;
 use libp2p::{
     core::Endpoint,
     kad::{self, store::MemoryStore},
-    multiaddr::Protocol,
     swarm::{
         dummy, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler,
         THandlerInEvent, THandlerOutEvent, ToSwarm,
@@ -12,9 +11,7 @@ use libp2p::{
     Multiaddr, PeerId,
 };
 
-fn is_relayed(addr: &Multiaddr) -> bool {
-    addr.iter().any(|p| p == Protocol::P2pCircuit)
-}
+use crate::relay_manager::is_relayed;
 
 pub struct Behaviour {
     inner: kad::Behaviour<MemoryStore>,
@@ -106,10 +103,9 @@ impl NetworkBehaviour for Behaviour {
         connection_id: ConnectionId,
         event: THandlerOutEvent<Self>,
     ) {
-        if let Either::Left(event) = event {
-            self.inner
-                .on_connection_handler_event(peer_id, connection_id, event)
-        }
+        let Either::Left(event) = event;
+        self.inner
+            .on_connection_handler_event(peer_id, connection_id, event)
     }
 
     fn poll(
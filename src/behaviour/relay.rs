@@ -0,0 +1,179 @@
+use std::collections::{HashMap, VecDeque};
+use std::task::{Context, Poll};
+
+use either::Either;
+use libp2p::{
+    core::Endpoint,
+    relay,
+    swarm::{
+        ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, NotifyHandler, THandler,
+        THandlerInEvent, THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId,
+};
+
+use crate::acl::RelayAcl;
+
+/// The events of the relay connection handler, whose module libp2p-relay keeps private.
+type HandlerEvent = <THandlerOutEvent<relay::Behaviour> as EitherLeft>::Left;
+type HandlerIn = <THandlerInEvent<relay::Behaviour> as EitherLeft>::Left;
+
+trait EitherLeft {
+    type Left;
+}
+
+impl<L, R> EitherLeft for Either<L, R> {
+    type Left = L;
+}
+
+/// The relay server, denying circuits to the destinations the ACL does not allow.
+///
+/// The rate limiters of `relay::Config` only see the peer requesting a circuit.
+pub struct Behaviour {
+    inner: relay::Behaviour,
+    acl: Option<RelayAcl>,
+    /// The remote addresses of the direct connections, whose IPs the ACL checks.
+    addrs: HashMap<PeerId, HashMap<ConnectionId, Multiaddr>>,
+    denials: VecDeque<ToSwarm<relay::Event, THandlerInEvent<relay::Behaviour>>>,
+}
+
+impl Behaviour {
+    pub fn new(inner: relay::Behaviour, acl: Option<RelayAcl>) -> Self {
+        Behaviour {
+            inner,
+            acl,
+            addrs: Default::default(),
+            denials: Default::default(),
+        }
+    }
+
+    fn allows_destination(&self, peer: PeerId) -> bool {
+        let Some(acl) = self.acl.as_ref() else {
+            return true;
+        };
+        let addr = self
+            .addrs
+            .get(&peer)
+            .and_then(|addrs| addrs.values().next())
+            .cloned()
+            .unwrap_or_else(Multiaddr::empty);
+        acl.allows_destination(peer, &addr)
+    }
+}
+
+impl NetworkBehaviour for Behaviour {
+    type ConnectionHandler = <relay::Behaviour as NetworkBehaviour>::ConnectionHandler;
+    type ToSwarm = relay::Event;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        self.inner
+            .handle_pending_inbound_connection(connection_id, local_addr, remote_addr)
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner.handle_established_inbound_connection(
+            connection_id,
+            peer,
+            local_addr,
+            remote_addr,
+        )
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        self.inner.handle_pending_outbound_connection(
+            connection_id,
+            maybe_peer,
+            addresses,
+            effective_role,
+        )
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner
+            .handle_established_outbound_connection(connection_id, peer, addr, role_override)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        match event {
+            FromSwarm::ConnectionEstablished(established) if !established.endpoint.is_relayed() => {
+                self.addrs.entry(established.peer_id).or_default().insert(
+                    established.connection_id,
+                    established.endpoint.get_remote_address().clone(),
+                );
+            }
+            FromSwarm::ConnectionClosed(closed) => {
+                if let Some(addrs) = self.addrs.get_mut(&closed.peer_id) {
+                    addrs.remove(&closed.connection_id);
+                    if addrs.is_empty() {
+                        self.addrs.remove(&closed.peer_id);
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        self.inner.on_swarm_event(event)
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {
+            Either::Left(HandlerEvent::CircuitReqReceived {
+                inbound_circuit_req,
+                ..
+            }) if !self.allows_destination(inbound_circuit_req.dst()) => {
+                // the handler reports the denial back, which the relay turns into an event.
+                self.denials.push_back(ToSwarm::NotifyHandler {
+                    peer_id,
+                    handler: NotifyHandler::One(connection_id),
+                    event: Either::Left(HandlerIn::DenyCircuitReq {
+                        circuit_id: None,
+                        inbound_circuit_req,
+                        status: "PERMISSION_DENIED".into(),
+                    }),
+                });
+            }
+            event => self
+                .inner
+                .on_connection_handler_event(peer_id, connection_id, event),
+        }
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        if let Some(denial) = self.denials.pop_front() {
+            return Poll::Ready(denial);
+        }
+
+        self.inner.poll(cx)
+    }
+}
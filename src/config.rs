@@ -0,0 +1,562 @@
+use std::net::SocketAddr;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{fmt, fs, io};
+
+use libp2p::{relay, Multiaddr};
+use serde::{Deserialize, Serialize};
+
+use crate::identity::KeyType;
+
+/// Everything that can be set in a `node.toml` file.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub identity: IdentityConfig,
+    pub bootstrap: BootstrapConfig,
+    pub network: NetworkConfig,
+    pub websocket: WebSocketConfig,
+    pub webrtc: WebRtcConfig,
+    pub relay: RelayConfig,
+    pub dcutr: DcutrConfig,
+    pub kad: KadConfig,
+    pub identify: IdentifyConfig,
+    pub autonat: AutonatConfig,
+    pub port_mapping: PortMappingConfig,
+    pub metrics: MetricsConfig,
+    pub control: ControlConfig,
+    pub events: EventsConfig,
+}
+
+impl Config {
+    /// Parse the TOML file at `path`.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let content = fs::read_to_string(path).map_err(ConfigError::Io)?;
+        toml::from_str(&content).map_err(|e| ConfigError::Parse(e.to_string()))
+    }
+
+    /// Render the configuration as TOML.
+    pub fn to_toml(&self) -> Result<String, ConfigError> {
+        toml::to_string_pretty(self).map_err(|e| ConfigError::Parse(e.to_string()))
+    }
+
+    /// The part of the configuration used by the node stack itself.
+    pub fn node(&self) -> NodeConfig {
+        NodeConfig {
+            network: self.network.clone(),
+            websocket: self.websocket.clone(),
+            webrtc: self.webrtc.clone(),
+            relay: self.relay.clone(),
+            dcutr: self.dcutr.clone(),
+            kad: self.kad.clone(),
+            identify: self.identify.clone(),
+            autonat: self.autonat.clone(),
+            port_mapping: self.port_mapping.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "read config: {e}"),
+            ConfigError::Parse(e) => write!(f, "parse config: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentityConfig {
+    /// Path of the protobuf-encoded identity keypair, created on first run.
+    pub path: Option<PathBuf>,
+    /// Algorithm of the identity key.
+    pub key_type: KeyType,
+    /// INSECURE: fixed value to generate a deterministic peer id, for testing only.
+    pub seed: Option<u8>,
+}
+
+/// Peers to reach once the node is up.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BootstrapConfig {
+    pub connect: Vec<Multiaddr>,
+    /// Dialed once identify has been received from one of the `connect` peers. A bare
+    /// `/p2p/<peer id>` is resolved through kademlia or the known relays.
+    pub peer: Option<Multiaddr>,
+    /// `key:value` record stored each time the kademlia routing table is updated.
+    pub kad_put: Option<String>,
+    /// Key looked up each time the kademlia routing table is updated.
+    pub kad_get: Option<String>,
+}
+
+/// Options of the node stack.
+#[derive(Debug, Clone, Default)]
+pub struct NodeConfig {
+    pub network: NetworkConfig,
+    pub websocket: WebSocketConfig,
+    pub webrtc: WebRtcConfig,
+    pub relay: RelayConfig,
+    pub dcutr: DcutrConfig,
+    pub kad: KadConfig,
+    pub identify: IdentifyConfig,
+    pub autonat: AutonatConfig,
+    pub port_mapping: PortMappingConfig,
+    pub metrics: MetricsConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    /// The port used to listen on all interfaces, over TCP and QUIC if enabled.
+    pub listen_port: u16,
+    /// Listen on UDP `listen_port` with QUIC and dial `/quic-v1` addresses. Off by default, the
+    /// node only uses TCP unless enabled.
+    pub quic: bool,
+    /// Listen on `::` next to `0.0.0.0`, with the same ports.
+    pub ipv6: bool,
+    /// Additional addresses to listen on, e.g. `/ip4/0.0.0.0/udp/4001/quic-v1`.
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Addresses the node is known to be reachable at, e.g. behind a forwarded port. They are
+    /// announced to peers and in relay reservations without autonat confirmation.
+    pub external_addrs: Vec<Multiaddr>,
+    /// The node runs on a public network, so observed loopback and private addresses are no
+    /// candidates for hole punching.
+    pub public: bool,
+    /// Dial TCP from the listen port, so that peers observe the mapping of a single local port.
+    /// Without QUIC this is needed to tell whether the NAT is symmetric.
+    pub tcp_port_reuse: bool,
+    /// Run on the in-process memory transport instead of the network, for tests. Only
+    /// `listen_addrs`, e.g. `/memory/1234`, are listened on.
+    pub memory: bool,
+    /// The port used for hole punching, DCUtR is disabled if not set.
+    pub dcutr_port: Option<u16>,
+    /// Timeout of the security and multiplexing upgrades of a connection.
+    #[serde(with = "humantime_serde")]
+    pub upgrade_timeout: Duration,
+    /// Idle connections are kept forever if not set.
+    #[serde(with = "humantime_serde")]
+    pub idle_connection_timeout: Option<Duration>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            listen_port: 0,
+            quic: false,
+            ipv6: true,
+            listen_addrs: Vec::new(),
+            external_addrs: Vec::new(),
+            public: false,
+            tcp_port_reuse: false,
+            memory: false,
+            dcutr_port: None,
+            upgrade_timeout: Duration::from_secs(2),
+            idle_connection_timeout: None,
+        }
+    }
+}
+
+/// WebSocket listeners, for clients unable to use raw TCP. `/ws` addresses can always be dialed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketConfig {
+    /// Port of the `/ws` listener. Disabled if not set.
+    pub port: Option<u16>,
+    /// Port of the `/wss` listener, requires `cert` and `key`. Disabled if not set.
+    pub tls_port: Option<u16>,
+    /// PEM certificate chain of the `/wss` listener. Dialers check it against the webpki roots,
+    /// so dialing `/wss` is left untested: the tests only listen with a self-signed certificate.
+    pub cert: Option<PathBuf>,
+    /// PEM private key of the `/wss` listener, PKCS#8, PKCS#1 or SEC1.
+    pub key: Option<PathBuf>,
+}
+
+/// WebRTC-direct listener, reachable from browsers.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebRtcConfig {
+    /// UDP port of the `/webrtc-direct` listener. Disabled if not set.
+    pub port: Option<u16>,
+    /// PEM certificate of the listener, created on first run. Its hash is part of the listen
+    /// addresses, so without it they change on every start.
+    pub cert: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayConfig {
+    /// Act as a relay server for other peers.
+    pub service: bool,
+    /// Listen through relay servers found with identify and kademlia.
+    pub listen_relayed: bool,
+    /// Number of relays listened through at the same time, preferring the lowest ping RTT.
+    pub relayed_listeners: usize,
+    /// Access control list of the relay server, reloaded on SIGHUP. Its `destination` rules are
+    /// checked on reservations and again on every circuit.
+    pub acl: Option<PathBuf>,
+
+    /// Maximum number of reservations held by the relay server.
+    pub max_reservations: usize,
+    /// Maximum number of reservations held by the relay server for a single peer, one per
+    /// connection of the peer.
+    pub max_reservations_per_peer: usize,
+    #[serde(with = "humantime_serde")]
+    pub reservation_duration: Duration,
+    pub reservation_rate_per_peer: RateLimit,
+    pub reservation_rate_per_ip: RateLimit,
+
+    /// Maximum number of circuits relayed at the same time.
+    pub max_circuits: usize,
+    /// Maximum number of circuits relayed at the same time from or to a single peer.
+    pub max_circuits_per_peer: usize,
+    #[serde(with = "humantime_serde")]
+    pub max_circuit_duration: Duration,
+    /// Maximum number of bytes relayed per circuit and direction.
+    pub max_circuit_bytes: u64,
+    pub circuit_src_rate_per_peer: RateLimit,
+    pub circuit_src_rate_per_ip: RateLimit,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        // same limits as the stock `relay::Config`.
+        RelayConfig {
+            service: false,
+            listen_relayed: false,
+            relayed_listeners: 2,
+            acl: None,
+
+            max_reservations: 128,
+            max_reservations_per_peer: 4,
+            reservation_duration: Duration::from_secs(60 * 60),
+            reservation_rate_per_peer: RateLimit::new(30, Duration::from_secs(60 * 2)),
+            reservation_rate_per_ip: RateLimit::new(60, Duration::from_secs(60)),
+
+            max_circuits: 16,
+            max_circuits_per_peer: 4,
+            max_circuit_duration: Duration::from_secs(2 * 60),
+            max_circuit_bytes: 1 << 17,
+            circuit_src_rate_per_peer: RateLimit::new(30, Duration::from_secs(60 * 2)),
+            circuit_src_rate_per_ip: RateLimit::new(60, Duration::from_secs(60)),
+        }
+    }
+}
+
+impl RelayConfig {
+    /// Options of the relay server behaviour.
+    pub fn server_config(&self) -> relay::Config {
+        let mut cfg = relay::Config {
+            max_reservations: self.max_reservations,
+            max_reservations_per_peer: self.max_reservations_per_peer,
+            reservation_duration: self.reservation_duration,
+            reservation_rate_limiters: Vec::new(),
+
+            max_circuits: self.max_circuits,
+            max_circuits_per_peer: self.max_circuits_per_peer,
+            max_circuit_duration: self.max_circuit_duration,
+            max_circuit_bytes: self.max_circuit_bytes,
+            circuit_src_rate_limiters: Vec::new(),
+        };
+
+        if let Some(limit) = NonZeroU32::new(self.reservation_rate_per_peer.limit) {
+            cfg = cfg.reservation_rate_per_peer(limit, self.reservation_rate_per_peer.interval);
+        }
+        if let Some(limit) = NonZeroU32::new(self.reservation_rate_per_ip.limit) {
+            cfg = cfg.reservation_rate_per_ip(limit, self.reservation_rate_per_ip.interval);
+        }
+        if let Some(limit) = NonZeroU32::new(self.circuit_src_rate_per_peer.limit) {
+            cfg = cfg.circuit_src_per_peer(limit, self.circuit_src_rate_per_peer.interval);
+        }
+        if let Some(limit) = NonZeroU32::new(self.circuit_src_rate_per_ip.limit) {
+            cfg = cfg.circuit_src_per_ip(limit, self.circuit_src_rate_per_ip.interval);
+        }
+
+        cfg
+    }
+}
+
+/// Allow `limit` requests, refilled at one every `interval`.
+///
+/// A `limit` of 0 disables the rate limiter, the `interval` can not be 0. Written as
+/// `LIMIT/INTERVAL` on the command line, e.g. `30/2m`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "RateLimitFields")]
+pub struct RateLimit {
+    pub limit: u32,
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+}
+
+impl RateLimit {
+    pub fn new(limit: u32, interval: Duration) -> Self {
+        RateLimit { limit, interval }
+    }
+}
+
+/// A [`RateLimit`] as written in the configuration file, before it is checked.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RateLimitFields {
+    limit: u32,
+    #[serde(with = "humantime_serde")]
+    interval: Duration,
+}
+
+impl TryFrom<RateLimitFields> for RateLimit {
+    type Error = String;
+
+    fn try_from(fields: RateLimitFields) -> Result<Self, Self::Error> {
+        // the relay server panics on a zero refill interval.
+        if fields.interval.is_zero() {
+            return Err("the interval of a rate limit can not be 0".to_string());
+        }
+        Ok(RateLimit::new(fields.limit, fields.interval))
+    }
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (limit, interval) = s
+            .split_once('/')
+            .ok_or_else(|| format!("expected LIMIT/INTERVAL, got `{s}`"))?;
+        let limit = limit
+            .trim()
+            .parse()
+            .map_err(|e| format!("invalid limit `{limit}`: {e}"))?;
+        let interval = humantime::parse_duration(interval.trim())
+            .map_err(|e| format!("invalid interval `{interval}`: {e}"))?;
+        RateLimit::try_from(RateLimitFields { limit, interval })
+    }
+}
+
+/// What happens to a relayed connection around DCUtR hole punching, which is enabled by
+/// `network.dcutr_port`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DcutrConfig {
+    /// Hole punches retried after a failed one, through a new relayed connection. The failed
+    /// relayed connection is kept.
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every further one.
+    #[serde(with = "humantime_serde")]
+    pub retry_backoff: Duration,
+    /// Close the relayed connections to a peer once a direct connection to it is established.
+    pub close_relayed: bool,
+}
+
+impl Default for DcutrConfig {
+    fn default() -> Self {
+        DcutrConfig {
+            retries: 3,
+            retry_backoff: Duration::from_secs(10),
+            close_relayed: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KadConfig {
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentifyConfig {
+    pub protocol_version: String,
+}
+
+impl Default for IdentifyConfig {
+    fn default() -> Self {
+        IdentifyConfig {
+            protocol_version: "/RelayDemo/0.0.1".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AutonatConfig {
+    /// Number of confirmations required before the NAT status is considered certain.
+    pub confidence_max: usize,
+    /// Delay before the first probe.
+    #[serde(with = "humantime_serde")]
+    pub boot_delay: Duration,
+    /// Interval of the probes while the NAT status is not certain.
+    #[serde(with = "humantime_serde")]
+    pub retry_interval: Duration,
+    /// Minimum interval between two probes through the same server.
+    #[serde(with = "humantime_serde")]
+    pub throttle_server_period: Duration,
+}
+
+impl Default for AutonatConfig {
+    fn default() -> Self {
+        AutonatConfig {
+            confidence_max: 1,
+            boot_delay: Duration::from_secs(15),
+            retry_interval: Duration::from_secs(90),
+            throttle_server_period: Duration::from_secs(90),
+        }
+    }
+}
+
+/// Port mappings requested from the router for the listen port and `network.dcutr_port`, with
+/// UPnP IGD or else NAT-PMP. The mapped listen address is announced once autonat confirms it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PortMappingConfig {
+    pub upnp: bool,
+    pub nat_pmp: bool,
+    /// Where UPnP gateways are searched for, the SSDP multicast address if not set.
+    pub upnp_search_addr: Option<SocketAddr>,
+    /// The NAT-PMP server, port 5351 of the default gateway if not set.
+    pub nat_pmp_gateway: Option<SocketAddr>,
+    /// Lifetime requested for the mappings, which are renewed halfway through it. Can not be 0,
+    /// which NAT-PMP takes as the removal of a mapping.
+    #[serde(
+        serialize_with = "humantime_serde::serialize",
+        deserialize_with = "deserialize_lease"
+    )]
+    pub lease_duration: Duration,
+}
+
+/// Parse a port mapping lease, e.g. `1h`, rejecting a zero lease.
+pub fn parse_lease(s: &str) -> Result<Duration, String> {
+    let lease = humantime::parse_duration(s).map_err(|e| format!("invalid lease `{s}`: {e}"))?;
+    check_lease(lease)
+}
+
+fn check_lease(lease: Duration) -> Result<Duration, String> {
+    match lease.is_zero() {
+        true => Err("the port mapping lease can not be 0".to_string()),
+        false => Ok(lease),
+    }
+}
+
+fn deserialize_lease<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let lease = humantime_serde::deserialize(deserializer)?;
+    check_lease(lease).map_err(serde::de::Error::custom)
+}
+
+impl PortMappingConfig {
+    pub fn enabled(&self) -> bool {
+        self.upnp || self.nat_pmp
+    }
+}
+
+impl Default for PortMappingConfig {
+    fn default() -> Self {
+        PortMappingConfig {
+            upnp: false,
+            nat_pmp: false,
+            upnp_search_addr: None,
+            nat_pmp_gateway: None,
+            lease_duration: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address of the Prometheus endpoint, served at `/metrics`. Disabled if not set.
+    pub addr: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ControlConfig {
+    /// Unix domain socket of the JSON-RPC control API. Disabled if not set.
+    pub socket: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventsConfig {
+    /// File the node events are appended to as JSON lines, `-` for stdout. Disabled if not set.
+    pub json: Option<PathBuf>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limit_is_parsed() {
+        assert_eq!(
+            "30/2m".parse(),
+            Ok(RateLimit::new(30, Duration::from_secs(2 * 60)))
+        );
+        assert_eq!(
+            " 5 / 1s ".parse(),
+            Ok(RateLimit::new(5, Duration::from_secs(1)))
+        );
+        // a zero limit disables the limiter.
+        assert_eq!(
+            "0/1m".parse(),
+            Ok(RateLimit::new(0, Duration::from_secs(60)))
+        );
+    }
+
+    #[test]
+    fn malformed_rate_limits_are_rejected() {
+        for s in [
+            "",
+            "30",
+            "30/",
+            "/2m",
+            "-1/2m",
+            "x/2m",
+            "30/2",
+            "30/forever",
+            "30/0s",
+        ] {
+            assert!(s.parse::<RateLimit>().is_err(), "`{s}`");
+        }
+    }
+
+    #[test]
+    fn zero_lease_is_rejected() {
+        assert_eq!(parse_lease("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert!(parse_lease("0s").is_err());
+        assert!(parse_lease("soon").is_err());
+
+        assert!(toml::from_str::<Config>("[port_mapping]\nlease_duration = \"0s\"\n").is_err());
+        let config = toml::from_str::<Config>("[port_mapping]\nlease_duration = \"2h\"\n").unwrap();
+        assert_eq!(
+            config.port_mapping.lease_duration,
+            Duration::from_secs(2 * 60 * 60)
+        );
+    }
+
+    #[test]
+    fn zero_interval_is_rejected_in_the_config_file() {
+        let toml = "[relay]\nreservation_rate_per_peer = { limit = 30, interval = \"0s\" }\n";
+        assert!(toml::from_str::<Config>(toml).is_err());
+
+        let toml = "[relay]\nreservation_rate_per_peer = { limit = 30, interval = \"2m\" }\n";
+        let config = toml::from_str::<Config>(toml).unwrap();
+        assert_eq!(
+            config.relay.reservation_rate_per_peer,
+            RateLimit::new(30, Duration::from_secs(2 * 60))
+        );
+    }
+}
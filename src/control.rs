@@ -0,0 +1,552 @@
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::Path;
+use std::time::SystemTime;
+use std::{fmt, fs, io};
+
+use libp2p::{core::ConnectedPoint, multiaddr::Protocol, swarm::ConnectionId, Multiaddr, PeerId};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{debug, info, warn};
+
+use crate::events::{HolePunchReportInfo, NatStatusInfo, PortMappingInfo};
+use crate::{connection_number, NodeHandle, RelayedListener, ReservationState};
+
+// JSON-RPC 2.0 error codes.
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+/// The node refused or failed to carry out the request.
+pub const NODE_ERROR: i64 = -32000;
+
+/// Requests larger than this are rejected, the connection is closed.
+const MAX_REQUEST_LEN: u64 = 64 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    jsonrpc: String,
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Response {
+    jsonrpc: String,
+    id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+impl Response {
+    fn result(id: Value, result: Value) -> Self {
+        Response {
+            jsonrpc: "2.0".to_string(),
+            id,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    fn error(id: Value, error: RpcError) -> Self {
+        Response {
+            jsonrpc: "2.0".to_string(),
+            id,
+            result: None,
+            error: Some(error),
+        }
+    }
+}
+
+/// The `error` member of a JSON-RPC response.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl fmt::Display) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+/// A peer currently connected to the node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub peer_id: PeerId,
+    pub connections: usize,
+}
+
+/// An open connection of the node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    pub peer_id: PeerId,
+    pub connection_id: usize,
+    /// `dialer` or `listener`.
+    pub endpoint: String,
+    pub remote_addr: Multiaddr,
+    /// Only known for inbound connections.
+    pub local_addr: Option<Multiaddr>,
+    pub relayed: bool,
+}
+
+impl ConnectionInfo {
+    pub fn new(peer_id: PeerId, connection_id: ConnectionId, endpoint: &ConnectedPoint) -> Self {
+        let (kind, remote_addr, local_addr) = match endpoint {
+            ConnectedPoint::Dialer { address, .. } => ("dialer", address.clone(), None),
+            ConnectedPoint::Listener {
+                local_addr,
+                send_back_addr,
+            } => ("listener", send_back_addr.clone(), Some(local_addr.clone())),
+        };
+
+        ConnectionInfo {
+            peer_id,
+            connection_id: connection_number(connection_id),
+            endpoint: kind.to_string(),
+            remote_addr,
+            local_addr,
+            relayed: endpoint.is_relayed(),
+        }
+    }
+}
+
+/// A listener reached through a relay, with the state of its reservation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayedListenerInfo {
+    pub listener_id: String,
+    pub relay_peer_id: PeerId,
+    pub addr: Multiaddr,
+    /// `pending` or `accepted`.
+    pub state: String,
+    /// Times are RFC 3339 in UTC.
+    pub requested_at: String,
+    pub accepted_at: Option<String>,
+    pub renewals: u32,
+    /// Estimated, unknown until the reservation was renewed once.
+    pub expires_at: Option<String>,
+}
+
+impl From<RelayedListener> for RelayedListenerInfo {
+    fn from(listener: RelayedListener) -> Self {
+        let time = |t: SystemTime| humantime::format_rfc3339_seconds(t).to_string();
+        let state = match listener.state {
+            ReservationState::Pending => "pending",
+            ReservationState::Accepted => "accepted",
+        };
+
+        RelayedListenerInfo {
+            listener_id: listener.listener_id.to_string(),
+            relay_peer_id: listener.relay_peer_id,
+            addr: listener.addr,
+            state: state.to_string(),
+            requested_at: time(listener.requested_at),
+            accepted_at: listener.accepted_at.map(time),
+            renewals: listener.renewals,
+            expires_at: listener.expires_at.map(time),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AddrParams {
+    addr: Multiaddr,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PeerParams {
+    peer_id: PeerId,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HolePunchReportsParams {
+    peer_id: Option<PeerId>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListenRelayedParams {
+    relay: Multiaddr,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ListenerParams {
+    listener_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CloseConnectionParams {
+    connection_id: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KadGetParams {
+    key: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KadPutParams {
+    key: String,
+    value: String,
+}
+
+/// Bind the control socket at `path`, replacing a stale socket left by a previous run.
+///
+/// The socket is only accessible to the user running the node, anything else at `path` is left
+/// untouched and fails the bind.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Ok(_) => match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("control socket {} is in use", path.display()),
+                ))
+            }
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+            Err(_) => {}
+        },
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Answer the JSON-RPC requests received on `listener` until the node shuts down, then remove
+/// the socket file.
+///
+/// Requests and responses are JSON objects, one per line.
+pub async fn serve(listener: UnixListener, handle: NodeHandle) {
+    let path = listener
+        .local_addr()
+        .ok()
+        .and_then(|addr| addr.as_pathname().map(Path::to_path_buf));
+    info!(?path, "serving control api");
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    let handle = handle.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_client(stream, handle).await {
+                            debug!(err = ?e, "control connection failed");
+                        }
+                    });
+                }
+                Err(e) => warn!(err = ?e, "accept control connection"),
+            },
+            _ = handle.closed() => break,
+        }
+    }
+
+    if let Some(path) = path {
+        if let Err(e) = fs::remove_file(&path) {
+            warn!(err = %e, ?path, "remove control socket");
+        }
+    }
+}
+
+async fn handle_client(stream: UnixStream, handle: NodeHandle) -> io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        let n = (&mut read)
+            .take(MAX_REQUEST_LEN)
+            .read_line(&mut line)
+            .await?;
+        if n == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') && n as u64 == MAX_REQUEST_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request too long",
+            ));
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Value>(&line) {
+            Ok(value) => handle_request(&handle, value).await,
+            Err(e) => Response::error(Value::Null, RpcError::new(PARSE_ERROR, e)),
+        };
+
+        let mut out = serde_json::to_vec(&response)?;
+        out.push(b'\n');
+        write.write_all(&out).await?;
+    }
+}
+
+async fn handle_request(handle: &NodeHandle, value: Value) -> Response {
+    let id = value.get("id").cloned().unwrap_or(Value::Null);
+    let request = match serde_json::from_value::<Request>(value) {
+        Ok(request) if request.jsonrpc == "2.0" => request,
+        Ok(request) => {
+            let msg = format!("unsupported jsonrpc version `{}`", request.jsonrpc);
+            return Response::error(id, RpcError::new(INVALID_REQUEST, msg));
+        }
+        Err(e) => return Response::error(id, RpcError::new(INVALID_REQUEST, e)),
+    };
+
+    debug!(method = %request.method, "control request");
+    match dispatch(handle, &request.method, request.params).await {
+        Ok(result) => Response::result(request.id, result),
+        Err(error) => Response::error(request.id, error),
+    }
+}
+
+fn params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e))
+}
+
+fn to_value<T: Serialize>(value: T) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|e| RpcError::new(NODE_ERROR, e))
+}
+
+async fn dispatch(handle: &NodeHandle, method: &str, params_: Value) -> Result<Value, RpcError> {
+    let node_error = |e: crate::Error| RpcError::new(NODE_ERROR, e);
+
+    match method {
+        "dial" => {
+            let AddrParams { addr } = params(params_)?;
+            handle.dial(addr).await.map_err(node_error)?;
+            Ok(Value::Null)
+        }
+
+        "dial_peer" => {
+            let PeerParams { peer_id } = params(params_)?;
+            let (connection_id, endpoint) = handle.dial_peer(peer_id).await.map_err(node_error)?;
+            to_value(ConnectionInfo::new(peer_id, connection_id, &endpoint))
+        }
+
+        "listen" => {
+            let AddrParams { addr } = params(params_)?;
+            let listener_id = handle.listen(addr).await.map_err(node_error)?;
+            Ok(Value::String(listener_id.to_string()))
+        }
+
+        "listen_relayed" => {
+            let ListenRelayedParams { relay } = params(params_)?;
+            if !relay.iter().any(|p| matches!(p, Protocol::P2p(_))) {
+                return Err(RpcError::new(
+                    INVALID_PARAMS,
+                    "relay address must end with /p2p/<peer id>",
+                ));
+            }
+            let listener_id = handle
+                .listen(relay.with(Protocol::P2pCircuit))
+                .await
+                .map_err(node_error)?;
+            Ok(Value::String(listener_id.to_string()))
+        }
+
+        "relayed_listeners" => {
+            let listeners = handle.relayed_listeners().await.map_err(node_error)?;
+            to_value(
+                listeners
+                    .into_iter()
+                    .map(RelayedListenerInfo::from)
+                    .collect::<Vec<_>>(),
+            )
+        }
+
+        "remove_relayed_listener" => {
+            let ListenerParams { listener_id } = params(params_)?;
+            let listeners = handle.relayed_listeners().await.map_err(node_error)?;
+            let Some(listener) = listeners
+                .into_iter()
+                .find(|l| l.listener_id.to_string() == listener_id)
+            else {
+                return Err(RpcError::new(
+                    INVALID_PARAMS,
+                    format!("no relayed listener `{listener_id}`"),
+                ));
+            };
+            let removed = handle
+                .remove_listener(listener.listener_id)
+                .await
+                .map_err(node_error)?;
+            Ok(Value::Bool(removed))
+        }
+
+        "peers" => {
+            let connections = handle.connections().await.map_err(node_error)?;
+            let mut peers = connections
+                .into_iter()
+                .map(|(peer_id, conns)| PeerInfo {
+                    peer_id,
+                    connections: conns.len(),
+                })
+                .collect::<Vec<_>>();
+            peers.sort_by_key(|p| p.peer_id);
+            to_value(peers)
+        }
+
+        "connections" => {
+            let connections = handle.connections().await.map_err(node_error)?;
+            let mut infos = connections
+                .iter()
+                .flat_map(|(peer_id, conns)| {
+                    conns
+                        .iter()
+                        .map(|(id, endpoint)| ConnectionInfo::new(*peer_id, *id, endpoint))
+                })
+                .collect::<Vec<_>>();
+            infos.sort_by_key(|c| c.connection_id);
+            to_value(infos)
+        }
+
+        "close_connection" => {
+            let CloseConnectionParams { connection_id } = params(params_)?;
+            let closed = handle
+                .close_connection(ConnectionId::new_unchecked(connection_id))
+                .await
+                .map_err(node_error)?;
+            Ok(Value::Bool(closed))
+        }
+
+        "kad_get" => {
+            let KadGetParams { key } = params(params_)?;
+            let value = handle.kad_get(&key).await.map_err(node_error)?;
+            Ok(Value::String(String::from_utf8_lossy(&value).into_owned()))
+        }
+
+        "kad_put" => {
+            let KadPutParams { key, value } = params(params_)?;
+            handle
+                .kad_put(&key, value.into_bytes(), None)
+                .await
+                .map_err(node_error)?;
+            Ok(Value::Null)
+        }
+
+        "nat_status" => {
+            let status = handle.nat_status().await.map_err(node_error)?;
+            to_value(NatStatusInfo::from(status))
+        }
+
+        "nat_mapping" => {
+            let mapping = handle.nat_mapping().await.map_err(node_error)?;
+            Ok(Value::String(mapping.to_string()))
+        }
+
+        "hole_punch_reports" => {
+            let HolePunchReportsParams { peer_id } = match params_ {
+                Value::Null => Default::default(),
+                params_ => params(params_)?,
+            };
+            let reports = handle
+                .hole_punch_reports(peer_id)
+                .await
+                .map_err(node_error)?;
+            to_value(
+                reports
+                    .into_iter()
+                    .map(HolePunchReportInfo::from)
+                    .collect::<Vec<_>>(),
+            )
+        }
+
+        "port_mappings" => {
+            let mappings = handle.port_mappings().await.map_err(node_error)?;
+            to_value(
+                mappings
+                    .into_iter()
+                    .map(PortMappingInfo::from)
+                    .collect::<Vec<_>>(),
+            )
+        }
+
+        other => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("unknown method `{other}`"),
+        )),
+    }
+}
+
+/// Errors returned by [`call`].
+#[derive(Debug)]
+pub enum ControlError {
+    Io(io::Error),
+    /// The node answered with an error.
+    Rpc(RpcError),
+}
+
+impl fmt::Display for ControlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlError::Io(e) => write!(f, "control socket: {e}"),
+            ControlError::Rpc(e) => write!(f, "control request: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ControlError {}
+
+/// Send a single request to the control socket at `path` and wait for its result.
+pub async fn call(path: &Path, method: &str, params: Value) -> Result<Value, ControlError> {
+    let stream = UnixStream::connect(path).await.map_err(ControlError::Io)?;
+    let (read, mut write) = stream.into_split();
+
+    let request = serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": method,
+        "params": params,
+    });
+    let mut out = serde_json::to_vec(&request).map_err(|e| ControlError::Io(e.into()))?;
+    out.push(b'\n');
+    write.write_all(&out).await.map_err(ControlError::Io)?;
+
+    let mut line = String::new();
+    BufReader::new(read)
+        .read_line(&mut line)
+        .await
+        .map_err(ControlError::Io)?;
+    if line.is_empty() {
+        return Err(ControlError::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+
+    let response: Response = serde_json::from_str(&line).map_err(|e| ControlError::Io(e.into()))?;
+    match (response.result, response.error) {
+        (_, Some(error)) => Err(ControlError::Rpc(error)),
+        (result, None) => Ok(result.unwrap_or(Value::Null)),
+    }
+}
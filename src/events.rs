@@ -0,0 +1,442 @@
+use std::io;
+use std::time::SystemTime;
+
+use libp2p::{autonat, core::ConnectedPoint, core::Endpoint, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::{
+    connection_number, DialAttempt, HolePunchReport, KadQueryResult, NodeEvent, PortMapping,
+    TimedEvent,
+};
+
+/// Bumped whenever a field is removed or changes meaning, adding fields keeps the version.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// One line of the JSON event stream.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventRecord {
+    pub version: u32,
+    /// RFC 3339 time at which the node emitted the event, in UTC with millisecond precision.
+    pub timestamp: String,
+    #[serde(flatten)]
+    pub event: JsonEvent,
+}
+
+impl EventRecord {
+    pub fn new(time: SystemTime, event: JsonEvent) -> Self {
+        EventRecord {
+            version: SCHEMA_VERSION,
+            timestamp: humantime::format_rfc3339_millis(time).to_string(),
+            event,
+        }
+    }
+}
+
+/// The events of the stream, tagged by their snake case name in the `event` field.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum JsonEvent {
+    NewListenAddr {
+        listener_id: String,
+        address: Multiaddr,
+    },
+    ExpiredListenAddr {
+        listener_id: String,
+        address: Multiaddr,
+    },
+    ListenerClosed {
+        listener_id: String,
+        addresses: Vec<Multiaddr>,
+        error: Option<String>,
+    },
+    ConnectionEstablished {
+        peer_id: PeerId,
+        connection_id: usize,
+        endpoint: EndpointInfo,
+    },
+    ConnectionClosed {
+        peer_id: PeerId,
+        connection_id: usize,
+        endpoint: EndpointInfo,
+    },
+    IdentifyReceived {
+        peer_id: PeerId,
+        protocol_version: String,
+        agent_version: String,
+        listen_addrs: Vec<Multiaddr>,
+        observed_addr: Multiaddr,
+        protocols: Vec<String>,
+    },
+    ReservationAccepted {
+        relay_peer_id: PeerId,
+        listener_id: Option<String>,
+        renewal: bool,
+    },
+    ReservationGranted {
+        peer_id: PeerId,
+        renewed: bool,
+    },
+    HolePunch {
+        remote_peer_id: PeerId,
+        success: bool,
+        /// The direct connection on success.
+        connection_id: Option<usize>,
+        error: Option<String>,
+    },
+    HolePunchReport(HolePunchReportInfo),
+    NatStatusChanged {
+        old: NatStatusInfo,
+        new: NatStatusInfo,
+    },
+    NatMappingChanged {
+        /// `unknown`, `cone` or `symmetric`.
+        old: String,
+        new: String,
+    },
+    KadRoutingUpdated {
+        peer_id: PeerId,
+    },
+    KadQueryResult {
+        /// `put_record`, `get_record`, `get_closest_peers` or `bootstrap`.
+        query: String,
+        /// Keys and values are decoded as UTF-8, invalid sequences are replaced.
+        key: Option<String>,
+        success: bool,
+        value: Option<String>,
+        peers: Option<Vec<PeerId>>,
+        error: Option<String>,
+    },
+    PortMapped(PortMappingInfo),
+    PortMappingConfirmed(PortMappingInfo),
+    PortMappingFailed {
+        /// `tcp` or `udp`.
+        protocol: String,
+        local_port: u16,
+        error: String,
+    },
+    /// Events were dropped because the writer could not keep up.
+    EventsLagged {
+        skipped: u64,
+    },
+}
+
+/// Endpoint details of a connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EndpointInfo {
+    /// `dialer` or `listener`.
+    pub role: String,
+    /// The role the connection behaves as, differs from `role` for hole punched connections.
+    pub role_override: String,
+    pub remote_addr: Multiaddr,
+    /// Only known for inbound connections.
+    pub local_addr: Option<Multiaddr>,
+    pub relayed: bool,
+}
+
+impl From<&ConnectedPoint> for EndpointInfo {
+    fn from(endpoint: &ConnectedPoint) -> Self {
+        let role = |e: Endpoint| match e {
+            Endpoint::Dialer => "dialer".to_string(),
+            Endpoint::Listener => "listener".to_string(),
+        };
+
+        match endpoint {
+            ConnectedPoint::Dialer {
+                address,
+                role_override,
+            } => EndpointInfo {
+                role: role(Endpoint::Dialer),
+                role_override: role(*role_override),
+                remote_addr: address.clone(),
+                local_addr: None,
+                relayed: endpoint.is_relayed(),
+            },
+            ConnectedPoint::Listener {
+                local_addr,
+                send_back_addr,
+            } => EndpointInfo {
+                role: role(Endpoint::Listener),
+                role_override: role(Endpoint::Listener),
+                remote_addr: send_back_addr.clone(),
+                local_addr: Some(local_addr.clone()),
+                relayed: endpoint.is_relayed(),
+            },
+        }
+    }
+}
+
+/// The diagnostics of a hole punch attempt.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HolePunchReportInfo {
+    pub remote_peer_id: PeerId,
+    pub relayed_connection_id: usize,
+    /// Times are RFC 3339 in UTC with millisecond precision.
+    pub started_at: String,
+    /// The candidates for a direct connection sent to the remote.
+    pub local_addrs: Vec<Multiaddr>,
+    /// The addresses received from the remote.
+    pub remote_addrs: Vec<Multiaddr>,
+    pub dials: Vec<DialAttemptInfo>,
+    /// Unknown while in progress, or if only the remote learned the outcome.
+    pub success: Option<bool>,
+    /// The direct connection on success.
+    pub connection_id: Option<usize>,
+    pub error: Option<String>,
+}
+
+impl From<HolePunchReport> for HolePunchReportInfo {
+    fn from(report: HolePunchReport) -> Self {
+        let (connection_id, error) = match report.outcome.clone() {
+            Some(Ok(id)) => (Some(connection_number(id)), None),
+            Some(Err(e)) => (None, Some(e)),
+            None => (None, None),
+        };
+
+        HolePunchReportInfo {
+            remote_peer_id: report.remote_peer_id,
+            relayed_connection_id: connection_number(report.relayed_connection_id),
+            started_at: humantime::format_rfc3339_millis(report.started_at).to_string(),
+            local_addrs: report.local_addrs,
+            remote_addrs: report.remote_addrs,
+            dials: report.dials.into_iter().map(Into::into).collect(),
+            success: report.outcome.as_ref().map(Result::is_ok),
+            connection_id,
+            error,
+        }
+    }
+}
+
+/// A dial of a hole punch attempt.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DialAttemptInfo {
+    pub connection_id: usize,
+    pub addrs: Vec<Multiaddr>,
+    /// `dialer` or `listener`, the role the connection is upgraded as.
+    pub role: String,
+    pub started_at: String,
+    /// Unknown while the dial is pending.
+    pub duration_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+impl From<DialAttempt> for DialAttemptInfo {
+    fn from(dial: DialAttempt) -> Self {
+        DialAttemptInfo {
+            connection_id: connection_number(dial.connection_id),
+            addrs: dial.addrs,
+            role: if dial.as_listener {
+                "listener"
+            } else {
+                "dialer"
+            }
+            .to_string(),
+            started_at: humantime::format_rfc3339_millis(dial.started_at).to_string(),
+            duration_ms: dial.duration.map(|d| d.as_millis() as u64),
+            error: dial.error,
+        }
+    }
+}
+
+/// A port of the node mapped by the router.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortMappingInfo {
+    /// `tcp` or `udp`.
+    pub protocol: String,
+    pub local_port: u16,
+    pub external_addr: Multiaddr,
+    /// `upnp` or `nat-pmp`.
+    pub method: String,
+    pub holepunch: bool,
+    pub confirmed: bool,
+}
+
+impl From<PortMapping> for PortMappingInfo {
+    fn from(mapping: PortMapping) -> Self {
+        PortMappingInfo {
+            protocol: mapping.protocol.to_string(),
+            local_port: mapping.local_port,
+            external_addr: mapping.external_addr,
+            method: mapping.method.to_string(),
+            holepunch: mapping.holepunch,
+            confirmed: mapping.confirmed,
+        }
+    }
+}
+
+/// The NAT status as determined by autonat.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NatStatusInfo {
+    /// `public`, `private` or `unknown`.
+    pub status: String,
+    /// The confirmed public address.
+    pub address: Option<Multiaddr>,
+}
+
+impl From<autonat::NatStatus> for NatStatusInfo {
+    fn from(status: autonat::NatStatus) -> Self {
+        let (status, address) = match status {
+            autonat::NatStatus::Public(addr) => ("public", Some(addr)),
+            autonat::NatStatus::Private => ("private", None),
+            autonat::NatStatus::Unknown => ("unknown", None),
+        };
+
+        NatStatusInfo {
+            status: status.to_string(),
+            address,
+        }
+    }
+}
+
+impl From<NodeEvent> for JsonEvent {
+    fn from(event: NodeEvent) -> Self {
+        match event {
+            NodeEvent::NewListenAddr {
+                listener_id,
+                address,
+            } => JsonEvent::NewListenAddr {
+                listener_id: listener_id.to_string(),
+                address,
+            },
+            NodeEvent::ExpiredListenAddr {
+                listener_id,
+                address,
+            } => JsonEvent::ExpiredListenAddr {
+                listener_id: listener_id.to_string(),
+                address,
+            },
+            NodeEvent::ListenerClosed {
+                listener_id,
+                addresses,
+                error,
+            } => JsonEvent::ListenerClosed {
+                listener_id: listener_id.to_string(),
+                addresses,
+                error,
+            },
+            NodeEvent::ConnectionEstablished {
+                peer_id,
+                connection_id,
+                endpoint,
+            } => JsonEvent::ConnectionEstablished {
+                peer_id,
+                connection_id: connection_number(connection_id),
+                endpoint: (&endpoint).into(),
+            },
+            NodeEvent::ConnectionClosed {
+                peer_id,
+                connection_id,
+                endpoint,
+            } => JsonEvent::ConnectionClosed {
+                peer_id,
+                connection_id: connection_number(connection_id),
+                endpoint: (&endpoint).into(),
+            },
+            NodeEvent::Identified { peer_id, info } => JsonEvent::IdentifyReceived {
+                peer_id,
+                protocol_version: info.protocol_version,
+                agent_version: info.agent_version,
+                listen_addrs: info.listen_addrs,
+                observed_addr: info.observed_addr,
+                protocols: info.protocols.iter().map(|p| p.to_string()).collect(),
+            },
+            NodeEvent::ReservationAccepted {
+                relay_peer_id,
+                listener_id,
+                renewal,
+            } => JsonEvent::ReservationAccepted {
+                relay_peer_id,
+                listener_id: listener_id.map(|id| id.to_string()),
+                renewal,
+            },
+            NodeEvent::ReservationGranted { peer_id, renewed } => {
+                JsonEvent::ReservationGranted { peer_id, renewed }
+            }
+            NodeEvent::HolePunch {
+                remote_peer_id,
+                result,
+            } => JsonEvent::HolePunch {
+                remote_peer_id,
+                success: result.is_ok(),
+                connection_id: result.as_ref().ok().copied().map(connection_number),
+                error: result.err(),
+            },
+            NodeEvent::HolePunchReport(report) => JsonEvent::HolePunchReport((*report).into()),
+            NodeEvent::NatStatusChanged { old, new } => JsonEvent::NatStatusChanged {
+                old: old.into(),
+                new: new.into(),
+            },
+            NodeEvent::NatMappingChanged { old, new } => JsonEvent::NatMappingChanged {
+                old: old.to_string(),
+                new: new.to_string(),
+            },
+            NodeEvent::KadRoutingUpdated { peer } => JsonEvent::KadRoutingUpdated { peer_id: peer },
+            NodeEvent::KadQueryResult(result) => kad_query_result(result),
+            NodeEvent::PortMapped(mapping) => JsonEvent::PortMapped(mapping.into()),
+            NodeEvent::PortMappingConfirmed(mapping) => {
+                JsonEvent::PortMappingConfirmed(mapping.into())
+            }
+            NodeEvent::PortMappingFailed {
+                protocol,
+                local_port,
+                error,
+            } => JsonEvent::PortMappingFailed {
+                protocol: protocol.to_string(),
+                local_port,
+                error,
+            },
+        }
+    }
+}
+
+fn kad_query_result(result: KadQueryResult) -> JsonEvent {
+    let lossy = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+    let (query, key, value, peers, error) = match result {
+        KadQueryResult::PutRecord { key, result } => {
+            ("put_record", Some(key), None, None, result.err())
+        }
+        KadQueryResult::GetRecord { key, result } => match result {
+            Ok(value) => ("get_record", Some(key), Some(value), None, None),
+            Err(e) => ("get_record", Some(key), None, None, Some(e)),
+        },
+        KadQueryResult::GetClosestPeers { key, result } => match result {
+            Ok(peers) => ("get_closest_peers", Some(key), None, Some(peers), None),
+            Err(e) => ("get_closest_peers", Some(key), None, None, Some(e)),
+        },
+        KadQueryResult::Bootstrap { result } => ("bootstrap", None, None, None, result.err()),
+    };
+
+    JsonEvent::KadQueryResult {
+        query: query.to_string(),
+        key: key.as_deref().map(lossy),
+        success: error.is_none(),
+        value: value.as_deref().map(lossy),
+        peers,
+        error,
+    }
+}
+
+/// Write every event received on `events` as a JSON line to `out`, until the node shuts down.
+pub async fn write_json<W>(
+    mut events: broadcast::Receiver<TimedEvent>,
+    mut out: W,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let record = match events.recv().await {
+            Ok(TimedEvent { time, event }) => EventRecord::new(time, event.into()),
+            Err(RecvError::Lagged(skipped)) => {
+                EventRecord::new(SystemTime::now(), JsonEvent::EventsLagged { skipped })
+            }
+            Err(RecvError::Closed) => return out.flush().await,
+        };
+
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        out.write_all(&line).await?;
+        out.flush().await?;
+    }
+}
@@ -0,0 +1,253 @@
+//! Retries of failed DCUtR hole punches.
+//!
+//! DCUtR upgrades a relayed connection once, driven by the side that accepted it, which is also
+//! the only side told about a failure. That side retries by opening a new relayed connection to
+//! the peer, whose upgrade the peer then drives. The relayed connection of the failed attempt is
+//! kept, so that the peer stays reachable in between. A peer that reached this node through its
+//! reservation is only dialed back through that relay if it announced a reservation there too.
+//!
+//! Every attempt is recorded in a [`HolePunchReport`] by the DCUtR behaviour.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant, SystemTime};
+
+use libp2p::{multiaddr::Protocol, swarm::ConnectionId, Multiaddr, PeerId};
+use tracing::info;
+
+use crate::config::DcutrConfig;
+
+/// Longest delay before a retry, however many failed before.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+/// What happened while upgrading a relayed connection, see
+/// [`NodeHandle::hole_punch_reports`](crate::NodeHandle::hole_punch_reports).
+#[derive(Debug, Clone)]
+pub struct HolePunchReport {
+    pub remote_peer_id: PeerId,
+    /// The relayed connection the upgrade was attempted on.
+    pub relayed_connection_id: ConnectionId,
+    pub started_at: SystemTime,
+    /// The candidates for a direct connection sent to the remote, the observed addresses of this
+    /// node translated to its listening ports.
+    pub local_addrs: Vec<Multiaddr>,
+    /// The addresses the remote sent, dialed by this node.
+    pub remote_addrs: Vec<Multiaddr>,
+    pub dials: Vec<DialAttempt>,
+    /// The direct connection on success. `None` while the upgrade is in progress, and on the side
+    /// reached through the relay if its own dials failed, as only the other side learns about
+    /// the failure.
+    pub outcome: Option<Result<ConnectionId, String>>,
+}
+
+/// A dial of the remote's addresses during a hole punch.
+#[derive(Debug, Clone)]
+pub struct DialAttempt {
+    pub connection_id: ConnectionId,
+    pub addrs: Vec<Multiaddr>,
+    /// Whether the connection is upgraded as the listener, which the side reached through the
+    /// relay does.
+    pub as_listener: bool,
+    pub started_at: SystemTime,
+    /// Until the connection was established or failed, `None` while pending.
+    pub duration: Option<Duration>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug)]
+struct Retry {
+    /// Retries started so far.
+    attempts: u32,
+    /// The `/p2p-circuit` address of the next retry, and when it is due.
+    next: Option<(Multiaddr, Instant)>,
+}
+
+pub(crate) struct HolePunchPolicy {
+    config: DcutrConfig,
+    retries: HashMap<PeerId, Retry>,
+    /// The relays each peer announced a reservation on, in its identify listen addresses.
+    reservations: HashMap<PeerId, HashSet<PeerId>>,
+}
+
+impl HolePunchPolicy {
+    pub(crate) fn new(config: DcutrConfig) -> Self {
+        HolePunchPolicy {
+            config,
+            retries: Default::default(),
+            reservations: Default::default(),
+        }
+    }
+
+    /// Whether the relayed connections to a peer are closed once a direct one is established.
+    pub(crate) fn close_relayed(&self) -> bool {
+        self.config.close_relayed
+    }
+
+    /// A hole punch failed, `circuit` reaches the peer through the relay of the failed attempt.
+    /// Returns whether a retry was scheduled.
+    pub(crate) fn on_failure(&mut self, peer: PeerId, circuit: Multiaddr, now: Instant) -> bool {
+        let retry = self.retries.entry(peer).or_insert(Retry {
+            attempts: 0,
+            next: None,
+        });
+        if retry.attempts >= self.config.retries {
+            info!(%peer, attempts = retry.attempts, "no hole punch retry left");
+            self.retries.remove(&peer);
+            return false;
+        }
+
+        let backoff = self
+            .config
+            .retry_backoff
+            .saturating_mul(2u32.saturating_pow(retry.attempts))
+            .min(MAX_RETRY_BACKOFF);
+        retry.attempts += 1;
+        retry.next = Some((circuit, now + backoff));
+        info!(%peer, attempt = retry.attempts, ?backoff, "hole punch retry scheduled");
+        true
+    }
+
+    pub(crate) fn on_success(&mut self, peer: PeerId) {
+        self.retries.remove(&peer);
+    }
+
+    /// The node is not connected to the peer anymore.
+    pub(crate) fn forget(&mut self, peer: PeerId) {
+        self.retries.remove(&peer);
+        self.reservations.remove(&peer);
+    }
+
+    /// The listen addresses `peer` announced, whose `/p2p-circuit` ones tell the relays it holds a
+    /// reservation on.
+    pub(crate) fn on_listen_addrs(&mut self, peer: PeerId, addrs: &[Multiaddr]) {
+        let relays = addrs
+            .iter()
+            .filter_map(|addr| {
+                let protocols = addr.iter().collect::<Vec<_>>();
+                let circuit = protocols.iter().position(|p| *p == Protocol::P2pCircuit)?;
+                match protocols[..circuit].last() {
+                    Some(Protocol::P2p(relay)) => Some(*relay),
+                    _ => None,
+                }
+            })
+            .collect::<HashSet<_>>();
+        self.reservations.insert(peer, relays);
+    }
+
+    /// Whether `peer` announced a reservation on `relay`, which a relayed connection to it
+    /// through that relay needs.
+    pub(crate) fn has_reservation(&self, peer: PeerId, relay: PeerId) -> bool {
+        self.reservations
+            .get(&peer)
+            .is_some_and(|relays| relays.contains(&relay))
+    }
+
+    /// The retries due by `now`, as the peer and the address to dial it at.
+    pub(crate) fn due(&mut self, now: Instant) -> Vec<(PeerId, Multiaddr)> {
+        let mut due = Vec::new();
+        for (peer, retry) in self.retries.iter_mut() {
+            if retry.next.as_ref().is_some_and(|(_, at)| *at <= now) {
+                let (circuit, _) = retry.next.take().expect("retry is due");
+                due.push((*peer, circuit));
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(retries: u32, retry_backoff: Duration) -> HolePunchPolicy {
+        HolePunchPolicy::new(DcutrConfig {
+            retries,
+            retry_backoff,
+            ..Default::default()
+        })
+    }
+
+    fn circuit(relay: PeerId, peer: PeerId) -> Multiaddr {
+        "/ip4/198.51.100.1/tcp/4001"
+            .parse::<Multiaddr>()
+            .unwrap()
+            .with(Protocol::P2p(relay))
+            .with(Protocol::P2pCircuit)
+            .with(Protocol::P2p(peer))
+    }
+
+    #[test]
+    fn retries_are_limited_and_backed_off() {
+        let backoff = Duration::from_secs(10);
+        let mut policy = policy(2, backoff);
+        let (peer, relay) = (PeerId::random(), PeerId::random());
+        let now = Instant::now();
+
+        assert!(policy.on_failure(peer, circuit(relay, peer), now));
+        assert!(policy
+            .due(now + backoff - Duration::from_millis(1))
+            .is_empty());
+        assert_eq!(policy.due(now + backoff), [(peer, circuit(relay, peer))]);
+
+        assert!(policy.on_failure(peer, circuit(relay, peer), now));
+        assert!(policy.due(now + backoff).is_empty());
+        assert_eq!(policy.due(now + 2 * backoff).len(), 1);
+
+        assert!(!policy.on_failure(peer, circuit(relay, peer), now));
+        assert!(policy.due(now + Duration::from_secs(3600)).is_empty());
+    }
+
+    #[test]
+    fn due_retries_are_consumed() {
+        let mut policy = policy(3, Duration::ZERO);
+        let (peer, relay) = (PeerId::random(), PeerId::random());
+        let now = Instant::now();
+
+        policy.on_failure(peer, circuit(relay, peer), now);
+        assert_eq!(policy.due(now).len(), 1);
+        assert!(policy.due(now).is_empty());
+    }
+
+    #[test]
+    fn success_and_disconnection_drop_the_retries() {
+        let mut policy = policy(3, Duration::ZERO);
+        let (peer, relay) = (PeerId::random(), PeerId::random());
+        let now = Instant::now();
+
+        policy.on_failure(peer, circuit(relay, peer), now);
+        policy.on_success(peer);
+        assert!(policy.due(now).is_empty());
+
+        policy.on_failure(peer, circuit(relay, peer), now);
+        policy.forget(peer);
+        assert!(policy.due(now).is_empty());
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let mut policy = policy(40, Duration::from_secs(10));
+        let (peer, relay) = (PeerId::random(), PeerId::random());
+        let now = Instant::now();
+
+        for _ in 0..30 {
+            assert!(policy.on_failure(peer, circuit(relay, peer), now));
+            assert_eq!(policy.due(now + MAX_RETRY_BACKOFF).len(), 1);
+        }
+    }
+
+    #[test]
+    fn reservations_from_relayed_listen_addresses() {
+        let mut policy = policy(3, Duration::ZERO);
+        let (peer, relay, other) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let direct = "/ip4/198.51.100.2/tcp/4001".parse::<Multiaddr>().unwrap();
+        let relayed = circuit(relay, peer);
+
+        assert!(!policy.has_reservation(peer, relay));
+        policy.on_listen_addrs(peer, &[direct.clone(), relayed]);
+        assert!(policy.has_reservation(peer, relay));
+        assert!(!policy.has_reservation(peer, other));
+
+        // the reservation expired, the peer no longer announces it.
+        policy.on_listen_addrs(peer, &[direct]);
+        assert!(!policy.has_reservation(peer, relay));
+    }
+}
@@ -0,0 +1,255 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use clap::ValueEnum;
+use libp2p::identity::{self, ecdsa, secp256k1, Keypair};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+pub mod pem;
+
+/// Identity key algorithms supported by the node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyType {
+    #[default]
+    Ed25519,
+    Secp256k1,
+    Ecdsa,
+    Rsa,
+}
+
+impl From<KeyType> for identity::KeyType {
+    fn from(value: KeyType) -> Self {
+        match value {
+            KeyType::Ed25519 => identity::KeyType::Ed25519,
+            KeyType::Secp256k1 => identity::KeyType::Secp256k1,
+            KeyType::Ecdsa => identity::KeyType::Ecdsa,
+            KeyType::Rsa => identity::KeyType::RSA,
+        }
+    }
+}
+
+/// Generate a new random keypair of the given type.
+///
+/// libp2p is unable to generate RSA keys, those have to be created by external tooling and
+/// imported from PKCS#8.
+pub fn generate(key_type: KeyType) -> io::Result<Keypair> {
+    match key_type {
+        KeyType::Ed25519 => Ok(Keypair::generate_ed25519()),
+        KeyType::Secp256k1 => Ok(Keypair::generate_secp256k1()),
+        KeyType::Ecdsa => Ok(Keypair::generate_ecdsa()),
+        KeyType::Rsa => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "RSA keys can not be generated, convert an existing one with `import-key` instead",
+        )),
+    }
+}
+
+/// Derive a keypair from a single byte.
+///
+/// There are only 256 such identities and anyone can recreate their private keys, so this is
+/// only meant for local testing.
+pub fn generate_from_seed(key_type: KeyType, secret_key_seed: u8) -> io::Result<Keypair> {
+    let mut bytes = [0u8; 32];
+    bytes[0] = secret_key_seed;
+
+    let invalid = |e| io::Error::new(io::ErrorKind::InvalidInput, e);
+    match key_type {
+        KeyType::Ed25519 => Keypair::ed25519_from_bytes(bytes).map_err(invalid),
+        KeyType::Secp256k1 | KeyType::Ecdsa => {
+            // keep the scalar non-zero for a seed of 0.
+            bytes[31] = 1;
+            if key_type == KeyType::Secp256k1 {
+                secp256k1::SecretKey::try_from_bytes(bytes)
+                    .map(|sk| secp256k1::Keypair::from(sk).into())
+                    .map_err(invalid)
+            } else {
+                ecdsa::SecretKey::try_from_bytes(bytes)
+                    .map(|sk| ecdsa::Keypair::from(sk).into())
+                    .map_err(invalid)
+            }
+        }
+        KeyType::Rsa => generate(key_type),
+    }
+}
+
+/// Load the protobuf-encoded keypair stored at `path`, creating a new one of `key_type` if the
+/// file does not exist yet.
+///
+/// An existing identity has to match `key_type`.
+pub fn load_or_create(path: &Path, key_type: KeyType) -> io::Result<Keypair> {
+    match read(path) {
+        Ok(bytes) => {
+            let key = Keypair::from_protobuf_encoding(&bytes).map_err(invalid_data)?;
+            if key.key_type() != key_type.into() {
+                return Err(invalid_data(format!(
+                    "identity file {} holds a {} key, expected {:?}",
+                    path.display(),
+                    key.key_type(),
+                    key_type
+                )));
+            }
+
+            info!(?path, peer_id = %key.public().to_peer_id(), "identity loaded");
+            Ok(key)
+        }
+
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let key = generate(key_type)?;
+            write(path, &key)?;
+            info!(?path, peer_id = %key.public().to_peer_id(), "identity created");
+            Ok(key)
+        }
+
+        Err(e) => Err(e),
+    }
+}
+
+/// Read the raw protobuf-encoded identity at `path`, refusing files other users can access.
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    let bytes = fs::read(path)?;
+    check_permissions(path)?;
+    Ok(bytes)
+}
+
+/// Write `key` to a new file at `path`, readable by the owner only.
+///
+/// Fails if the file already exists, an identity is never overwritten.
+pub fn write(path: &Path, key: &Keypair) -> io::Result<()> {
+    let bytes = key
+        .to_protobuf_encoding()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    write_secret(path, &bytes)
+}
+
+/// Write secret `bytes` to a new file at `path`, readable by the owner only.
+pub fn write_secret(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut opts = OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+
+    let mut file = opts.open(path)?;
+    file.write_all(bytes)?;
+    file.sync_all()
+}
+
+pub(crate) fn invalid_data<E>(e: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "identity file {} is accessible by other users (mode {:o}), run `chmod 600` on it",
+                path.display(),
+                mode & 0o777
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(path: &Path) -> io::Result<()> {
+    tracing::warn!(
+        ?path,
+        "unable to check identity file permissions on this platform"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    const KEY_TYPES: [KeyType; 3] = [KeyType::Ed25519, KeyType::Secp256k1, KeyType::Ecdsa];
+
+    /// A key file path in a new directory, removed with its content when dropped.
+    fn temp_path() -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("identity.key");
+        (dir, path)
+    }
+
+    #[test]
+    fn created_identity_is_loaded_again() {
+        for key_type in KEY_TYPES {
+            let (_dir, path) = temp_path();
+            let created = load_or_create(&path, key_type).unwrap();
+            let loaded = load_or_create(&path, key_type).unwrap();
+            assert_eq!(loaded.public(), created.public(), "{key_type:?}");
+            assert_eq!(loaded.key_type(), key_type.into());
+        }
+    }
+
+    #[test]
+    fn identity_of_another_type_is_rejected() {
+        let (_dir, path) = temp_path();
+        load_or_create(&path, KeyType::Ed25519).unwrap();
+
+        let e = load_or_create(&path, KeyType::Secp256k1).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn identity_is_never_overwritten() {
+        let (_dir, path) = temp_path();
+        let key = generate(KeyType::Ed25519).unwrap();
+        write(&path, &key).unwrap();
+
+        let e = write(&path, &generate(KeyType::Ed25519).unwrap()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(
+            load_or_create(&path, KeyType::Ed25519).unwrap().public(),
+            key.public()
+        );
+    }
+
+    #[test]
+    fn seed_derived_identities_are_deterministic() {
+        for key_type in KEY_TYPES {
+            for seed in [0, 1, 255] {
+                let a = generate_from_seed(key_type, seed).unwrap();
+                let b = generate_from_seed(key_type, seed).unwrap();
+                assert_eq!(a.public(), b.public(), "{key_type:?} {seed}");
+            }
+        }
+        assert!(generate(KeyType::Rsa).is_err());
+    }
+
+    #[test]
+    fn truncated_identity_is_rejected() {
+        let (_dir, path) = temp_path();
+        let bytes = generate(KeyType::Ed25519)
+            .unwrap()
+            .to_protobuf_encoding()
+            .unwrap();
+        write_secret(&path, &bytes[..bytes.len() - 1]).unwrap();
+
+        let e = load_or_create(&path, KeyType::Ed25519).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,31 @@
+//! A libp2p node combining a relay server and client with DCUtR hole punching, autonat and
+//! kademlia.
+//!
+//! Nodes are created with [`RelayNode::builder`] and controlled through a [`NodeHandle`].
+
+pub mod acl;
+mod behaviour;
+pub mod config;
+#[cfg(unix)]
+pub mod control;
+pub mod events;
+mod hole_punch;
+pub mod identity;
+pub mod metrics;
+mod nat_mapping;
+mod node;
+pub mod port_mapping;
+mod relay_manager;
+pub mod transport;
+
+pub(crate) use node::connection_number;
+pub(crate) use transport::is_quic_addr;
+
+pub use config::{Config, NodeConfig};
+pub use hole_punch::{DialAttempt, HolePunchReport};
+pub use nat_mapping::NatMapping;
+pub use node::{
+    Error, KadQueryResult, NodeEvent, NodeHandle, RelayNode, RelayNodeBuilder, RelayedListener,
+    ReservationState, TimedEvent,
+};
+pub use port_mapping::{MappingMethod, PortMapping, PortProtocol};
@@ -1,50 +1,349 @@
-use std::{net::Ipv4Addr, collections::HashSet};
-use std::any::type_name_of_val;
-use std::collections::HashMap;
-
-use clap::Parser;
-use futures::{StreamExt, executor::block_on, FutureExt};
-use libp2p::{
-    autonat, dcutr, identify, identity::Keypair, multiaddr::Protocol, noise, ping, relay, tcp,
-    tcp::tokio::Transport as TokioTcpTransport, yamux, Multiaddr, SwarmBuilder, Transport,
-    swarm::{SwarmEvent, ConnectionId}, PeerId, core::{ConnectedPoint, Endpoint}, kad::{self, store::MemoryStore},
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use libp2p::{identity::Keypair, multiaddr::Protocol, Multiaddr, PeerId};
+#[cfg(unix)]
+use libp2p_relay_demo::control;
+use libp2p_relay_demo::{
+    config::{self, BootstrapConfig, ConfigError, RateLimit},
+    events, identity, Config, NodeEvent, NodeHandle, RelayNode,
 };
-use tracing::{warn, info, warn_span, debug};
+use tokio::io::AsyncWrite;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{info, warn, warn_span, Instrument};
 use tracing_subscriber::EnvFilter;
 
-mod behaviour;
-mod transport;
+#[derive(Debug, Parser)]
+#[clap(name = "libp2p relay node", args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
 
-pub(crate) use transport::is_holepunch_direct_addr;
-use behaviour::{Behaviour, BehaviourEvent};
+    #[clap(flatten)]
+    node: Option<Opt>,
+}
 
-#[derive(Debug, Parser)]
-#[clap(name = "libp2p relay node")]
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Generate a new identity and print its peer id
+    Keygen {
+        /// Path of the identity file to create
+        #[clap(long)]
+        out: PathBuf,
+
+        #[clap(long, value_enum, default_value_t = identity::KeyType::Ed25519)]
+        key_type: identity::KeyType,
+    },
+
+    /// Convert a protobuf-encoded identity into a PKCS#8 PEM document
+    ExportKey {
+        #[clap(long)]
+        identity: PathBuf,
+
+        /// Path of the PEM file to create, printed to stdout if omitted
+        #[clap(long)]
+        out: Option<PathBuf>,
+    },
+
+    /// Convert a PKCS#8 PEM document into a protobuf-encoded identity
+    ImportKey {
+        #[clap(long)]
+        pem: PathBuf,
+
+        /// Path of the identity file to create
+        #[clap(long)]
+        out: PathBuf,
+    },
+
+    /// Print the configuration resulting from the config file and the given flags
+    PrintConfig(Box<Opt>),
+
+    /// Send a request to the control socket of a running node
+    #[cfg(unix)]
+    Ctl {
+        /// Control socket of the node, see --control-socket
+        #[clap(long)]
+        socket: PathBuf,
+
+        #[clap(subcommand)]
+        request: CtlCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum CtlCommand {
+    /// Dial a multiaddr
+    Dial { addr: Multiaddr },
+
+    /// Connect to a peer by its id, directly, through kademlia or through a relay
+    DialPeer { peer_id: PeerId },
+
+    /// List the connected peers
+    Peers,
+
+    /// List the open connections
+    Connections,
+
+    /// Start listening on a multiaddr
+    Listen { addr: Multiaddr },
+
+    /// Listen through a relay, given as /.../p2p/<relay peer id>
+    ListenRelayed { relay: Multiaddr },
+
+    /// List the relayed listeners and the state of their reservations
+    RelayedListeners,
+
+    /// Stop a relayed listener by its id, as listed by `relayed-listeners`
+    RemoveRelayed { listener_id: String },
+
+    /// Close a connection by its id, as listed by `connections`
+    Close { connection_id: usize },
+
+    /// Look up a kademlia record
+    KadGet { key: String },
+
+    /// Store a kademlia record
+    KadPut { key: String, value: String },
+
+    /// Print the NAT status determined by autonat
+    NatStatus,
+
+    /// Print whether the NAT is a cone or a symmetric one, as observed by peers
+    NatMapping,
+
+    /// Print the diagnostics of the recent hole punch attempts
+    HolePunchReports {
+        /// Only those with this peer
+        #[clap(long)]
+        peer_id: Option<PeerId>,
+    },
+
+    /// Print the ports mapped by the router through UPnP or NAT-PMP
+    PortMappings,
+}
+
+impl CtlCommand {
+    fn request(self) -> (&'static str, serde_json::Value) {
+        use serde_json::json;
+
+        match self {
+            CtlCommand::Dial { addr } => ("dial", json!({ "addr": addr })),
+            CtlCommand::DialPeer { peer_id } => ("dial_peer", json!({ "peer_id": peer_id })),
+            CtlCommand::Peers => ("peers", json!({})),
+            CtlCommand::Connections => ("connections", json!({})),
+            CtlCommand::Listen { addr } => ("listen", json!({ "addr": addr })),
+            CtlCommand::ListenRelayed { relay } => ("listen_relayed", json!({ "relay": relay })),
+            CtlCommand::RelayedListeners => ("relayed_listeners", json!({})),
+            CtlCommand::RemoveRelayed { listener_id } => (
+                "remove_relayed_listener",
+                json!({ "listener_id": listener_id }),
+            ),
+            CtlCommand::Close { connection_id } => (
+                "close_connection",
+                json!({ "connection_id": connection_id }),
+            ),
+            CtlCommand::KadGet { key } => ("kad_get", json!({ "key": key })),
+            CtlCommand::KadPut { key, value } => ("kad_put", json!({ "key": key, "value": value })),
+            CtlCommand::NatStatus => ("nat_status", json!({})),
+            CtlCommand::NatMapping => ("nat_mapping", json!({})),
+            CtlCommand::HolePunchReports { peer_id } => {
+                ("hole_punch_reports", json!({ "peer_id": peer_id }))
+            }
+            CtlCommand::PortMappings => ("port_mappings", json!({})),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+#[clap(group(clap::ArgGroup::new("key").required(true).multiple(true).args(["identity", "seed", "config"])))]
 struct Opt {
-    /// Fixed value to generate deterministic peer id
+    /// TOML configuration file, flags given on the command line take precedence
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Path of the protobuf-encoded identity keypair, created on first run
+    #[clap(long)]
+    identity: Option<PathBuf>,
+
+    /// Algorithm of the identity key, used both to generate and to load it
+    #[clap(long, value_enum)]
+    key_type: Option<identity::KeyType>,
+
+    /// INSECURE: fixed value to generate deterministic peer id, for testing only
+    #[clap(long)]
+    seed: Option<u8>,
+
+    /// The port used to listen on all interfaces, over TCP and QUIC if enabled, IPv4 and IPv6
+    #[clap(long)]
+    listen_port: Option<u16>,
+
+    /// Listen with QUIC on UDP --listen-port and dial QUIC addresses, off by default
+    #[clap(long, overrides_with = "no_quic")]
+    quic: bool,
+
+    /// Neither listen with nor dial QUIC, even if enabled in the config file
+    #[clap(long)]
+    no_quic: bool,
+
+    /// Listen on IPv6 `::` next to IPv4 `0.0.0.0`
+    #[clap(long, overrides_with = "no_ipv6")]
+    ipv6: bool,
+
+    /// Only listen on IPv4
+    #[clap(long)]
+    no_ipv6: bool,
+
+    /// Port of the WebSocket `/ws` listener
+    #[clap(long)]
+    ws_port: Option<u16>,
+
+    /// Port of the secure WebSocket `/wss` listener, requires --wss-cert and --wss-key
+    #[clap(long)]
+    wss_port: Option<u16>,
+
+    /// PEM certificate chain of the secure WebSocket listener
+    #[clap(long)]
+    wss_cert: Option<PathBuf>,
+
+    /// PEM private key of the secure WebSocket listener
+    #[clap(long)]
+    wss_key: Option<PathBuf>,
+
+    /// UDP port of the WebRTC-direct listener, for browsers
+    #[clap(long)]
+    webrtc_port: Option<u16>,
+
+    /// PEM certificate of the WebRTC-direct listener, created on first run
+    #[clap(long)]
+    webrtc_cert: Option<PathBuf>,
+
+    /// Additional address to listen on, TCP, QUIC, WebSocket or WebRTC, may be repeated
+    #[clap(long = "listen")]
+    listen_addrs: Vec<Multiaddr>,
+
+    /// Address the node is known to be reachable at, announced without autonat confirmation,
+    /// may be repeated
+    #[clap(long = "external-addr")]
+    external_addrs: Vec<Multiaddr>,
+
+    /// Run on a public network, ignoring observed loopback and private addresses as hole punch
+    /// candidates
+    #[clap(long)]
+    public: bool,
+
+    /// Dial TCP from --listen-port, needed without QUIC to detect a symmetric NAT
+    #[clap(long)]
+    tcp_port_reuse: bool,
+
+    /// Map --listen-port and --dcutr-port through the UPnP gateway of the router
     #[clap(long)]
-    seed: u8,
+    upnp: bool,
 
-    /// The port used to listen on all interfaces
+    /// Map the ports through the NAT-PMP server of the router, when no UPnP gateway is found
     #[clap(long)]
-    listen_port: u16,
+    nat_pmp: bool,
+
+    /// Send the UPnP search to this address instead of the SSDP multicast group
+    #[clap(long)]
+    upnp_search_addr: Option<SocketAddr>,
+
+    /// Address of the NAT-PMP server, the default gateway on port 5351 otherwise
+    #[clap(long)]
+    nat_pmp_gateway: Option<SocketAddr>,
+
+    /// Lease requested for the port mappings, renewed halfway through, e.g. `1h`
+    #[clap(long, value_parser = config::parse_lease)]
+    port_mapping_lease: Option<Duration>,
 
     #[clap(long)]
     connect: Vec<Multiaddr>,
 
+    /// Dialed once a `--connect` peer is identified, a bare /p2p/<peer id> is resolved through
+    /// kademlia or the known relays
     #[clap(long)]
     peer: Option<Multiaddr>,
 
-    #[clap(long, default_value_t = false)]
+    #[clap(long)]
     relay_service: bool,
 
-    #[clap(long, default_value_t = false)]
+    #[clap(long)]
     listen_relayed: bool,
 
+    /// Number of relays listened through at the same time with --listen-relayed
+    #[clap(long)]
+    relayed_listeners: Option<usize>,
+
+    /// Access control list of the relay server, reloaded on SIGHUP
+    #[clap(long)]
+    relay_acl: Option<PathBuf>,
+
+    /// Maximum number of reservations held by the relay server
+    #[clap(long)]
+    relay_max_reservations: Option<usize>,
+
+    /// Maximum number of reservations held by the relay server for a single peer
+    #[clap(long)]
+    relay_max_reservations_per_peer: Option<usize>,
+
+    /// Lifetime of a reservation, e.g. `1h`
+    #[clap(long, value_parser = humantime::parse_duration)]
+    relay_reservation_duration: Option<Duration>,
+
+    /// Reservation rate limit per peer as LIMIT/INTERVAL, e.g. `30/2m`, a limit of 0 disables it
+    #[clap(long)]
+    relay_reservation_rate_per_peer: Option<RateLimit>,
+
+    /// Reservation rate limit per IP as LIMIT/INTERVAL, a limit of 0 disables it
+    #[clap(long)]
+    relay_reservation_rate_per_ip: Option<RateLimit>,
+
+    /// Maximum number of circuits relayed at the same time
+    #[clap(long)]
+    relay_max_circuits: Option<usize>,
+
+    /// Maximum number of circuits relayed at the same time from or to a single peer
+    #[clap(long)]
+    relay_max_circuits_per_peer: Option<usize>,
+
+    /// Maximum lifetime of a circuit, e.g. `2m`
+    #[clap(long, value_parser = humantime::parse_duration)]
+    relay_max_circuit_duration: Option<Duration>,
+
+    /// Maximum number of bytes relayed per circuit and direction
+    #[clap(long)]
+    relay_max_circuit_bytes: Option<u64>,
+
+    /// Circuit rate limit per source peer as LIMIT/INTERVAL, a limit of 0 disables it
+    #[clap(long)]
+    relay_circuit_src_rate_per_peer: Option<RateLimit>,
+
+    /// Circuit rate limit per source IP as LIMIT/INTERVAL, a limit of 0 disables it
+    #[clap(long)]
+    relay_circuit_src_rate_per_ip: Option<RateLimit>,
+
     #[clap(long)]
     dcutr_port: Option<u16>,
 
-    #[clap(long, default_value_t = false)]
+    /// Hole punches retried after a failed one, the relayed connection is kept meanwhile
+    #[clap(long)]
+    dcutr_retries: Option<u32>,
+
+    /// Delay before the first hole punch retry, doubled for every further one, e.g. `10s`
+    #[clap(long, value_parser = humantime::parse_duration)]
+    dcutr_retry_backoff: Option<Duration>,
+
+    /// Close the relayed connections to a peer once a direct connection is established
+    #[clap(long, overrides_with = "no_dcutr_close_relayed")]
+    dcutr_close_relayed: bool,
+
+    /// Keep the relayed connections to a peer next to the direct one
+    #[clap(long)]
+    no_dcutr_close_relayed: bool,
+
+    #[clap(long)]
     kad: bool,
 
     #[clap(long)]
@@ -52,239 +351,462 @@ struct Opt {
 
     #[clap(long)]
     kad_get: Option<String>,
+
+    /// Serve Prometheus metrics over HTTP on this address
+    #[clap(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Serve the JSON-RPC control API on this Unix domain socket
+    #[clap(long)]
+    control_socket: Option<PathBuf>,
+
+    /// Append node events as JSON lines to this file, `-` for stdout
+    #[clap(long, value_name = "PATH|-")]
+    events_json: Option<PathBuf>,
 }
 
-fn generate_ed25519(secret_key_seed: u8) -> Keypair {
-    let mut bytes = [0u8; 32];
-    bytes[0] = secret_key_seed;
+impl Opt {
+    /// Load the configuration file if any and apply the command line flags on top of it.
+    ///
+    /// `matches` are those the options were parsed from, telling which flags were given.
+    fn into_config(self, matches: &ArgMatches) -> Result<Config, ConfigError> {
+        let mut cfg = match self.config.as_ref() {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+
+        if self.identity.is_some() || self.seed.is_some() {
+            cfg.identity.path = self.identity;
+            cfg.identity.seed = self.seed;
+        }
+        if let Some(key_type) = self.key_type {
+            cfg.identity.key_type = key_type;
+        }
 
-    Keypair::ed25519_from_bytes(bytes).expect("only errors on wrong length")
+        if let Some(port) = self.listen_port {
+            cfg.network.listen_port = port;
+        }
+        if given(matches, "quic") {
+            cfg.network.quic = self.quic;
+        }
+        if given(matches, "no_quic") {
+            cfg.network.quic = !self.no_quic;
+        }
+        if given(matches, "ipv6") {
+            cfg.network.ipv6 = self.ipv6;
+        }
+        if given(matches, "no_ipv6") {
+            cfg.network.ipv6 = !self.no_ipv6;
+        }
+        if !self.listen_addrs.is_empty() {
+            cfg.network.listen_addrs = self.listen_addrs;
+        }
+        if !self.external_addrs.is_empty() {
+            cfg.network.external_addrs = self.external_addrs;
+        }
+        if given(matches, "public") {
+            cfg.network.public = self.public;
+        }
+        if given(matches, "tcp_port_reuse") {
+            cfg.network.tcp_port_reuse = self.tcp_port_reuse;
+        }
+        if self.ws_port.is_some() {
+            cfg.websocket.port = self.ws_port;
+        }
+        if self.wss_port.is_some() {
+            cfg.websocket.tls_port = self.wss_port;
+        }
+        if self.wss_cert.is_some() {
+            cfg.websocket.cert = self.wss_cert;
+        }
+        if self.wss_key.is_some() {
+            cfg.websocket.key = self.wss_key;
+        }
+        if self.webrtc_port.is_some() {
+            cfg.webrtc.port = self.webrtc_port;
+        }
+        if self.webrtc_cert.is_some() {
+            cfg.webrtc.cert = self.webrtc_cert;
+        }
+        if self.dcutr_port.is_some() {
+            cfg.network.dcutr_port = self.dcutr_port;
+        }
+        if let Some(v) = self.dcutr_retries {
+            cfg.dcutr.retries = v;
+        }
+        if let Some(v) = self.dcutr_retry_backoff {
+            cfg.dcutr.retry_backoff = v;
+        }
+        if given(matches, "dcutr_close_relayed") {
+            cfg.dcutr.close_relayed = self.dcutr_close_relayed;
+        }
+        if given(matches, "no_dcutr_close_relayed") {
+            cfg.dcutr.close_relayed = !self.no_dcutr_close_relayed;
+        }
+
+        if given(matches, "upnp") {
+            cfg.port_mapping.upnp = self.upnp;
+        }
+        if given(matches, "nat_pmp") {
+            cfg.port_mapping.nat_pmp = self.nat_pmp;
+        }
+        if self.upnp_search_addr.is_some() {
+            cfg.port_mapping.upnp_search_addr = self.upnp_search_addr;
+        }
+        if self.nat_pmp_gateway.is_some() {
+            cfg.port_mapping.nat_pmp_gateway = self.nat_pmp_gateway;
+        }
+        if let Some(v) = self.port_mapping_lease {
+            cfg.port_mapping.lease_duration = v;
+        }
+
+        if !self.connect.is_empty() {
+            cfg.bootstrap.connect = self.connect;
+        }
+        if self.peer.is_some() {
+            cfg.bootstrap.peer = self.peer;
+        }
+        if self.kad_put.is_some() {
+            cfg.bootstrap.kad_put = self.kad_put;
+        }
+        if self.kad_get.is_some() {
+            cfg.bootstrap.kad_get = self.kad_get;
+        }
+
+        if given(matches, "relay_service") {
+            cfg.relay.service = self.relay_service;
+        }
+        if given(matches, "listen_relayed") {
+            cfg.relay.listen_relayed = self.listen_relayed;
+        }
+        if let Some(v) = self.relayed_listeners {
+            cfg.relay.relayed_listeners = v;
+        }
+        if self.relay_acl.is_some() {
+            cfg.relay.acl = self.relay_acl;
+        }
+        if let Some(v) = self.relay_max_reservations {
+            cfg.relay.max_reservations = v;
+        }
+        if let Some(v) = self.relay_max_reservations_per_peer {
+            cfg.relay.max_reservations_per_peer = v;
+        }
+        if let Some(v) = self.relay_reservation_duration {
+            cfg.relay.reservation_duration = v;
+        }
+        if let Some(v) = self.relay_reservation_rate_per_peer {
+            cfg.relay.reservation_rate_per_peer = v;
+        }
+        if let Some(v) = self.relay_reservation_rate_per_ip {
+            cfg.relay.reservation_rate_per_ip = v;
+        }
+        if let Some(v) = self.relay_max_circuits {
+            cfg.relay.max_circuits = v;
+        }
+        if let Some(v) = self.relay_max_circuits_per_peer {
+            cfg.relay.max_circuits_per_peer = v;
+        }
+        if let Some(v) = self.relay_max_circuit_duration {
+            cfg.relay.max_circuit_duration = v;
+        }
+        if let Some(v) = self.relay_max_circuit_bytes {
+            cfg.relay.max_circuit_bytes = v;
+        }
+        if let Some(v) = self.relay_circuit_src_rate_per_peer {
+            cfg.relay.circuit_src_rate_per_peer = v;
+        }
+        if let Some(v) = self.relay_circuit_src_rate_per_ip {
+            cfg.relay.circuit_src_rate_per_ip = v;
+        }
+        if given(matches, "kad") {
+            cfg.kad.enabled = self.kad;
+        }
+
+        if self.metrics_addr.is_some() {
+            cfg.metrics.addr = self.metrics_addr;
+        }
+        if self.control_socket.is_some() {
+            cfg.control.socket = self.control_socket;
+        }
+        if self.events_json.is_some() {
+            cfg.events.json = self.events_json;
+        }
+
+        Ok(cfg)
+    }
+}
+
+/// Whether the flag `id` was given on the command line rather than left to its default.
+fn given(matches: &ArgMatches, id: &str) -> bool {
+    matches.value_source(id) == Some(ValueSource::CommandLine)
+}
+
+async fn run_command(cmd: Command, matches: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    match cmd {
+        Command::Keygen { out, key_type } => {
+            let key = identity::generate(key_type)?;
+            identity::write(&out, &key)?;
+            println!("{}", key.public().to_peer_id());
+        }
+
+        Command::ExportKey { identity, out } => {
+            let pem = identity::pem::protobuf_to_pem(&identity::read(&identity)?)?;
+            match out {
+                Some(path) => identity::write_secret(&path, pem.as_bytes())?,
+                None => print!("{pem}"),
+            }
+        }
+
+        Command::ImportKey { pem, out } => {
+            let encoded = identity::pem::pem_to_protobuf(&std::fs::read_to_string(pem)?)?;
+            let key = Keypair::from_protobuf_encoding(&encoded)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            identity::write_secret(&out, &encoded)?;
+            println!("{}", key.public().to_peer_id());
+        }
+
+        Command::PrintConfig(opt) => {
+            let matches = matches
+                .subcommand_matches("print-config")
+                .expect("print-config matches");
+            print!("{}", opt.into_config(matches)?.to_toml()?);
+        }
+
+        #[cfg(unix)]
+        Command::Ctl { socket, request } => {
+            let (method, params) = request.request();
+            let result = control::call(&socket, method, params).await?;
+            println!("{}", serde_json::to_string_pretty(&result)?);
+        }
+    }
+
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() {
     let _ = tracing_subscriber::fmt()
         .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
         .try_init();
 
-    let opt = Opt::parse();
-    info!("options {:?}", opt);
-
-    let key = generate_ed25519(opt.seed);
-    let tcp_cfg = tcp::Config::default();
-
-    let mut swarm = SwarmBuilder::with_existing_identity(key)
-        .with_tokio()
-        .with_other_transport(|keypair| {
-            let tcp_trans = transport::HolePunchTransport::new(tcp_cfg.clone())
-                .or_transport(TokioTcpTransport::new(tcp_cfg));
-
-            let tcp_upgraded = {
-                let noise = noise::Config::new(keypair)
-                    .expect("Signing libp2p-noise static DH keypair failed.");
-
-                tcp_trans
-                    .upgrade(libp2p::core::upgrade::Version::V1Lazy)
-                    .authenticate(noise)
-                    .multiplex(yamux::Config::default())
-                    .timeout(std::time::Duration::from_secs(2))
-                    .boxed()
-            };
-
-            Ok(libp2p::dns::tokio::Transport::system(tcp_upgraded)?.boxed())
-        })
-        .expect("swarm builder with transport")
-        .with_relay_client(noise::Config::new, yamux::Config::default)
-        .expect("swarm with relay client")
-        .with_behaviour(|key, relay_client| Behaviour {
-            kad: opt.kad.then(|| kad::Behaviour::new(key.public().to_peer_id(), MemoryStore::new(key.public().to_peer_id())).into()).into(),
-            relay: opt
-                .relay_service
-                .then(|| relay::Behaviour::new(key.public().to_peer_id(), Default::default()))
-                .into(),
-            relay_client,
-            dcutr: opt
-                .dcutr_port
-                .map(|_| dcutr::Behaviour::new(key.public().to_peer_id()).into())
-                .into(),
-            autonat: autonat::Behaviour::new(key.public().to_peer_id(), autonat::Config {
-                confidence_max: 1,
-                .. Default::default()
-            }).into(),
-            ping: ping::Behaviour::default(),
-            identify: identify::Behaviour::new(identify::Config::new(
-                "/RelayDemo/0.0.1".to_string(),
-                key.public(),
-            )),
-        })
-        .expect("swarm with behaviour")
-        .with_swarm_config(|c| {
-            c.with_idle_connection_timeout(std::time::Duration::from_secs(u64::MAX))
-        })
-        .build();
-
-    let listen_addr = Multiaddr::from(Ipv4Addr::UNSPECIFIED).with(Protocol::Tcp(opt.listen_port));
-    swarm
-        .listen_on(listen_addr)
-        .expect("swarm listen on tcp normal");
-
-    if let Some(port) = opt.dcutr_port {
-        let listen_addr = Multiaddr::from(Ipv4Addr::UNSPECIFIED)
-            .with(Protocol::Tcp(port))
-            .with(Protocol::P2pWebRtcDirect);
-        swarm
-            .listen_on(listen_addr)
-            .expect("swarm listen on tcp for dcutr");
-    }
-
-    // Wait to listen on all interfaces.
-    block_on(async {
-        let mut delay = futures_timer::Delay::new(std::time::Duration::from_secs(1)).fuse();
-        loop {
-            futures::select! {
-                event = swarm.next() => {
-                    match event.unwrap() {
-                        SwarmEvent::NewListenAddr { address, .. } => {
-                            tracing::info!(%address, "Listening on address");
-                        }
-                        event => panic!("{event:?}"),
-                    }
-                }
-                _ = delay => {
-                    // Likely listening on all interfaces now, thus continuing by breaking the loop.
-                    break;
-                }
+    let matches = Cli::command().get_matches();
+    let opt = match Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit()) {
+        Cli {
+            command: Some(cmd), ..
+        } => {
+            if let Err(e) = run_command(cmd, &matches).await {
+                eprintln!("{e}");
+                std::process::exit(1);
             }
+            return;
+        }
+        Cli {
+            node: Some(opt), ..
+        } => opt,
+        Cli { node: None, .. } => {
+            Cli::command().print_help().expect("print help");
+            std::process::exit(2);
+        }
+    };
+
+    let cfg = match opt.into_config(&matches) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    info!("config {:?}", cfg);
+
+    let key = match (cfg.identity.path.as_ref(), cfg.identity.seed) {
+        (Some(path), _) => identity::load_or_create(path, cfg.identity.key_type)
+            .map_err(|e| format!("failed to load identity {}: {e}", path.display())),
+        (None, Some(seed)) => {
+            warn!(
+                seed,
+                "using an insecure seed-derived identity, do not use this outside of tests"
+            );
+            identity::generate_from_seed(cfg.identity.key_type, seed)
+                .map_err(|e| format!("failed to generate identity from seed: {e}"))
+        }
+        // clap requires --identity or --seed unless a config file is given.
+        (None, None) => {
+            eprintln!("the config file sets neither identity.path nor identity.seed");
+            std::process::exit(2);
         }
+    };
+    let key = match key {
+        Ok(key) => key,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    let handle = RelayNode::builder(key)
+        .config(cfg.node())
+        .spawn()
+        .await
+        .expect("start node");
+
+    #[cfg(unix)]
+    let control = cfg.control.socket.as_ref().map(|path| {
+        let listener = control::bind(path).expect("bind control socket");
+        tokio::spawn(control::serve(listener, handle.clone()))
     });
 
+    if let Some(path) = cfg.events.json.as_ref() {
+        let out: Box<dyn AsyncWrite + Send + Unpin> = if path.as_os_str() == "-" {
+            Box::new(tokio::io::stdout())
+        } else {
+            let file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await
+                .expect("open events file");
+            Box::new(file)
+        };
+
+        let node_events = handle.subscribe();
+        tokio::spawn(async move {
+            if let Err(e) = events::write_json(node_events, out).await {
+                warn!(err = %e, "writing events stopped");
+            }
+        });
+    }
+
+    let mut events = handle.subscribe();
+
+    let opt = cfg.bootstrap;
     for dest in opt.connect.iter().cloned() {
-        if let Err(e) = swarm.dial(dest) {
+        if let Err(e) = handle.dial(dest).await {
             warn!("connect: {e:?}");
         }
     }
 
-    block_on(async {
-        info!("Swarm Loop");
-
-        let mut connections: HashMap<PeerId, HashMap<ConnectionId, ConnectedPoint>>  = HashMap::new();
-        let mut relayed_connections: HashMap<PeerId, HashSet<ConnectionId>> = HashMap::new();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(timed) => on_node_event(&opt, &handle, timed.event).await,
+                Err(RecvError::Lagged(skipped)) => warn!(skipped, "node events lagged"),
+                Err(RecvError::Closed) => break,
+            },
+            _ = &mut shutdown => {
+                // the port mappings are removed before the node stops.
+                if let Err(e) = handle.shutdown().await {
+                    warn!(err = ?e, "shutdown");
+                }
+                break;
+            }
+        }
+    }
 
-        loop {
-            match swarm.next().await.expect("swarm stream") {
-                SwarmEvent::Behaviour(BehaviourEvent::Identify(evt)) => {
-                    match evt {
-                        identify::Event::Received { peer_id, info } => {
-                            let _span = warn_span!("identify", ?peer_id).entered();
-                            info!(?info, "received");
+    // the control server removes its socket once the node stopped.
+    #[cfg(unix)]
+    if let Some(control) = control {
+        let _ = control.await;
+    }
+}
 
-                            let is_relay_server = info.protocols.contains(&relay::HOP_PROTOCOL_NAME);
-                            if is_relay_server {
-                                info!("relay candidate");
-                            }
+/// Resolves on Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
 
-                            if is_relay_server && opt.listen_relayed {
-                                if let Some(addr) = connections.get(&peer_id).and_then(|c| c.values().find_map(|point| match point {
-                                    ConnectedPoint::Dialer { address, role_override: Endpoint::Dialer } => Some(address.clone()),
-                                    _ => None
-                                })) {
-                                    let listen_addr = addr.with(Protocol::P2pCircuit);
-                                    let _inner_span = warn_span!("relayed", ?listen_addr).entered();
-                                    match swarm.listen_on(listen_addr) {
-                                        Ok(_) => info!("listened"),
-                                        Err(e) => warn!(err=?e, "failed"),
-                                    }
-                                }
-                            }
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            return;
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
 
-                            if let Some(peer_addr) = opt.peer.as_ref() {
-                                let _peer_span = warn_span!("peer", ?peer_addr).entered();
-                                let pre_connected = opt.connect.iter().any(|addr| addr.iter().any(|p| p == Protocol::P2p(peer_id)));
-                                if pre_connected {
-                                    match swarm.dial(peer_addr.clone()) {
-                                        Ok(_) => info!("dialed"),
+async fn on_node_event(opt: &BootstrapConfig, handle: &NodeHandle, event: NodeEvent) {
+    match event {
+        NodeEvent::Identified { peer_id, .. } => {
+            if let Some(peer_addr) = opt.peer.as_ref() {
+                let _peer_span = warn_span!("peer", ?peer_addr).entered();
+                let pre_connected = opt
+                    .connect
+                    .iter()
+                    .any(|addr| addr.iter().any(|p| p == Protocol::P2p(peer_id)));
+                if pre_connected {
+                    let peer_only = match peer_addr.iter().collect::<Vec<_>>()[..] {
+                        [Protocol::P2p(peer_id)] => Some(peer_id),
+                        _ => None,
+                    };
+                    match peer_only {
+                        Some(peer_id) => {
+                            let handle = handle.clone();
+                            let span = warn_span!("dial peer", %peer_id);
+                            tokio::spawn(
+                                async move {
+                                    match handle.dial_peer(peer_id).await {
+                                        Ok((_, endpoint)) => info!(?endpoint, "connected"),
                                         Err(e) => warn!(err=?e, "dial failure"),
-                                    };
+                                    }
                                 }
-                            }
-                        },
-
-                        _other => {},
-                    }
-                }
-
-                SwarmEvent::Behaviour(BehaviourEvent::Autonat(evt)) => {
-                    info!(?evt, "autonat");
-                }
-
-                SwarmEvent::Behaviour(BehaviourEvent::Dcutr(evt)) => {
-                    info!(?evt, "DCUTR");
-                    if let Some(conns) = relayed_connections.remove(&evt.remote_peer_id) {
-                        for conn in conns {
-                            let closed = swarm.close_connection(conn);
-                            info!(?conn, ?closed, "close relayed connection");
+                                .instrument(span),
+                            );
                         }
+                        None => match handle.dial(peer_addr.clone()).await {
+                            Ok(_) => info!("dialed"),
+                            Err(e) => warn!(err=?e, "dial failure"),
+                        },
                     }
                 }
+            }
+        }
 
-                SwarmEvent::Behaviour(BehaviourEvent::Kad(evt)) => {
-                    info!(?evt, "kademlia");
-                    if let kad::Event::RoutingUpdated { peer, .. } = evt {
-                        let _kad_span = warn_span!("kad", ?peer);
-                        if let Some(put) = opt.kad_put.as_ref() {
-                            let mut splitted = put.splitn(2, ':');
-                            let k = splitted.next().unwrap_or("").trim();
-                            let v = splitted.next().unwrap_or("").trim();
-
-                            if !k.is_empty() && !v.is_empty() {
-                                let _put_span = warn_span!("put", k, v).entered();
-                                if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
-                                    let query_id =  kad.inner_mut().put_record_to(kad::Record{key: kad::RecordKey::new(&k), value: v.as_bytes().to_vec(), publisher: None, expires: None}, [peer].into_iter(), kad::Quorum::One);
-                                    info!(?query_id, "query");
-                                }
+        NodeEvent::KadRoutingUpdated { peer } => {
+            if let Some(put) = opt.kad_put.as_ref() {
+                let mut splitted = put.splitn(2, ':');
+                let k = splitted.next().unwrap_or("").trim().to_owned();
+                let v = splitted.next().unwrap_or("").trim().to_owned();
+
+                if !k.is_empty() && !v.is_empty() {
+                    let handle = handle.clone();
+                    let span = warn_span!("put", k, v, ?peer);
+                    tokio::spawn(
+                        async move {
+                            match handle.kad_put(&k, v.into_bytes(), Some(vec![peer])).await {
+                                Ok(()) => info!("record stored"),
+                                Err(e) => warn!(err=?e, "put failure"),
                             }
                         }
+                        .instrument(span),
+                    );
+                }
+            }
 
-                        if let Some(key) = opt.kad_get.as_ref() {
-                            let k = key.trim();
-                            if !k.is_empty() {
-                                let _get_span = warn_span!("kad get", k).entered();
-                                if let Some(kad) = swarm.behaviour_mut().kad.as_mut() {
-                                    let query_id =  kad.inner_mut().get_record(kad::RecordKey::new(&k));
-                                    info!(?query_id, "get record");
+            if let Some(key) = opt.kad_get.as_ref() {
+                let k = key.trim().to_owned();
+                if !k.is_empty() {
+                    let handle = handle.clone();
+                    let span = warn_span!("kad get", k);
+                    tokio::spawn(
+                        async move {
+                            match handle.kad_get(&k).await {
+                                Ok(value) => {
+                                    info!(value = %String::from_utf8_lossy(&value), "record found")
                                 }
+                                Err(e) => warn!(err=?e, "get failure"),
                             }
                         }
-                    }
-                }
-
-                SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, .. } => {
-                    info!(?peer_id, ?connection_id, ?endpoint, "connection established");
-                    if endpoint.is_relayed() {
-                        relayed_connections.entry(peer_id).or_default().insert(connection_id);
-                    }
-
-                    connections.entry(peer_id).or_default().insert(connection_id, endpoint);
-                }
-
-                SwarmEvent::ConnectionClosed { peer_id, connection_id, endpoint, .. } => {
-                    info!(?peer_id, ?connection_id, "connection closed");
-                    if endpoint.is_relayed() {
-                        relayed_connections.entry(peer_id).and_modify(|set| { set.remove(&connection_id); });
-                    }
-
-                    let entry = connections.entry(peer_id);
-                    let mut is_empty = false;
-                    entry.and_modify(|c| { c.remove(&connection_id); is_empty = c.is_empty(); });
-                    if is_empty {
-                        connections.remove(&peer_id);
-                    }
-                }
-
-                event => {
-                    debug!(?event, "OTHER EVENT<{}>", type_name_of_val(&event));
+                        .instrument(span),
+                    );
                 }
             }
         }
-    });
+
+        _ => {}
+    }
 }
@@ -0,0 +1,322 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use libp2p::{
+    autonat,
+    core::{ConnectedPoint, Endpoint},
+    metrics::{Metrics as Libp2pMetrics, Recorder},
+    relay,
+    swarm::ConnectionId,
+    PeerId,
+};
+use prometheus_client::{
+    encoding::{text::encode, EncodeLabelSet, EncodeLabelValue},
+    metrics::{counter::Counter, family::Family, gauge::Gauge},
+    registry::Registry,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+const CONTENT_TYPE: &str = "application/openmetrics-text;charset=utf-8;version=1.0.0";
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum ConnectionKind {
+    Direct,
+    Relayed,
+    HolePunched,
+}
+
+impl ConnectionKind {
+    fn of(endpoint: &ConnectedPoint) -> Self {
+        match endpoint {
+            _ if endpoint.is_relayed() => ConnectionKind::Relayed,
+            // DCUtR dials its candidates as listener.
+            ConnectedPoint::Dialer {
+                role_override: Endpoint::Listener,
+                ..
+            } => ConnectionKind::HolePunched,
+            _ => ConnectionKind::Direct,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ConnectionLabels {
+    kind: ConnectionKind,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, EncodeLabelValue)]
+enum Outcome {
+    Success,
+    Failure,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct OutcomeLabels {
+    outcome: Outcome,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, EncodeLabelValue)]
+enum NatStatus {
+    Public,
+    Private,
+    Unknown,
+}
+
+impl From<&autonat::NatStatus> for NatStatus {
+    fn from(value: &autonat::NatStatus) -> Self {
+        match value {
+            autonat::NatStatus::Public(_) => NatStatus::Public,
+            autonat::NatStatus::Private => NatStatus::Private,
+            autonat::NatStatus::Unknown => NatStatus::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct NatStatusLabels {
+    status: NatStatus,
+}
+
+/// The libp2p protocol metrics plus the node specific ones.
+pub struct NodeMetrics {
+    libp2p: Libp2pMetrics,
+
+    connections_established: Family<ConnectionLabels, Counter>,
+    connections_active: Family<ConnectionLabels, Gauge>,
+    hole_punches: Family<OutcomeLabels, Counter>,
+    relay_reservations_active: Gauge,
+    relay_circuits_active: Gauge,
+    nat_status_changes: Family<NatStatusLabels, Counter>,
+    nat_status: Family<NatStatusLabels, Gauge>,
+
+    connection_kinds: HashMap<ConnectionId, ConnectionKind>,
+    /// The peers counted by `relay_reservations_active`. The relay server reports accepted and
+    /// timed out reservations, but drops the reservation of a closed connection without an event,
+    /// so a peer stays counted until it times out or its last connection closes.
+    reservations: HashSet<PeerId>,
+}
+
+impl NodeMetrics {
+    pub fn new(registry: &mut Registry) -> Self {
+        let libp2p = Libp2pMetrics::new(registry);
+        let registry = registry.sub_registry_with_prefix("relay_demo");
+
+        let connections_established = Family::default();
+        registry.register(
+            "connections_established",
+            "Connections established by endpoint kind",
+            connections_established.clone(),
+        );
+
+        let connections_active = Family::default();
+        registry.register(
+            "connections_active",
+            "Currently open connections by endpoint kind",
+            connections_active.clone(),
+        );
+
+        let hole_punches = Family::default();
+        registry.register(
+            "dcutr_hole_punches",
+            "DCUtR hole punch attempts by outcome",
+            hole_punches.clone(),
+        );
+
+        let relay_reservations_active = Gauge::default();
+        registry.register(
+            "relay_reservations_active",
+            "Peers holding a reservation on the relay server, inferred from relay events",
+            relay_reservations_active.clone(),
+        );
+
+        let relay_circuits_active = Gauge::default();
+        registry.register(
+            "relay_circuits_active",
+            "Circuits currently relayed by the relay server",
+            relay_circuits_active.clone(),
+        );
+
+        let nat_status_changes = Family::default();
+        registry.register(
+            "autonat_status_changes",
+            "NAT status changes reported by autonat, by new status",
+            nat_status_changes.clone(),
+        );
+
+        let nat_status = Family::default();
+        registry.register(
+            "autonat_status",
+            "Current NAT status, 1 for the active one",
+            nat_status.clone(),
+        );
+
+        NodeMetrics {
+            libp2p,
+            connections_established,
+            connections_active,
+            hole_punches,
+            relay_reservations_active,
+            relay_circuits_active,
+            nat_status_changes,
+            nat_status,
+            connection_kinds: Default::default(),
+            reservations: Default::default(),
+        }
+    }
+
+    /// Feed an event to the matching libp2p protocol metrics.
+    pub fn record<E>(&self, event: &E)
+    where
+        Libp2pMetrics: Recorder<E>,
+    {
+        self.libp2p.record(event)
+    }
+
+    pub fn on_connection_established(
+        &mut self,
+        connection_id: ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        let kind = ConnectionKind::of(endpoint);
+        self.connection_kinds.insert(connection_id, kind);
+        self.connections_established
+            .get_or_create(&ConnectionLabels { kind })
+            .inc();
+        self.connections_active
+            .get_or_create(&ConnectionLabels { kind })
+            .inc();
+    }
+
+    pub fn on_connection_closed(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        num_established: u32,
+    ) {
+        if let Some(kind) = self.connection_kinds.remove(&connection_id) {
+            self.connections_active
+                .get_or_create(&ConnectionLabels { kind })
+                .dec();
+        }
+
+        // the relay server silently drops the reservations of disconnected peers.
+        if num_established == 0 && self.reservations.remove(&peer_id) {
+            self.relay_reservations_active.dec();
+        }
+    }
+
+    /// Record a DCUtR result, with the direct connection on success.
+    pub fn on_hole_punch(&mut self, direct: Option<ConnectionId>) {
+        let outcome = match direct {
+            Some(connection_id) => {
+                // the connection may have been counted as direct by the dialing side.
+                if let Some(kind) = self.connection_kinds.get_mut(&connection_id) {
+                    if *kind != ConnectionKind::HolePunched {
+                        self.connections_active
+                            .get_or_create(&ConnectionLabels { kind: *kind })
+                            .dec();
+                        *kind = ConnectionKind::HolePunched;
+                        self.connections_active
+                            .get_or_create(&ConnectionLabels { kind: *kind })
+                            .inc();
+                    }
+                }
+                Outcome::Success
+            }
+            None => Outcome::Failure,
+        };
+
+        self.hole_punches
+            .get_or_create(&OutcomeLabels { outcome })
+            .inc();
+    }
+
+    pub fn on_relay_event(&mut self, event: &relay::Event) {
+        self.record(event);
+
+        match event {
+            relay::Event::ReservationReqAccepted { src_peer_id, .. }
+                if self.reservations.insert(*src_peer_id) =>
+            {
+                self.relay_reservations_active.inc();
+            }
+            relay::Event::ReservationTimedOut { src_peer_id }
+                if self.reservations.remove(src_peer_id) =>
+            {
+                self.relay_reservations_active.dec();
+            }
+            relay::Event::CircuitReqAccepted { .. } => {
+                self.relay_circuits_active.inc();
+            }
+            relay::Event::CircuitClosed { .. } => {
+                self.relay_circuits_active.dec();
+            }
+            _ => {}
+        }
+    }
+
+    pub fn on_nat_status_changed(&mut self, old: &autonat::NatStatus, new: &autonat::NatStatus) {
+        self.nat_status_changes
+            .get_or_create(&NatStatusLabels { status: new.into() })
+            .inc();
+        self.nat_status
+            .get_or_create(&NatStatusLabels { status: old.into() })
+            .set(0);
+        self.nat_status
+            .get_or_create(&NatStatusLabels { status: new.into() })
+            .set(1);
+    }
+}
+
+/// Serve the metrics of `registry` over HTTP on `addr`.
+pub async fn serve(listener: TcpListener, registry: Arc<Registry>) {
+    if let Ok(addr) = listener.local_addr() {
+        info!(%addr, "serving metrics");
+    }
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, remote)) => {
+                let registry = registry.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_request(stream, &registry).await {
+                        debug!(%remote, err = ?e, "metrics request failed");
+                    }
+                });
+            }
+            Err(e) => warn!(err = ?e, "accept metrics connection"),
+        }
+    }
+}
+
+async fn handle_request(mut stream: TcpStream, registry: &Registry) -> std::io::Result<()> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    // only the request line matters, the rest of the head is skipped.
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") && buf.len() < 8192 {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&buf);
+    let mut parts = request.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let mut body = String::new();
+            encode(&mut body, registry).map_err(std::io::Error::other)?;
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {CONTENT_TYPE}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
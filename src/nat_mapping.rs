@@ -0,0 +1,229 @@
+//! Classification of the NAT of this node from the addresses peers observe it at.
+//!
+//! A cone NAT maps a local port to the same external port whatever the destination, a symmetric
+//! NAT to a new one per destination. Peers observing connections dialed from the same local
+//! port, as QUIC and TCP with port reuse do, tell them apart: behind a cone NAT they see the
+//! same port, behind a symmetric NAT different ones.
+//!
+//! Most symmetric NATs allocate their ports in sequence, so the ports following the last one
+//! observed are predicted to be those of the next mappings, e.g. of a hole punch.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::IpAddr;
+
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+
+/// Peers whose latest observation is kept.
+const MAX_OBSERVATIONS: usize = 16;
+/// Ports predicted after the last one observed behind a symmetric NAT.
+const PREDICTED_PORTS: u16 = 8;
+
+/// How the NAT of this node maps ports, see
+/// [`NodeHandle::nat_mapping`](crate::NodeHandle::nat_mapping).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NatMapping {
+    /// Not observed by enough peers yet.
+    Unknown,
+    /// One external port per local port, also the case without NAT.
+    Cone,
+    /// One external port per local port and destination.
+    Symmetric,
+}
+
+impl fmt::Display for NatMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            NatMapping::Unknown => "unknown",
+            NatMapping::Cone => "cone",
+            NatMapping::Symmetric => "symmetric",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Tcp,
+    Quic,
+}
+
+#[derive(Debug)]
+struct Observation {
+    peer: PeerId,
+    kind: Kind,
+    ip: IpAddr,
+    port: u16,
+}
+
+pub(crate) struct NatClassifier {
+    /// Whether TCP dials from its listen port, otherwise every TCP connection has a port of its
+    /// own and its observations tell nothing.
+    tcp_port_reuse: bool,
+    /// The latest observation of every peer, the most recent first.
+    observations: VecDeque<Observation>,
+}
+
+impl NatClassifier {
+    pub(crate) fn new(tcp_port_reuse: bool) -> Self {
+        NatClassifier {
+            tcp_port_reuse,
+            observations: Default::default(),
+        }
+    }
+
+    /// `peer` observed this node at `addr` on a connection this node dialed.
+    pub(crate) fn observe(&mut self, peer: PeerId, addr: &Multiaddr) {
+        let Some((kind, ip, port)) = parse(addr) else {
+            return;
+        };
+        if kind == Kind::Tcp && !self.tcp_port_reuse {
+            return;
+        }
+
+        self.observations
+            .retain(|o| o.peer != peer || o.kind != kind);
+        self.observations.push_front(Observation {
+            peer,
+            kind,
+            ip,
+            port,
+        });
+        self.observations.truncate(MAX_OBSERVATIONS);
+    }
+
+    /// Compares the most recent observation with the previous one of the same kind and IP.
+    pub(crate) fn mapping(&self) -> NatMapping {
+        for (i, latest) in self.observations.iter().enumerate() {
+            let previous = self
+                .observations
+                .iter()
+                .skip(i + 1)
+                .find(|o| o.kind == latest.kind && o.ip == latest.ip);
+            if let Some(previous) = previous {
+                return match previous.port == latest.port {
+                    true => NatMapping::Cone,
+                    false => NatMapping::Symmetric,
+                };
+            }
+        }
+        NatMapping::Unknown
+    }
+
+    /// The addresses the next mappings of a symmetric NAT are expected at, the most likely
+    /// first. Empty for other NATs.
+    pub(crate) fn predicted_addrs(&self) -> Vec<Multiaddr> {
+        if self.mapping() != NatMapping::Symmetric {
+            return Vec::new();
+        }
+
+        let mut addrs = Vec::new();
+        for kind in [Kind::Tcp, Kind::Quic] {
+            let Some(last) = self.observations.iter().find(|o| o.kind == kind) else {
+                continue;
+            };
+            let ports = (1..=PREDICTED_PORTS).filter_map(|n| last.port.checked_add(n));
+            addrs.extend(ports.map(|port| {
+                let addr = Multiaddr::from(last.ip);
+                match kind {
+                    Kind::Tcp => addr.with(Protocol::Tcp(port)),
+                    Kind::Quic => addr.with(Protocol::Udp(port)).with(Protocol::QuicV1),
+                }
+            }));
+        }
+        addrs
+    }
+}
+
+/// The kind, IP and port of a `/tcp` or `/quic-v1` address.
+fn parse(addr: &Multiaddr) -> Option<(Kind, IpAddr, u16)> {
+    let mut iter = addr.iter();
+    let ip = match iter.next()? {
+        Protocol::Ip4(ip) => IpAddr::from(ip),
+        Protocol::Ip6(ip) => IpAddr::from(ip),
+        _ => return None,
+    };
+    match (iter.next()?, iter.next()) {
+        (Protocol::Tcp(port), None | Some(Protocol::P2p(_))) => Some((Kind::Tcp, ip, port)),
+        (Protocol::Udp(port), Some(Protocol::QuicV1)) => Some((Kind::Quic, ip, port)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: [u8; 4] = [203, 0, 113, 7];
+
+    fn tcp(port: u16) -> Multiaddr {
+        Multiaddr::from(IpAddr::from(IP)).with(Protocol::Tcp(port))
+    }
+
+    fn quic(port: u16) -> Multiaddr {
+        Multiaddr::from(IpAddr::from(IP))
+            .with(Protocol::Udp(port))
+            .with(Protocol::QuicV1)
+    }
+
+    #[test]
+    fn unknown_until_two_peers_observe_the_same_kind() {
+        let mut nat = NatClassifier::new(true);
+        assert_eq!(nat.mapping(), NatMapping::Unknown);
+
+        let peer = PeerId::random();
+        nat.observe(peer, &tcp(4001));
+        nat.observe(peer, &tcp(4002));
+        assert_eq!(nat.mapping(), NatMapping::Unknown);
+
+        nat.observe(PeerId::random(), &quic(4001));
+        assert_eq!(nat.mapping(), NatMapping::Unknown);
+        assert!(nat.predicted_addrs().is_empty());
+    }
+
+    #[test]
+    fn same_port_is_a_cone() {
+        let mut nat = NatClassifier::new(true);
+        nat.observe(PeerId::random(), &quic(4001));
+        nat.observe(PeerId::random(), &quic(4001));
+
+        assert_eq!(nat.mapping(), NatMapping::Cone);
+        assert!(nat.predicted_addrs().is_empty());
+    }
+
+    #[test]
+    fn different_ports_are_a_symmetric_nat() {
+        let mut nat = NatClassifier::new(true);
+        nat.observe(PeerId::random(), &tcp(40000));
+        nat.observe(PeerId::random(), &tcp(40001));
+
+        assert_eq!(nat.mapping(), NatMapping::Symmetric);
+        let predicted = nat.predicted_addrs();
+        assert_eq!(predicted.len(), PREDICTED_PORTS as usize);
+        assert_eq!(predicted[0], tcp(40002));
+        assert_eq!(predicted[predicted.len() - 1], tcp(40001 + PREDICTED_PORTS));
+    }
+
+    #[test]
+    fn predicted_ports_stop_at_the_last_port() {
+        let mut nat = NatClassifier::new(true);
+        nat.observe(PeerId::random(), &quic(65520));
+        nat.observe(PeerId::random(), &quic(65532));
+
+        assert_eq!(
+            nat.predicted_addrs(),
+            [quic(65533), quic(65534), quic(65535)]
+        );
+    }
+
+    #[test]
+    fn tcp_without_port_reuse_is_ignored() {
+        let mut nat = NatClassifier::new(false);
+        nat.observe(PeerId::random(), &tcp(40000));
+        nat.observe(PeerId::random(), &tcp(40001));
+        assert_eq!(nat.mapping(), NatMapping::Unknown);
+
+        nat.observe(PeerId::random(), &quic(40000));
+        nat.observe(PeerId::random(), &quic(40000));
+        assert_eq!(nat.mapping(), NatMapping::Cone);
+    }
+}
@@ -0,0 +1,1929 @@
+use std::any::type_name_of_val;
+use std::collections::{hash_map, HashMap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::{future::Either, AsyncRead, AsyncWrite, StreamExt};
+use libp2p::{
+    autonat,
+    core::{
+        muxing::StreamMuxerBox,
+        transport::{Boxed, ListenerId, MemoryTransport, OptionalTransport},
+        ConnectedPoint, Endpoint,
+    },
+    dcutr, identify,
+    identity::Keypair,
+    kad::{self, store::MemoryStore},
+    multiaddr::Protocol,
+    noise, ping, quic, relay,
+    swarm::{
+        dial_opts::{DialOpts, PeerCondition},
+        ConnectionId, SwarmEvent,
+    },
+    tcp::{self, tokio::Transport as TokioTcpTransport},
+    websocket, yamux, Multiaddr, PeerId, Swarm, SwarmBuilder, Transport,
+};
+use libp2p_webrtc as webrtc;
+use prometheus_client::registry::Registry;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn, warn_span};
+
+use crate::acl::RelayAcl;
+use crate::behaviour::direct_client;
+use crate::behaviour::{self, Behaviour, BehaviourEvent};
+use crate::config::NodeConfig;
+use crate::hole_punch::{HolePunchPolicy, HolePunchReport};
+use crate::metrics::{self, NodeMetrics};
+use crate::nat_mapping::{NatClassifier, NatMapping};
+use crate::port_mapping::{MappingEvent, PortMapper, PortMapping, PortProtocol};
+use crate::relay_manager::{is_relayed, RelayAction, RelayManager};
+use crate::transport;
+#[cfg(feature = "testing")]
+use crate::transport::sim::{SimHost, SimTransport};
+
+const COMMAND_BUFFER: usize = 64;
+const EVENT_BUFFER: usize = 1024;
+/// Interval at which timed out reservations are replaced, failed relays and hole punches
+/// retried.
+const TICK: Duration = Duration::from_secs(5);
+
+/// Errors returned by [`RelayNodeBuilder`] and [`NodeHandle`].
+#[derive(Debug)]
+pub enum Error {
+    /// The swarm could not be constructed.
+    Build(String),
+    /// A listener could not be started.
+    Listen(String),
+    /// A dial could not be started.
+    Dial(String),
+    /// The kademlia behaviour is not enabled on this node.
+    KadDisabled,
+    /// A kademlia query failed.
+    Kad(String),
+    /// The node event loop has stopped.
+    Shutdown,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Build(e) => write!(f, "build swarm: {e}"),
+            Error::Listen(e) => write!(f, "listen: {e}"),
+            Error::Dial(e) => write!(f, "dial: {e}"),
+            Error::KadDisabled => f.write_str("kademlia is not enabled"),
+            Error::Kad(e) => write!(f, "kademlia: {e}"),
+            Error::Shutdown => f.write_str("node has shut down"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Events emitted by a running node, see [`NodeHandle::subscribe`].
+#[derive(Debug, Clone)]
+pub enum NodeEvent {
+    NewListenAddr {
+        listener_id: ListenerId,
+        address: Multiaddr,
+    },
+    ExpiredListenAddr {
+        listener_id: ListenerId,
+        address: Multiaddr,
+    },
+    /// A listener stopped, on request or because of an error.
+    ListenerClosed {
+        listener_id: ListenerId,
+        addresses: Vec<Multiaddr>,
+        error: Option<String>,
+    },
+    ConnectionEstablished {
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        endpoint: ConnectedPoint,
+    },
+    ConnectionClosed {
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        endpoint: ConnectedPoint,
+    },
+    Identified {
+        peer_id: PeerId,
+        info: Box<identify::Info>,
+    },
+    NatStatusChanged {
+        old: autonat::NatStatus,
+        new: autonat::NatStatus,
+    },
+    /// The NAT was found to be a cone or a symmetric one, from the addresses peers observe.
+    NatMappingChanged {
+        old: NatMapping,
+        new: NatMapping,
+    },
+    /// Result of a DCUtR hole punch, the connection id of the direct connection on success.
+    HolePunch {
+        remote_peer_id: PeerId,
+        result: Result<ConnectionId, String>,
+    },
+    KadRoutingUpdated {
+        peer: PeerId,
+    },
+    KadQueryResult(KadQueryResult),
+    /// The diagnostics of a finished hole punch attempt, see [`NodeHandle::hole_punch_reports`].
+    HolePunchReport(Box<HolePunchReport>),
+    /// A relay accepted the reservation requested by this node.
+    ReservationAccepted {
+        relay_peer_id: PeerId,
+        /// The relayed listener the reservation was made for.
+        listener_id: Option<ListenerId>,
+        renewal: bool,
+    },
+    /// The relay server of this node accepted a reservation request.
+    ReservationGranted {
+        peer_id: PeerId,
+        renewed: bool,
+    },
+    /// The router mapped a port of this node, or mapped it anew to another address.
+    PortMapped(PortMapping),
+    /// Autonat reached this node at the address of a mapped port, which is now one of its
+    /// external addresses.
+    PortMappingConfirmed(PortMapping),
+    /// A port could not be mapped, or its mapping could not be renewed.
+    PortMappingFailed {
+        protocol: PortProtocol,
+        local_port: u16,
+        error: String,
+    },
+}
+
+/// A [`NodeEvent`] with the time the node emitted it, which subscribers may receive much later.
+#[derive(Debug, Clone)]
+pub struct TimedEvent {
+    pub time: SystemTime,
+    pub event: NodeEvent,
+}
+
+/// Outcome of a kademlia query, reported once per query.
+#[derive(Debug, Clone)]
+pub enum KadQueryResult {
+    PutRecord {
+        key: Vec<u8>,
+        result: Result<(), String>,
+    },
+    /// Only the first record found is reported.
+    GetRecord {
+        key: Vec<u8>,
+        result: Result<Vec<u8>, String>,
+    },
+    GetClosestPeers {
+        key: Vec<u8>,
+        result: Result<Vec<PeerId>, String>,
+    },
+    Bootstrap {
+        result: Result<(), String>,
+    },
+}
+
+/// A listener reached through a relay, see [`NodeHandle::relayed_listeners`].
+#[derive(Debug, Clone)]
+pub struct RelayedListener {
+    pub listener_id: ListenerId,
+    pub relay_peer_id: PeerId,
+    /// The `/p2p-circuit` address listened on.
+    pub addr: Multiaddr,
+    pub state: ReservationState,
+    pub requested_at: SystemTime,
+    /// When the reservation was last accepted or renewed.
+    pub accepted_at: Option<SystemTime>,
+    pub renewals: u32,
+    /// Estimated from the interval between renewals, as the relay client renews a reservation
+    /// after 3/4 of its lifetime. Unknown until the first renewal.
+    pub expires_at: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservationState {
+    /// The relay has not answered yet.
+    Pending,
+    Accepted,
+}
+
+impl RelayedListener {
+    fn new(listener_id: ListenerId, addr: Multiaddr) -> Option<Self> {
+        // the relay is the last peer before `/p2p-circuit`.
+        let mut relay_peer_id = None;
+        for protocol in addr.iter() {
+            match protocol {
+                Protocol::P2p(peer_id) => relay_peer_id = Some(peer_id),
+                Protocol::P2pCircuit => break,
+                _ => {}
+            }
+        }
+
+        Some(RelayedListener {
+            listener_id,
+            relay_peer_id: relay_peer_id?,
+            addr,
+            state: ReservationState::Pending,
+            requested_at: SystemTime::now(),
+            accepted_at: None,
+            renewals: 0,
+            expires_at: None,
+        })
+    }
+
+    fn accepted(&mut self, renewal: bool) {
+        let now = SystemTime::now();
+        if renewal {
+            self.renewals += 1;
+            let interval = self.accepted_at.and_then(|t| now.duration_since(t).ok());
+            self.expires_at = interval.map(|i| now + i * 4 / 3);
+        }
+        self.state = ReservationState::Accepted;
+        self.accepted_at = Some(now);
+    }
+}
+
+/// The number of a connection id, which `ConnectionId` only exposes through `Display`.
+pub(crate) fn connection_number(connection_id: ConnectionId) -> usize {
+    connection_id
+        .to_string()
+        .parse()
+        .expect("connection id is displayed as a number")
+}
+
+/// Builder of a [`RelayNode`].
+pub struct RelayNodeBuilder {
+    keypair: Keypair,
+    config: NodeConfig,
+    #[cfg(feature = "testing")]
+    sim_host: Option<SimHost>,
+}
+
+impl RelayNodeBuilder {
+    pub fn config(mut self, config: NodeConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn listen_port(mut self, port: u16) -> Self {
+        self.config.network.listen_port = port;
+        self
+    }
+
+    pub fn dcutr_port(mut self, port: Option<u16>) -> Self {
+        self.config.network.dcutr_port = port;
+        self
+    }
+
+    pub fn relay_service(mut self, enabled: bool) -> Self {
+        self.config.relay.service = enabled;
+        self
+    }
+
+    pub fn listen_relayed(mut self, enabled: bool) -> Self {
+        self.config.relay.listen_relayed = enabled;
+        self
+    }
+
+    pub fn kad(mut self, enabled: bool) -> Self {
+        self.config.kad.enabled = enabled;
+        self
+    }
+
+    /// Run on a host of a simulated network instead of the real one, for tests. The simulated
+    /// network only carries IPv4 TCP, so QUIC, IPv6, WebSocket and WebRTC are disabled.
+    #[cfg(feature = "testing")]
+    pub fn simulated(mut self, host: SimHost) -> Self {
+        self.sim_host = Some(host);
+        self
+    }
+
+    /// Construct the swarm and start listening.
+    pub fn build(self) -> Result<RelayNode, Error> {
+        let RelayNodeBuilder {
+            keypair,
+            mut config,
+            #[cfg(feature = "testing")]
+            sim_host,
+        } = self;
+        #[cfg(feature = "testing")]
+        let simulated = sim_host.is_some();
+        #[cfg(not(feature = "testing"))]
+        let simulated = false;
+        if simulated {
+            config.network.quic = false;
+            config.network.ipv6 = false;
+            config.websocket = Default::default();
+            config.webrtc = Default::default();
+        }
+        let tcp_cfg = tcp::Config::default();
+
+        let acl = match config.relay.acl.clone().filter(|_| config.relay.service) {
+            Some(path) => Some(RelayAcl::load(path).map_err(|e| Error::Build(e.to_string()))?),
+            None => None,
+        };
+        let metrics = match config.metrics.addr {
+            Some(addr) => {
+                let mut registry = Registry::default();
+                let metrics = NodeMetrics::new(&mut registry);
+                let listener = std::net::TcpListener::bind(addr)
+                    .and_then(|l| l.set_nonblocking(true).map(|_| l))
+                    .map_err(|e| Error::Listen(format!("metrics on {addr}: {e}")))?;
+                Some((metrics, (listener, registry)))
+            }
+            None => None,
+        };
+        let (metrics, metrics_listener) = metrics.unzip();
+
+        let ws_tls = match config.websocket.tls_port {
+            Some(_) => {
+                let (Some(cert), Some(key)) = (&config.websocket.cert, &config.websocket.key)
+                else {
+                    return Err(Error::Build(
+                        "the websocket tls listener requires a cert and a key".to_string(),
+                    ));
+                };
+                Some(
+                    transport::websocket_tls(cert, key)
+                        .map_err(|e| Error::Build(format!("websocket tls: {e}")))?,
+                )
+            }
+            None => None,
+        };
+
+        let webrtc_cert = match config.webrtc.port {
+            Some(_) => Some(
+                transport::webrtc_certificate(config.webrtc.cert.as_deref())
+                    .map_err(|e| Error::Build(format!("webrtc certificate: {e}")))?,
+            ),
+            None => None,
+        };
+
+        let mut relay_cfg = config.relay.server_config();
+        if let Some(acl) = acl.as_ref() {
+            // checked first so that denied requests are not charged to the rate limiters.
+            relay_cfg
+                .reservation_rate_limiters
+                .insert(0, acl.reservation_limiter());
+            relay_cfg
+                .circuit_src_rate_limiters
+                .insert(0, acl.circuit_limiter());
+        }
+
+        let holepunch = transport::HolePunchAddrs::default().public(config.network.public);
+        let (relay_trans, relay_client) = relay::client::new(keypair.public().to_peer_id());
+        let mut swarm = SwarmBuilder::with_existing_identity(keypair)
+            .with_tokio()
+            .with_other_transport(|keypair| {
+                let noise = || {
+                    noise::Config::new(keypair)
+                        .expect("Signing libp2p-noise static DH keypair failed.")
+                };
+                let timeout = config.network.upgrade_timeout;
+                let relay_trans = upgrade(
+                    transport::RemovableListeners::new(relay_trans),
+                    noise(),
+                    timeout,
+                );
+                let relayed = |trans: Boxed<(PeerId, StreamMuxerBox)>| {
+                    relay_trans
+                        .or_transport(trans)
+                        .map(|either, _| either.into_inner())
+                        .boxed()
+                };
+
+                if config.network.memory {
+                    return Ok(relayed(upgrade(
+                        MemoryTransport::default(),
+                        noise(),
+                        timeout,
+                    )));
+                }
+                #[cfg(feature = "testing")]
+                if let Some(host) = sim_host {
+                    let trans = transport::HolePunchTransport::with_transport(
+                        SimTransport::new(host.clone()).port_reuse(true),
+                        holepunch.clone(),
+                    )
+                    .or_transport(
+                        SimTransport::new(host).port_reuse(config.network.tcp_port_reuse),
+                    );
+                    return Ok(relayed(upgrade(trans, noise(), timeout)));
+                }
+
+                // dns is resolved below the websocket transport so that `/dns/.../wss` dials
+                // keep the host name for TLS, which is why it is not wrapped in the outer dns
+                // transport below.
+                let mut ws_trans = websocket::WsConfig::new(libp2p::dns::tokio::Transport::system(
+                    TokioTcpTransport::new(tcp_cfg.clone()),
+                )?);
+                if let Some(tls) = ws_tls {
+                    ws_trans.set_tls_config(tls);
+                }
+
+                let ws_upgraded = upgrade(ws_trans, noise(), timeout);
+
+                let tcp_trans =
+                    transport::HolePunchTransport::new(tcp_cfg.clone(), holepunch.clone())
+                        .or_transport(TokioTcpTransport::new(
+                            tcp_cfg.port_reuse(config.network.tcp_port_reuse),
+                        ));
+
+                let tcp_upgraded = upgrade(tcp_trans, noise(), timeout);
+
+                // QUIC always dials from its listening socket, which is all UDP hole punching
+                // needs, so it doesn't require a dedicated transport.
+                let quic_trans = match config.network.quic {
+                    true => OptionalTransport::some(quic::tokio::Transport::new(
+                        quic::Config::new(keypair),
+                    )),
+                    false => OptionalTransport::none(),
+                };
+
+                let webrtc_trans = match webrtc_cert {
+                    Some(cert) => OptionalTransport::some(webrtc::tokio::Transport::new(
+                        keypair.clone(),
+                        cert,
+                    )),
+                    None => OptionalTransport::none(),
+                };
+
+                let trans = tcp_upgraded
+                    .or_transport(quic_trans)
+                    .map(|either, _| match either {
+                        Either::Left((peer_id, muxer)) => (peer_id, muxer),
+                        Either::Right((peer_id, conn)) => (peer_id, StreamMuxerBox::new(conn)),
+                    })
+                    .or_transport(webrtc_trans)
+                    .map(|either, _| match either {
+                        Either::Left((peer_id, muxer)) => (peer_id, muxer),
+                        Either::Right((peer_id, conn)) => (peer_id, StreamMuxerBox::new(conn)),
+                    });
+
+                // the dns transport accepts every `/dns` address, so the websocket transport
+                // must be asked first.
+                let trans = ws_upgraded
+                    .or_transport(libp2p::dns::tokio::Transport::system(trans)?)
+                    .map(|either, _| either.into_inner())
+                    .boxed();
+                Ok(relayed(trans))
+            })
+            .map_err(|e| Error::Build(e.to_string()))?
+            .with_behaviour(|key| Behaviour {
+                kad: config
+                    .kad
+                    .enabled
+                    .then(|| {
+                        kad::Behaviour::new(
+                            key.public().to_peer_id(),
+                            MemoryStore::new(key.public().to_peer_id()),
+                        )
+                        .into()
+                    })
+                    .into(),
+                relay: config
+                    .relay
+                    .service
+                    .then(|| {
+                        behaviour::relay::Behaviour::new(
+                            relay::Behaviour::new(key.public().to_peer_id(), relay_cfg),
+                            acl.clone(),
+                        )
+                    })
+                    .into(),
+                relay_client,
+                dcutr: config
+                    .network
+                    .dcutr_port
+                    .map(|_| {
+                        behaviour::direct_client::Behaviour::new(
+                            dcutr::Behaviour::new(key.public().to_peer_id()),
+                            holepunch.clone(),
+                        )
+                    })
+                    .into(),
+                autonat: behaviour::autonat::Behaviour::new(
+                    autonat::Behaviour::new(
+                        key.public().to_peer_id(),
+                        autonat::Config {
+                            confidence_max: config.autonat.confidence_max,
+                            boot_delay: config.autonat.boot_delay,
+                            retry_interval: config.autonat.retry_interval,
+                            throttle_server_period: config.autonat.throttle_server_period,
+                            // the simulated network uses the IPs reserved for benchmarks, which
+                            // are not global.
+                            only_global_ips: !simulated,
+                            ..Default::default()
+                        },
+                    ),
+                    holepunch.clone(),
+                ),
+                ping: ping::Behaviour::default(),
+                identify: identify::Behaviour::new(identify::Config::new(
+                    config.identify.protocol_version.clone(),
+                    key.public(),
+                )),
+            })
+            .map_err(|e| Error::Build(e.to_string()))?
+            .with_swarm_config(|c| {
+                c.with_idle_connection_timeout(
+                    config
+                        .network
+                        .idle_connection_timeout
+                        .unwrap_or(Duration::from_secs(u64::MAX)),
+                )
+            })
+            .build();
+        info!(peer_id = %swarm.local_peer_id(), "local peer id");
+
+        let mut port_mapper = (config.port_mapping.enabled() && !config.network.memory)
+            .then(|| PortMapper::spawn(config.port_mapping.clone()));
+
+        let mut listen_ips = Vec::new();
+        if !config.network.memory {
+            listen_ips.push(IpAddr::from(Ipv4Addr::UNSPECIFIED));
+            if config.network.ipv6 {
+                listen_ips.push(IpAddr::from(Ipv6Addr::UNSPECIFIED));
+            }
+        }
+
+        for ip in listen_ips {
+            let mut listen_addrs =
+                vec![Multiaddr::from(ip).with(Protocol::Tcp(config.network.listen_port))];
+            if config.network.quic {
+                listen_addrs.push(
+                    Multiaddr::from(ip)
+                        .with(Protocol::Udp(config.network.listen_port))
+                        .with(Protocol::QuicV1),
+                );
+            }
+            if let Some(port) = config.websocket.port {
+                listen_addrs.push(
+                    Multiaddr::from(ip)
+                        .with(Protocol::Tcp(port))
+                        .with(Protocol::Ws("/".into())),
+                );
+            }
+            if let Some(port) = config.websocket.tls_port {
+                listen_addrs.push(
+                    Multiaddr::from(ip)
+                        .with(Protocol::Tcp(port))
+                        .with(Protocol::Wss("/".into())),
+                );
+            }
+            // libp2p-webrtc binds IPv6 sockets as dual-stack, which would take the port of the
+            // IPv4 listener.
+            if let Some(port) = config.webrtc.port.filter(|_| ip.is_ipv4()) {
+                listen_addrs.push(
+                    Multiaddr::from(ip)
+                        .with(Protocol::Udp(port))
+                        .with(Protocol::WebRTCDirect),
+                );
+            }
+            let holepunch_addr = config
+                .network
+                .dcutr_port
+                .map(|port| Multiaddr::from(ip).with(Protocol::Tcp(port)));
+
+            let listen_addrs = listen_addrs.into_iter().map(|addr| (addr, false));
+            for (listen_addr, is_holepunch) in listen_addrs.chain(holepunch_addr.map(|a| (a, true)))
+            {
+                if is_holepunch {
+                    holepunch.claim_listen_addr(&listen_addr);
+                }
+                match swarm.listen_on(listen_addr.clone()) {
+                    Ok(listener_id) => {
+                        if let Some(mapper) = port_mapper.as_mut() {
+                            let is_holepunch = holepunch.is_listener(listener_id);
+                            mapper.add_listener(listener_id, &listen_addr, is_holepunch);
+                        }
+                    }
+                    // hosts without IPv6 still run on IPv4 alone.
+                    Err(e) if ip.is_ipv6() => {
+                        warn!(%listen_addr, err = ?e, "IPv6 listener failed")
+                    }
+                    Err(e) => return Err(Error::Listen(e.to_string())),
+                }
+            }
+        }
+
+        for listen_addr in config.network.listen_addrs.iter().cloned() {
+            swarm
+                .listen_on(listen_addr)
+                .map_err(|e| Error::Listen(e.to_string()))?;
+        }
+        for addr in config.network.external_addrs.iter().cloned() {
+            swarm.add_external_address(addr);
+        }
+
+        let (commands_tx, commands_rx) = mpsc::channel(COMMAND_BUFFER);
+        let (events_tx, _) = broadcast::channel(EVENT_BUFFER);
+
+        // without relayed listeners the manager only keeps track of the relays, for dialing
+        // peers through them.
+        let relay_manager = RelayManager::new(match config.relay.listen_relayed {
+            true => config.relay.relayed_listeners,
+            false => 0,
+        });
+
+        Ok(RelayNode {
+            swarm,
+            commands_tx: Some(commands_tx),
+            commands_rx,
+            events_tx,
+            acl,
+            metrics,
+            metrics_listener,
+            metrics_server: None,
+            connections: Default::default(),
+            relayed_connections: Default::default(),
+            pending_kad: Default::default(),
+            peer_dials: Default::default(),
+            relay_manager,
+            hole_punch: HolePunchPolicy::new(config.dcutr.clone()),
+            nat: NatClassifier::new(config.network.tcp_port_reuse),
+            relayed_listeners: Default::default(),
+            port_mapper,
+        })
+    }
+
+    /// Build the node, wait for its listeners to come up and run it in the background.
+    pub async fn spawn(self) -> Result<NodeHandle, Error> {
+        let mut node = self.build()?;
+        node.wait_for_listeners().await;
+
+        let handle = node.handle();
+        tokio::spawn(node.run());
+        Ok(handle)
+    }
+}
+
+enum Command {
+    Dial {
+        addr: Multiaddr,
+        reply: oneshot::Sender<Result<(), Error>>,
+    },
+    DialPeer {
+        peer_id: PeerId,
+        reply: PeerDialReply,
+    },
+    Listen {
+        addr: Multiaddr,
+        reply: oneshot::Sender<Result<ListenerId, Error>>,
+    },
+    KadPut {
+        record: kad::Record,
+        peers: Option<Vec<PeerId>>,
+        reply: oneshot::Sender<Result<(), Error>>,
+    },
+    KadGet {
+        key: kad::RecordKey,
+        reply: oneshot::Sender<Result<Vec<u8>, Error>>,
+    },
+    Connections {
+        reply: oneshot::Sender<HashMap<PeerId, HashMap<ConnectionId, ConnectedPoint>>>,
+    },
+    CloseConnection {
+        connection_id: ConnectionId,
+        reply: oneshot::Sender<bool>,
+    },
+    NatStatus {
+        reply: oneshot::Sender<autonat::NatStatus>,
+    },
+    NatMapping {
+        reply: oneshot::Sender<NatMapping>,
+    },
+    RelayedListeners {
+        reply: oneshot::Sender<Vec<RelayedListener>>,
+    },
+    RemoveListener {
+        listener_id: ListenerId,
+        reply: oneshot::Sender<bool>,
+    },
+    HolePunchReports {
+        peer: Option<PeerId>,
+        reply: oneshot::Sender<Vec<HolePunchReport>>,
+    },
+    PortMappings {
+        reply: oneshot::Sender<Vec<PortMapping>>,
+    },
+    /// Handled by the event loop, which stops once it replied.
+    Shutdown {
+        reply: oneshot::Sender<()>,
+    },
+}
+
+enum PendingKad {
+    Put(oneshot::Sender<Result<(), Error>>),
+    Get(oneshot::Sender<Result<Vec<u8>, Error>>),
+    /// Lookup of a peer dialed with [`NodeHandle::dial_peer`].
+    Lookup(PeerId),
+}
+
+type PeerDialReply = oneshot::Sender<Result<(ConnectionId, ConnectedPoint), Error>>;
+
+/// A [`NodeHandle::dial_peer`] in progress.
+struct PeerDial {
+    stage: Option<PeerDialStage>,
+    /// The dial of the current stage.
+    dial: Option<ConnectionId>,
+    replies: Vec<PeerDialReply>,
+}
+
+/// The ways of reaching a peer known by its id only, in the order they are tried.
+#[derive(Debug, Clone, Copy)]
+enum PeerDialStage {
+    /// The addresses the behaviours know, from identify or the kademlia routing table.
+    Direct,
+    /// A kademlia lookup of the peer, which may connect to it.
+    Lookup,
+    /// The addresses learned during the lookup.
+    Found,
+    /// `/p2p-circuit` addresses through the known relays.
+    Relayed,
+}
+
+impl PeerDialStage {
+    fn after(stage: Option<Self>) -> Option<Self> {
+        match stage {
+            None => Some(PeerDialStage::Direct),
+            Some(PeerDialStage::Direct) => Some(PeerDialStage::Lookup),
+            Some(PeerDialStage::Lookup) => Some(PeerDialStage::Found),
+            Some(PeerDialStage::Found) => Some(PeerDialStage::Relayed),
+            Some(PeerDialStage::Relayed) => None,
+        }
+    }
+}
+
+/// A relay / DCUtR node, driven by [`RelayNode::run`].
+pub struct RelayNode {
+    swarm: Swarm<Behaviour>,
+    commands_tx: Option<mpsc::Sender<Command>>,
+    commands_rx: mpsc::Receiver<Command>,
+    events_tx: broadcast::Sender<TimedEvent>,
+    acl: Option<RelayAcl>,
+    metrics: Option<NodeMetrics>,
+    /// Bound by the builder, served once the node runs inside a runtime.
+    metrics_listener: Option<(std::net::TcpListener, Registry)>,
+    metrics_server: Option<JoinHandle<()>>,
+
+    connections: HashMap<PeerId, HashMap<ConnectionId, ConnectedPoint>>,
+    relayed_connections: HashMap<PeerId, HashSet<ConnectionId>>,
+    pending_kad: HashMap<kad::QueryId, PendingKad>,
+    peer_dials: HashMap<PeerId, PeerDial>,
+    relay_manager: RelayManager,
+    hole_punch: HolePunchPolicy,
+    nat: NatClassifier,
+    relayed_listeners: HashMap<ListenerId, RelayedListener>,
+    port_mapper: Option<PortMapper>,
+}
+
+impl RelayNode {
+    pub fn builder(keypair: Keypair) -> RelayNodeBuilder {
+        RelayNodeBuilder {
+            keypair,
+            config: Default::default(),
+            #[cfg(feature = "testing")]
+            sim_host: None,
+        }
+    }
+
+    pub fn local_peer_id(&self) -> PeerId {
+        *self.swarm.local_peer_id()
+    }
+
+    /// Create a new handle to control this node.
+    pub fn handle(&self) -> NodeHandle {
+        NodeHandle {
+            local_peer_id: self.local_peer_id(),
+            commands: self
+                .commands_tx
+                .clone()
+                .expect("only taken once the node is running"),
+            events: self.events_tx.clone(),
+        }
+    }
+
+    /// Drive the swarm for a short while so that it listens on all interfaces.
+    pub async fn wait_for_listeners(&mut self) {
+        let mut delay = futures_timer::Delay::new(Duration::from_secs(1));
+        loop {
+            tokio::select! {
+                event = self.swarm.select_next_some() => self.on_swarm_event(event),
+                _ = &mut delay => break,
+            }
+        }
+    }
+
+    /// Run the event loop until every [`NodeHandle`] has been dropped or
+    /// [`NodeHandle::shutdown`] is called.
+    pub async fn run(mut self) {
+        info!("Swarm Loop");
+
+        // the node only stops once no outside handle is left.
+        self.commands_tx.take();
+
+        if let Some((listener, registry)) = self.metrics_listener.take() {
+            match tokio::net::TcpListener::from_std(listener) {
+                Ok(listener) => {
+                    let server = tokio::spawn(metrics::serve(listener, Arc::new(registry)));
+                    self.metrics_server = Some(server);
+                }
+                Err(e) => warn!(err = %e, "metrics server failed"),
+            }
+        }
+
+        let mut hangup = self.acl.is_some().then(hangup_signal).flatten();
+        let mut tick = futures_timer::Delay::new(TICK);
+
+        loop {
+            tokio::select! {
+                event = self.swarm.select_next_some() => self.on_swarm_event(event),
+                _ = &mut tick => {
+                    self.manage_relays();
+                    self.retry_hole_punches();
+                    tick.reset(TICK);
+                }
+                _ = next_hangup(&mut hangup) => {
+                    if let Some(Err(e)) = self.acl.as_ref().map(RelayAcl::reload) {
+                        warn!(err = %e, "keeping the current relay acl");
+                    }
+                }
+                event = next_port_mapping(&mut self.port_mapper) => self.on_port_mapping(event),
+                command = self.commands_rx.recv() => match command {
+                    Some(Command::Shutdown { reply }) => {
+                        info!("shutting down");
+                        self.stop().await;
+                        let _ = reply.send(());
+                        return;
+                    }
+                    Some(command) => self.on_command(command),
+                    None => {
+                        info!("all handles dropped, stopping");
+                        self.stop().await;
+                        return;
+                    }
+                },
+            }
+        }
+    }
+
+    /// Remove what the node set up outside of itself, before it stops.
+    async fn stop(&mut self) {
+        if let Some(mapper) = self.port_mapper.as_mut() {
+            mapper.shutdown().await;
+        }
+    }
+
+    fn emit(&self, event: NodeEvent) {
+        // having no subscriber is fine.
+        let _ = self.events_tx.send(TimedEvent {
+            time: SystemTime::now(),
+            event,
+        });
+    }
+
+    fn on_command(&mut self, command: Command) {
+        match command {
+            Command::Dial { addr, reply } => {
+                let res = self
+                    .swarm
+                    .dial(addr)
+                    .map_err(|e| Error::Dial(e.to_string()));
+                let _ = reply.send(res);
+            }
+
+            Command::DialPeer { peer_id, reply } => {
+                // a direct connection is preferred over a relayed one.
+                let connected = self
+                    .connections
+                    .get(&peer_id)
+                    .and_then(|c| c.iter().min_by_key(|(_, endpoint)| endpoint.is_relayed()));
+                if let Some((connection_id, endpoint)) = connected {
+                    let _ = reply.send(Ok((*connection_id, endpoint.clone())));
+                    return;
+                }
+
+                match self.peer_dials.entry(peer_id) {
+                    hash_map::Entry::Occupied(mut dial) => dial.get_mut().replies.push(reply),
+                    hash_map::Entry::Vacant(entry) => {
+                        entry.insert(PeerDial {
+                            stage: None,
+                            dial: None,
+                            replies: vec![reply],
+                        });
+                        self.dial_peer_next(peer_id);
+                    }
+                }
+            }
+
+            Command::Listen { addr, reply } => {
+                let _ = reply.send(self.listen_on(addr));
+            }
+
+            Command::KadPut {
+                record,
+                peers,
+                reply,
+            } => {
+                let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() else {
+                    let _ = reply.send(Err(Error::KadDisabled));
+                    return;
+                };
+
+                let query_id = match peers {
+                    Some(peers) => Ok(kad.inner_mut().put_record_to(
+                        record,
+                        peers.into_iter(),
+                        kad::Quorum::One,
+                    )),
+                    None => kad.inner_mut().put_record(record, kad::Quorum::One),
+                };
+
+                match query_id {
+                    Ok(query_id) => {
+                        info!(?query_id, "put record");
+                        self.pending_kad.insert(query_id, PendingKad::Put(reply));
+                    }
+                    Err(e) => {
+                        let _ = reply.send(Err(Error::Kad(format!("{e:?}"))));
+                    }
+                }
+            }
+
+            Command::KadGet { key, reply } => {
+                let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() else {
+                    let _ = reply.send(Err(Error::KadDisabled));
+                    return;
+                };
+
+                let query_id = kad.inner_mut().get_record(key);
+                info!(?query_id, "get record");
+                self.pending_kad.insert(query_id, PendingKad::Get(reply));
+            }
+
+            Command::Connections { reply } => {
+                let _ = reply.send(self.connections.clone());
+            }
+
+            Command::CloseConnection {
+                connection_id,
+                reply,
+            } => {
+                let _ = reply.send(self.swarm.close_connection(connection_id));
+            }
+
+            Command::NatStatus { reply } => {
+                let _ = reply.send(self.swarm.behaviour().autonat.nat_status());
+            }
+
+            Command::NatMapping { reply } => {
+                let _ = reply.send(self.nat.mapping());
+            }
+
+            Command::RelayedListeners { reply } => {
+                let mut listeners = self.relayed_listeners.values().cloned().collect::<Vec<_>>();
+                listeners.sort_by_key(|l| l.requested_at);
+                let _ = reply.send(listeners);
+            }
+
+            Command::RemoveListener { listener_id, reply } => {
+                self.relay_manager.on_listener_removed(listener_id);
+                let _ = reply.send(self.swarm.remove_listener(listener_id));
+            }
+
+            Command::HolePunchReports { peer, reply } => {
+                let reports = self
+                    .swarm
+                    .behaviour()
+                    .dcutr
+                    .as_ref()
+                    .map(|dcutr| {
+                        dcutr
+                            .reports()
+                            .filter(|r| peer.is_none_or(|p| p == r.remote_peer_id))
+                            .cloned()
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                let _ = reply.send(reports);
+            }
+
+            Command::PortMappings { reply } => {
+                let mappings = self
+                    .port_mapper
+                    .as_ref()
+                    .map(PortMapper::mappings)
+                    .unwrap_or_default();
+                let _ = reply.send(mappings);
+            }
+
+            Command::Shutdown { .. } => unreachable!("handled by the event loop"),
+        }
+    }
+
+    fn close_relayed_connections(&mut self, peer_id: PeerId) {
+        if let Some(conns) = self.relayed_connections.remove(&peer_id) {
+            for conn in conns {
+                let closed = self.swarm.close_connection(conn);
+                info!(?conn, ?closed, "close relayed connection");
+            }
+        }
+    }
+
+    /// The address reaching `peer` through the relay of the relayed connection `conn`.
+    ///
+    /// A connection the peer opened through the reservation of this node only leads back to the
+    /// peer if it holds a reservation on the same relay.
+    fn circuit_to(&self, peer: PeerId, conn: ConnectionId) -> Option<Multiaddr> {
+        match self.connections.get(&peer)?.get(&conn)? {
+            ConnectedPoint::Dialer { address, .. } => Some(address.clone()),
+            ConnectedPoint::Listener { local_addr, .. } => {
+                let relay_addr = local_addr
+                    .iter()
+                    .take_while(|p| *p != Protocol::P2pCircuit)
+                    .collect::<Multiaddr>();
+                let (relay, relay_addr) = match relay_addr.iter().last() {
+                    Some(Protocol::P2p(relay)) => (relay, relay_addr),
+                    // the relay is the peer this node reached at that address.
+                    _ => {
+                        let (relay, _) = self.connections.iter().find(|(_, c)| {
+                            c.values().any(|e| *e.get_remote_address() == relay_addr)
+                        })?;
+                        (*relay, relay_addr.with(Protocol::P2p(*relay)))
+                    }
+                };
+                if !self.hole_punch.has_reservation(peer, relay) {
+                    info!(%peer, %relay, "the peer holds no known reservation on the relay");
+                    return None;
+                }
+                Some(
+                    relay_addr
+                        .with(Protocol::P2pCircuit)
+                        .with(Protocol::P2p(peer)),
+                )
+            }
+        }
+    }
+
+    /// Open a new relayed connection to each peer whose hole punch retry is due, for the peer
+    /// to attempt the upgrade.
+    fn retry_hole_punches(&mut self) {
+        for (peer_id, circuit) in self.hole_punch.due(Instant::now()) {
+            let _span = warn_span!("hole punch retry", %peer_id, %circuit).entered();
+            let opts = DialOpts::peer_id(peer_id)
+                .condition(PeerCondition::Always)
+                .addresses(vec![circuit])
+                .build();
+            match self.swarm.dial(opts) {
+                Ok(()) => info!("dialing"),
+                Err(e) => warn!(err = %e, "not dialed"),
+            }
+        }
+    }
+
+    /// Move a [`NodeHandle::dial_peer`] on to its next stage, fail it once none is left.
+    fn dial_peer_next(&mut self, peer_id: PeerId) {
+        loop {
+            let Some(dial) = self.peer_dials.get_mut(&peer_id) else {
+                return;
+            };
+            dial.dial = None;
+            dial.stage = PeerDialStage::after(dial.stage);
+            let Some(stage) = dial.stage else {
+                let dial = self.peer_dials.remove(&peer_id).expect("dial is pending");
+                warn!(%peer_id, "no route to peer");
+                for reply in dial.replies {
+                    let _ = reply.send(Err(Error::Dial(format!("no route to {peer_id}"))));
+                }
+                return;
+            };
+
+            let _span = warn_span!("dial peer", %peer_id, ?stage).entered();
+            let opts = match stage {
+                PeerDialStage::Direct | PeerDialStage::Found => DialOpts::peer_id(peer_id).build(),
+                PeerDialStage::Lookup => {
+                    let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() else {
+                        continue;
+                    };
+                    let query_id = kad.inner_mut().get_closest_peers(peer_id);
+                    info!(?query_id, "look up");
+                    self.pending_kad
+                        .insert(query_id, PendingKad::Lookup(peer_id));
+                    return;
+                }
+                PeerDialStage::Relayed => {
+                    let addrs = self
+                        .relay_manager
+                        .circuit_addrs()
+                        .into_iter()
+                        .filter(|(relay, _)| *relay != peer_id)
+                        .map(|(_, addr)| addr)
+                        .collect::<Vec<_>>();
+                    if addrs.is_empty() {
+                        continue;
+                    }
+                    // a dial the behaviours started, e.g. for the lookup, may still be pending.
+                    DialOpts::peer_id(peer_id)
+                        .condition(PeerCondition::Always)
+                        .addresses(addrs)
+                        .build()
+                }
+            };
+
+            let connection_id = opts.connection_id();
+            match self.swarm.dial(opts) {
+                Ok(()) => {
+                    info!("dialing");
+                    if let Some(dial) = self.peer_dials.get_mut(&peer_id) {
+                        dial.dial = Some(connection_id);
+                    }
+                    return;
+                }
+                Err(e) => debug!(err = %e, "not dialed"),
+            }
+        }
+    }
+
+    /// Start a listener, keeping track of it if it listens through a relay.
+    fn listen_on(&mut self, addr: Multiaddr) -> Result<ListenerId, Error> {
+        let listener_id = self
+            .swarm
+            .listen_on(addr.clone())
+            .map_err(|e| Error::Listen(e.to_string()))?;
+        if is_relayed(&addr) {
+            if let Some(listener) = RelayedListener::new(listener_id, addr) {
+                self.relayed_listeners.insert(listener_id, listener);
+            }
+        }
+        Ok(listener_id)
+    }
+
+    /// Update the relayed listener a reservation was accepted for.
+    fn on_reservation_accepted(&mut self, relay: PeerId, renewal: bool) -> Option<ListenerId> {
+        // the relay client does not tell which listener a reservation belongs to, the one
+        // waiting the longest for this relay is assumed.
+        let listener = self
+            .relayed_listeners
+            .values_mut()
+            .filter(|l| {
+                l.relay_peer_id == relay && (l.state == ReservationState::Accepted) == renewal
+            })
+            .min_by_key(|l| l.accepted_at.unwrap_or(l.requested_at))?;
+        listener.accepted(renewal);
+        Some(listener.listener_id)
+    }
+
+    fn on_swarm_event(&mut self, event: SwarmEvent<BehaviourEvent>) {
+        if let Some(metrics) = self.metrics.as_ref() {
+            metrics.record(&event);
+        }
+
+        match event {
+            SwarmEvent::NewListenAddr {
+                listener_id,
+                address,
+            } => {
+                info!(%address, "Listening on address");
+                if let Some(mapper) = self.port_mapper.as_mut() {
+                    mapper.on_new_listen_addr(listener_id, &address);
+                }
+                self.emit(NodeEvent::NewListenAddr {
+                    listener_id,
+                    address,
+                });
+            }
+
+            SwarmEvent::ExpiredListenAddr {
+                listener_id,
+                address,
+            } => {
+                info!(%address, "Listen address expired");
+                self.emit(NodeEvent::ExpiredListenAddr {
+                    listener_id,
+                    address,
+                });
+            }
+
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(evt)) => {
+                if let Some(metrics) = self.metrics.as_ref() {
+                    metrics.record(&evt);
+                }
+
+                if let identify::Event::Received { peer_id, info } = evt {
+                    self.on_identify_received(peer_id, info);
+                }
+            }
+
+            SwarmEvent::Behaviour(BehaviourEvent::Autonat(evt)) => {
+                info!(?evt, "autonat");
+                match evt {
+                    autonat::Event::StatusChanged { old, new } => {
+                        if let Some(metrics) = self.metrics.as_mut() {
+                            metrics.on_nat_status_changed(&old, &new);
+                        }
+
+                        self.emit(NodeEvent::NatStatusChanged { old, new });
+                    }
+                    autonat::Event::OutboundProbe(autonat::OutboundProbeEvent::Response {
+                        address,
+                        ..
+                    }) => {
+                        let confirmed = self
+                            .port_mapper
+                            .as_mut()
+                            .and_then(|mapper| mapper.confirm(&address));
+                        if let Some(mapping) = confirmed {
+                            info!(%address, "port mapping confirmed");
+                            self.emit(NodeEvent::PortMappingConfirmed(mapping));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            SwarmEvent::Behaviour(BehaviourEvent::Dcutr(direct_client::Event::Report(report))) => {
+                info!(?report, "hole punch report");
+                self.emit(NodeEvent::HolePunchReport(report));
+            }
+
+            SwarmEvent::Behaviour(BehaviourEvent::Dcutr(direct_client::Event::HolePunch(evt))) => {
+                info!(?evt, "DCUTR");
+                if let Some(metrics) = self.metrics.as_mut() {
+                    metrics.record(&evt);
+                    metrics.on_hole_punch(evt.result.as_ref().ok().copied());
+                }
+
+                let peer_id = evt.remote_peer_id;
+                match &evt.result {
+                    Ok(connection_id) => {
+                        self.hole_punch.on_success(peer_id);
+                        // the direct connection may be gone already.
+                        let confirmed = self
+                            .connections
+                            .get(&peer_id)
+                            .and_then(|c| c.get(connection_id))
+                            .is_some_and(|endpoint| !endpoint.is_relayed());
+                        if confirmed && self.hole_punch.close_relayed() {
+                            self.close_relayed_connections(peer_id);
+                        }
+                    }
+                    Err(_) => {
+                        let circuit = self.relayed_connections.get(&peer_id).and_then(|conns| {
+                            conns
+                                .iter()
+                                .find_map(|conn| self.circuit_to(peer_id, *conn))
+                        });
+                        let retry = circuit.is_some_and(|circuit| {
+                            self.hole_punch.on_failure(peer_id, circuit, Instant::now())
+                        });
+                        if !retry {
+                            info!(?peer_id, "keeping the relayed connection");
+                        }
+                    }
+                }
+
+                self.emit(NodeEvent::HolePunch {
+                    remote_peer_id: evt.remote_peer_id,
+                    result: evt.result.map_err(|e| e.to_string()),
+                });
+            }
+
+            SwarmEvent::Behaviour(BehaviourEvent::Kad(evt)) => {
+                info!(?evt, "kademlia");
+                if let Some(metrics) = self.metrics.as_ref() {
+                    metrics.record(&evt);
+                }
+
+                match evt {
+                    kad::Event::RoutingUpdated {
+                        peer, addresses, ..
+                    } => {
+                        self.relay_manager.add_peer(peer, addresses.into_vec());
+                        self.manage_relays();
+                        self.emit(NodeEvent::KadRoutingUpdated { peer });
+                    }
+
+                    kad::Event::OutboundQueryProgressed {
+                        id, result, step, ..
+                    } => self.on_kad_query_progressed(id, result, step),
+
+                    _ => {}
+                }
+            }
+
+            SwarmEvent::Behaviour(BehaviourEvent::Relay(evt)) => {
+                info!(?evt, "relay");
+                if let Some(metrics) = self.metrics.as_mut() {
+                    metrics.on_relay_event(&evt);
+                }
+
+                if let relay::Event::ReservationReqAccepted {
+                    src_peer_id,
+                    renewed,
+                } = evt
+                {
+                    self.emit(NodeEvent::ReservationGranted {
+                        peer_id: src_peer_id,
+                        renewed,
+                    });
+                }
+            }
+
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(evt)) => {
+                info!(?evt, "relay client");
+                if let relay::client::Event::ReservationReqAccepted {
+                    relay_peer_id,
+                    renewal,
+                    ..
+                } = evt
+                {
+                    self.relay_manager.on_reservation_accepted(relay_peer_id);
+                    let listener_id = self.on_reservation_accepted(relay_peer_id, renewal);
+                    self.emit(NodeEvent::ReservationAccepted {
+                        relay_peer_id,
+                        listener_id,
+                        renewal,
+                    });
+                }
+            }
+
+            SwarmEvent::Behaviour(BehaviourEvent::Ping(evt)) => {
+                debug!(?evt, "ping");
+                if let Some(metrics) = self.metrics.as_ref() {
+                    metrics.record(&evt);
+                }
+                if let Ok(rtt) = evt.result {
+                    self.relay_manager.on_rtt(evt.peer, rtt);
+                }
+            }
+
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                connection_id,
+                endpoint,
+                ..
+            } => {
+                info!(
+                    ?peer_id,
+                    ?connection_id,
+                    ?endpoint,
+                    "connection established"
+                );
+                if let Some(metrics) = self.metrics.as_mut() {
+                    metrics.on_connection_established(connection_id, &endpoint);
+                }
+
+                if endpoint.is_relayed() {
+                    self.relayed_connections
+                        .entry(peer_id)
+                        .or_default()
+                        .insert(connection_id);
+                }
+
+                self.connections
+                    .entry(peer_id)
+                    .or_default()
+                    .insert(connection_id, endpoint.clone());
+                if let Some(dial) = self.peer_dials.remove(&peer_id) {
+                    for reply in dial.replies {
+                        let _ = reply.send(Ok((connection_id, endpoint.clone())));
+                    }
+                }
+                self.emit(NodeEvent::ConnectionEstablished {
+                    peer_id,
+                    connection_id,
+                    endpoint,
+                });
+            }
+
+            SwarmEvent::OutgoingConnectionError {
+                connection_id,
+                peer_id: Some(peer_id),
+                error,
+            } => {
+                debug!(?peer_id, ?connection_id, err = %error, "outgoing connection failed");
+                let failed = self
+                    .peer_dials
+                    .get(&peer_id)
+                    .is_some_and(|dial| dial.dial == Some(connection_id));
+                if failed {
+                    self.dial_peer_next(peer_id);
+                }
+            }
+
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                connection_id,
+                endpoint,
+                num_established,
+                ..
+            } => {
+                info!(?peer_id, ?connection_id, "connection closed");
+                if let Some(metrics) = self.metrics.as_mut() {
+                    metrics.on_connection_closed(peer_id, connection_id, num_established);
+                }
+                if endpoint.is_relayed() {
+                    self.relayed_connections.entry(peer_id).and_modify(|set| {
+                        set.remove(&connection_id);
+                    });
+                }
+
+                let entry = self.connections.entry(peer_id);
+                let mut is_empty = false;
+                entry.and_modify(|c| {
+                    c.remove(&connection_id);
+                    is_empty = c.is_empty();
+                });
+                if is_empty {
+                    self.connections.remove(&peer_id);
+                    self.hole_punch.forget(peer_id);
+                }
+
+                self.emit(NodeEvent::ConnectionClosed {
+                    peer_id,
+                    connection_id,
+                    endpoint,
+                });
+            }
+
+            SwarmEvent::ListenerClosed {
+                listener_id,
+                addresses,
+                reason,
+            } => {
+                info!(?listener_id, ?addresses, ?reason, "listener closed");
+                self.relayed_listeners.remove(&listener_id);
+                self.relay_manager.on_listener_closed(listener_id);
+                self.manage_relays();
+
+                self.emit(NodeEvent::ListenerClosed {
+                    listener_id,
+                    addresses,
+                    error: reason.err().map(|e| e.to_string()),
+                });
+            }
+
+            event => {
+                debug!(?event, "OTHER EVENT<{}>", type_name_of_val(&event));
+            }
+        }
+    }
+
+    fn on_identify_received(&mut self, peer_id: PeerId, info: identify::Info) {
+        let _span = warn_span!("identify", ?peer_id).entered();
+        info!(?info, "received");
+
+        let is_relay_server = info.protocols.contains(&relay::HOP_PROTOCOL_NAME);
+        if is_relay_server {
+            info!("relay candidate");
+        }
+
+        // kademlia only learns the addresses of the peers it dialed by itself.
+        if info.protocols.contains(&kad::PROTOCOL_NAME) {
+            if let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() {
+                for addr in info.listen_addrs.iter().filter(|a| !is_relayed(a)) {
+                    kad.inner_mut().add_address(&peer_id, addr.clone());
+                }
+            }
+        }
+
+        self.on_observed(peer_id, &info.observed_addr);
+        self.hole_punch.on_listen_addrs(peer_id, &info.listen_addrs);
+
+        if is_relay_server {
+            // the address this node reached the relay at comes first.
+            let dialed = self.connections.get(&peer_id).and_then(|c| {
+                c.values().find_map(|point| match point {
+                    ConnectedPoint::Dialer {
+                        address,
+                        role_override: Endpoint::Dialer,
+                    } => Some(address.clone()),
+                    _ => None,
+                })
+            });
+            let addrs = dialed.into_iter().chain(info.listen_addrs.iter().cloned());
+            self.relay_manager.add_relay(peer_id, addrs.collect());
+        } else {
+            self.relay_manager.not_a_relay(peer_id);
+        }
+        self.manage_relays();
+
+        self.emit(NodeEvent::Identified {
+            peer_id,
+            info: Box::new(info),
+        });
+    }
+
+    /// Classify the NAT with the address `peer` observed this node at.
+    fn on_observed(&mut self, peer: PeerId, addr: &Multiaddr) {
+        // only connections dialed from the listen ports tell how the NAT maps them, hole punched
+        // ones are dialed from another port.
+        let dcutr = self.swarm.behaviour().dcutr.as_ref();
+        let dialed = self.connections.get(&peer).is_some_and(|conns| {
+            conns.iter().all(|(id, point)| {
+                point.is_dialer()
+                    && !point.is_relayed()
+                    && !dcutr.is_some_and(|d| d.is_hole_punched(*id))
+            })
+        });
+        if !dialed {
+            return;
+        }
+
+        let old = self.nat.mapping();
+        self.nat.observe(peer, addr);
+        let new = self.nat.mapping();
+
+        let predicted = self.nat.predicted_addrs();
+        if let Some(dcutr) = self.swarm.behaviour_mut().dcutr.as_mut() {
+            dcutr.add_candidates(&predicted);
+        }
+        if new != old {
+            info!(?old, ?new, "nat mapping");
+            self.emit(NodeEvent::NatMappingChanged { old, new });
+        }
+    }
+
+    fn on_port_mapping(&mut self, event: MappingEvent) {
+        match event {
+            MappingEvent::Mapped { mapping, previous } => {
+                info!(?mapping, "port mapped");
+                if let Some(previous) = previous.filter(|p| p.confirmed) {
+                    self.remove_mapped_address(&previous);
+                }
+                // the hole punch listener is only reached by the peers it dials, autonat could
+                // not confirm its address.
+                if mapping.holepunch {
+                    if let Some(dcutr) = self.swarm.behaviour_mut().dcutr.as_mut() {
+                        dcutr.add_candidates(std::slice::from_ref(&mapping.external_addr));
+                    }
+                } else {
+                    self.swarm
+                        .behaviour_mut()
+                        .autonat
+                        .probe_address(mapping.external_addr.clone());
+                }
+                self.emit(NodeEvent::PortMapped(mapping));
+            }
+
+            MappingEvent::Failed {
+                protocol,
+                local_port,
+                error,
+                lost,
+            } => {
+                warn!(%protocol, local_port, err = %error, "port mapping failed");
+                if let Some(lost) = lost.filter(|l| l.confirmed) {
+                    self.remove_mapped_address(&lost);
+                }
+                self.emit(NodeEvent::PortMappingFailed {
+                    protocol,
+                    local_port,
+                    error,
+                });
+            }
+        }
+    }
+
+    /// Stop announcing the address of a mapping autonat confirmed.
+    fn remove_mapped_address(&mut self, mapping: &PortMapping) {
+        // autonat confirms the addresses with the peer id of this node.
+        let addr = mapping
+            .external_addr
+            .clone()
+            .with(Protocol::P2p(self.local_peer_id()));
+        self.swarm.remove_external_address(&addr);
+    }
+
+    /// Carry out what the relay manager asks for.
+    fn manage_relays(&mut self) {
+        for action in self.relay_manager.poll(Instant::now()) {
+            match action {
+                RelayAction::Listen { relay, addr } => {
+                    let _span = warn_span!("relayed", listen_addr = %addr).entered();
+                    let listener = self
+                        .listen_on(addr)
+                        .inspect(|_| info!("listened"))
+                        .inspect_err(|e| warn!(err = ?e, "failed"))
+                        .ok();
+                    self.relay_manager.on_listen(relay, listener);
+                }
+
+                RelayAction::RemoveListener(id) => {
+                    self.swarm.remove_listener(id);
+                }
+
+                RelayAction::Probe { peer, addrs } => {
+                    let opts = DialOpts::peer_id(peer).addresses(addrs).build();
+                    if let Err(e) = self.swarm.dial(opts) {
+                        debug!(%peer, err = %e, "relay probe failed");
+                    }
+                }
+
+                RelayAction::FindPeers => {
+                    let local_peer_id = self.local_peer_id();
+                    if let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() {
+                        kad.inner_mut().get_closest_peers(local_peer_id);
+                    }
+                }
+            }
+        }
+    }
+
+    fn on_kad_query_progressed(
+        &mut self,
+        id: kad::QueryId,
+        result: kad::QueryResult,
+        step: kad::ProgressStep,
+    ) {
+        let reported = match result {
+            kad::QueryResult::PutRecord(res) => {
+                let key = match &res {
+                    Ok(ok) => ok.key.to_vec(),
+                    Err(e) => e.key().to_vec(),
+                };
+                let result = res.map(|_| ()).map_err(|e| e.to_string());
+                if let Some(PendingKad::Put(reply)) = self.pending_kad.remove(&id) {
+                    let _ = reply.send(result.clone().map_err(Error::Kad));
+                }
+                Some(KadQueryResult::PutRecord { key, result })
+            }
+
+            kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(found))) => {
+                if let Some(PendingKad::Get(reply)) = self.pending_kad.remove(&id) {
+                    let _ = reply.send(Ok(found.record.value.clone()));
+                }
+
+                // the first record is all we are interested in.
+                if let Some(mut query) = self
+                    .swarm
+                    .behaviour_mut()
+                    .kad
+                    .as_mut()
+                    .and_then(|kad| kad.inner_mut().query_mut(&id))
+                {
+                    query.finish();
+                }
+
+                Some(KadQueryResult::GetRecord {
+                    key: found.record.key.to_vec(),
+                    result: Ok(found.record.value),
+                })
+            }
+
+            // only follows a found record, which has already been reported.
+            kad::QueryResult::GetRecord(Ok(_)) => {
+                if let Some(PendingKad::Get(reply)) = self.pending_kad.remove(&id) {
+                    let _ = reply.send(Err(Error::Kad("record not found".to_string())));
+                }
+                None
+            }
+
+            kad::QueryResult::GetRecord(Err(e)) => {
+                if let Some(PendingKad::Get(reply)) = self.pending_kad.remove(&id) {
+                    let _ = reply.send(Err(Error::Kad(e.to_string())));
+                }
+                Some(KadQueryResult::GetRecord {
+                    key: e.key().to_vec(),
+                    result: Err(e.to_string()),
+                })
+            }
+
+            kad::QueryResult::GetClosestPeers(res) => {
+                if let Some(PendingKad::Lookup(peer_id)) = self.pending_kad.remove(&id) {
+                    self.dial_peer_next(peer_id);
+                }
+                Some(match res {
+                    Ok(ok) => KadQueryResult::GetClosestPeers {
+                        key: ok.key,
+                        result: Ok(ok.peers),
+                    },
+                    Err(e) => KadQueryResult::GetClosestPeers {
+                        key: e.key().clone(),
+                        result: Err(e.to_string()),
+                    },
+                })
+            }
+
+            kad::QueryResult::Bootstrap(res) => step.last.then(|| KadQueryResult::Bootstrap {
+                result: res.map(|_| ()).map_err(|e| e.to_string()),
+            }),
+
+            _ => {
+                if step.last {
+                    self.pending_kad.remove(&id);
+                }
+                None
+            }
+        };
+
+        if let Some(result) = reported {
+            self.emit(NodeEvent::KadQueryResult(result));
+        }
+    }
+}
+
+impl Drop for RelayNode {
+    fn drop(&mut self) {
+        if let Some(server) = self.metrics_server.take() {
+            server.abort();
+        }
+    }
+}
+
+/// Secure and multiplex the connections of a stream oriented transport.
+fn upgrade<T>(trans: T, noise: noise::Config, timeout: Duration) -> Boxed<(PeerId, StreamMuxerBox)>
+where
+    T: Transport + Send + Unpin + 'static,
+    T::Output: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    T::Error: Send + Sync + 'static,
+    T::Dial: Send + 'static,
+    T::ListenerUpgrade: Send + 'static,
+{
+    trans
+        .upgrade(libp2p::core::upgrade::Version::V1Lazy)
+        .authenticate(noise)
+        .multiplex(yamux::Config::default())
+        .timeout(timeout)
+        .boxed()
+}
+
+#[cfg(unix)]
+type HangupSignal = tokio::signal::unix::Signal;
+#[cfg(not(unix))]
+type HangupSignal = futures::future::Pending<()>;
+
+#[cfg(unix)]
+fn hangup_signal() -> Option<HangupSignal> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    signal(SignalKind::hangup())
+        .inspect_err(|e| warn!(err = ?e, "unable to listen for SIGHUP"))
+        .ok()
+}
+
+#[cfg(not(unix))]
+fn hangup_signal() -> Option<HangupSignal> {
+    None
+}
+
+/// Resolves on the next SIGHUP, never if the signal is not watched.
+async fn next_hangup(signal: &mut Option<HangupSignal>) {
+    match signal.as_mut() {
+        #[cfg(unix)]
+        Some(signal) => {
+            signal.recv().await;
+        }
+        _ => futures::future::pending().await,
+    }
+}
+
+/// Resolves on the next change of the port mappings, never without port mapping.
+async fn next_port_mapping(mapper: &mut Option<PortMapper>) -> MappingEvent {
+    match mapper.as_mut() {
+        Some(mapper) => mapper.next_event().await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Handle to a running [`RelayNode`], cheap to clone.
+#[derive(Clone)]
+pub struct NodeHandle {
+    local_peer_id: PeerId,
+    commands: mpsc::Sender<Command>,
+    events: broadcast::Sender<TimedEvent>,
+}
+
+impl NodeHandle {
+    pub fn local_peer_id(&self) -> PeerId {
+        self.local_peer_id
+    }
+
+    /// Subscribe to the events of the node.
+    pub fn subscribe(&self) -> broadcast::Receiver<TimedEvent> {
+        self.events.subscribe()
+    }
+
+    /// Resolves once the node stopped.
+    pub async fn closed(&self) {
+        self.commands.closed().await
+    }
+
+    async fn request<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<T>) -> Command,
+    ) -> Result<T, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.commands
+            .send(command(tx))
+            .await
+            .map_err(|_| Error::Shutdown)?;
+        rx.await.map_err(|_| Error::Shutdown)
+    }
+
+    /// Start dialing `addr`.
+    pub async fn dial(&self, addr: Multiaddr) -> Result<(), Error> {
+        self.request(|reply| Command::Dial { addr, reply }).await?
+    }
+
+    /// Connect to a peer known by its id only, through the addresses known for it, then those
+    /// found with a kademlia lookup, then through the known relays. Resolves to the
+    /// connection, which DCUtR upgrades when it is relayed.
+    pub async fn dial_peer(
+        &self,
+        peer_id: PeerId,
+    ) -> Result<(ConnectionId, ConnectedPoint), Error> {
+        self.request(|reply| Command::DialPeer { peer_id, reply })
+            .await?
+    }
+
+    /// Start listening on `addr`, which may be a relayed `/p2p-circuit` address.
+    pub async fn listen(&self, addr: Multiaddr) -> Result<ListenerId, Error> {
+        self.request(|reply| Command::Listen { addr, reply })
+            .await?
+    }
+
+    /// Store a record in the DHT, at the given peers or the closest ones if `peers` is `None`.
+    pub async fn kad_put(
+        &self,
+        key: impl AsRef<[u8]>,
+        value: Vec<u8>,
+        peers: Option<Vec<PeerId>>,
+    ) -> Result<(), Error> {
+        let record = kad::Record::new(kad::RecordKey::new(&key), value);
+        self.request(|reply| Command::KadPut {
+            record,
+            peers,
+            reply,
+        })
+        .await?
+    }
+
+    /// Look up the value of a record in the DHT.
+    pub async fn kad_get(&self, key: impl AsRef<[u8]>) -> Result<Vec<u8>, Error> {
+        let key = kad::RecordKey::new(&key);
+        self.request(|reply| Command::KadGet { key, reply }).await?
+    }
+
+    /// The currently established connections per peer.
+    pub async fn connections(
+        &self,
+    ) -> Result<HashMap<PeerId, HashMap<ConnectionId, ConnectedPoint>>, Error> {
+        self.request(|reply| Command::Connections { reply }).await
+    }
+
+    /// Close a connection, returns `false` if it was not open.
+    pub async fn close_connection(&self, connection_id: ConnectionId) -> Result<bool, Error> {
+        self.request(|reply| Command::CloseConnection {
+            connection_id,
+            reply,
+        })
+        .await
+    }
+
+    /// The NAT status as currently assumed by autonat.
+    pub async fn nat_status(&self) -> Result<autonat::NatStatus, Error> {
+        self.request(|reply| Command::NatStatus { reply }).await
+    }
+
+    /// Whether the NAT is a cone or a symmetric one, as far as the peers dialed observed.
+    pub async fn nat_mapping(&self) -> Result<NatMapping, Error> {
+        self.request(|reply| Command::NatMapping { reply }).await
+    }
+
+    /// The listeners through relays with their reservations, oldest first.
+    pub async fn relayed_listeners(&self) -> Result<Vec<RelayedListener>, Error> {
+        self.request(|reply| Command::RelayedListeners { reply })
+            .await
+    }
+
+    /// The diagnostics of the recent hole punch attempts, of all peers or of `peer` only. Those
+    /// still in progress come last.
+    pub async fn hole_punch_reports(
+        &self,
+        peer: Option<PeerId>,
+    ) -> Result<Vec<HolePunchReport>, Error> {
+        self.request(|reply| Command::HolePunchReports { peer, reply })
+            .await
+    }
+
+    /// The ports of the listen and hole punch listeners mapped by the router.
+    pub async fn port_mappings(&self) -> Result<Vec<PortMapping>, Error> {
+        self.request(|reply| Command::PortMappings { reply }).await
+    }
+
+    /// Stop the node once its port mappings are removed from the router.
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        self.request(|reply| Command::Shutdown { reply }).await
+    }
+
+    /// Stop a listener, returns `false` if it was not running.
+    pub async fn remove_listener(&self, listener_id: ListenerId) -> Result<bool, Error> {
+        self.request(|reply| Command::RemoveListener { listener_id, reply })
+            .await
+    }
+}
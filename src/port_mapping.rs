@@ -0,0 +1,630 @@
+//! Port mappings requested from the router, so that peers can dial the listen and hole punch
+//! ports of a node behind a home NAT.
+//!
+//! A background task looks for a UPnP IGD gateway first, then for a NAT-PMP server, maps the
+//! ports through the first one found and renews the mappings halfway through their lease. The
+//! mappings are removed when the node shuts down. PCP, the successor of NAT-PMP, is not
+//! supported.
+
+mod nat_pmp;
+#[cfg(feature = "testing")]
+pub mod sim;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::future;
+use futures_timer::Delay;
+use igd_next::{
+    aio::{self, tokio::Tokio},
+    AddPortError, PortMappingProtocol, SearchOptions,
+};
+use libp2p::{core::transport::ListenerId, multiaddr::Protocol, Multiaddr};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+use crate::config::PortMappingConfig;
+
+/// Description of the UPnP mappings, as shown by the router.
+const DESCRIPTION: &str = "libp2p-relay-demo";
+/// How long a UPnP gateway answering a search is waited for.
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(3);
+/// Timeout of the HTTP requests to a UPnP gateway.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Delay before a failed mapping is requested again, through a gateway searched anew.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+/// How long the removal of the mappings may delay the shutdown of the node.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PortProtocol {
+    Tcp,
+    /// Only mapped for QUIC.
+    Udp,
+}
+
+impl PortProtocol {
+    fn igd(self) -> PortMappingProtocol {
+        match self {
+            PortProtocol::Tcp => PortMappingProtocol::TCP,
+            PortProtocol::Udp => PortMappingProtocol::UDP,
+        }
+    }
+}
+
+impl fmt::Display for PortProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PortProtocol::Tcp => "tcp",
+            PortProtocol::Udp => "udp",
+        })
+    }
+}
+
+/// How a port was mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingMethod {
+    Upnp,
+    NatPmp,
+}
+
+impl fmt::Display for MappingMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MappingMethod::Upnp => "upnp",
+            MappingMethod::NatPmp => "nat-pmp",
+        })
+    }
+}
+
+/// A port of the node mapped by the router, see
+/// [`NodeHandle::port_mappings`](crate::NodeHandle::port_mappings).
+#[derive(Debug, Clone)]
+pub struct PortMapping {
+    pub protocol: PortProtocol,
+    pub local_port: u16,
+    /// The address of the router the port is reachable at, e.g. `/ip4/.../tcp/...`.
+    pub external_addr: Multiaddr,
+    pub method: MappingMethod,
+    /// The port of the hole punch listener, whose external address is only passed to DCUtR as
+    /// candidate since that listener only accepts the connections it dials itself.
+    pub holepunch: bool,
+    /// Autonat reached the node at `external_addr`, which is then an external address of the
+    /// node. Never the case for the hole punch port.
+    pub confirmed: bool,
+}
+
+/// What the node learns from a [`PortMapper`].
+#[derive(Debug)]
+pub(crate) enum MappingEvent {
+    /// A port was mapped, or mapped anew to another address than `previous`.
+    Mapped {
+        mapping: PortMapping,
+        previous: Option<PortMapping>,
+    },
+    /// A port could not be mapped, or its mapping could not be renewed and is `lost`.
+    Failed {
+        protocol: PortProtocol,
+        local_port: u16,
+        error: String,
+        lost: Option<PortMapping>,
+    },
+}
+
+enum Request {
+    Map {
+        protocol: PortProtocol,
+        port: u16,
+    },
+    /// Remove the mappings and stop.
+    Shutdown(oneshot::Sender<()>),
+}
+
+/// Sent by the task only when the state of a port changes, not on renewals.
+enum TaskEvent {
+    Mapped {
+        protocol: PortProtocol,
+        local_port: u16,
+        external: SocketAddrV4,
+        method: MappingMethod,
+    },
+    Failed {
+        protocol: PortProtocol,
+        local_port: u16,
+        error: String,
+    },
+}
+
+/// Maps the ports of the listeners of a node through a background task.
+pub(crate) struct PortMapper {
+    requests: mpsc::UnboundedSender<Request>,
+    events: mpsc::UnboundedReceiver<TaskEvent>,
+    /// The listeners whose ports are mapped, with whether they are the hole punch listener.
+    listeners: HashMap<ListenerId, bool>,
+    /// The ports requested, with whether they are the hole punch port.
+    requested: HashMap<(PortProtocol, u16), bool>,
+    mappings: HashMap<(PortProtocol, u16), PortMapping>,
+}
+
+impl PortMapper {
+    /// Start the task, which removes the mappings once the mapper is dropped.
+    pub(crate) fn spawn(config: PortMappingConfig) -> Self {
+        let (requests_tx, requests_rx) = mpsc::unbounded_channel();
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        let task = Task {
+            config,
+            gateway: None,
+            ports: HashMap::new(),
+            events: events_tx,
+        };
+        tokio::spawn(task.run(requests_rx));
+
+        PortMapper {
+            requests: requests_tx,
+            events: events_rx,
+            listeners: HashMap::new(),
+            requested: HashMap::new(),
+            mappings: HashMap::new(),
+        }
+    }
+
+    /// Map the port of a listener once it listens, if it listens on IPv4 with TCP or QUIC.
+    pub(crate) fn add_listener(
+        &mut self,
+        listener_id: ListenerId,
+        addr: &Multiaddr,
+        holepunch: bool,
+    ) {
+        if listen_port(addr).is_some() {
+            self.listeners.insert(listener_id, holepunch);
+        }
+    }
+
+    pub(crate) fn on_new_listen_addr(&mut self, listener_id: ListenerId, addr: &Multiaddr) {
+        let Some(holepunch) = self.listeners.get(&listener_id).copied() else {
+            return;
+        };
+        let Some(key @ (protocol, port)) = listen_port(addr) else {
+            return;
+        };
+        // a listener on all interfaces reports the same port for each of them.
+        if self.requested.insert(key, holepunch).is_none() {
+            let _ = self.requests.send(Request::Map { protocol, port });
+        }
+    }
+
+    /// The next change of the mappings, never resolves once the task stopped.
+    pub(crate) async fn next_event(&mut self) -> MappingEvent {
+        let Some(event) = self.events.recv().await else {
+            return future::pending().await;
+        };
+
+        match event {
+            TaskEvent::Mapped {
+                protocol,
+                local_port,
+                external,
+                method,
+            } => {
+                let key = (protocol, local_port);
+                let mapping = PortMapping {
+                    protocol,
+                    local_port,
+                    external_addr: external_addr(protocol, external),
+                    method,
+                    holepunch: self.requested.get(&key).copied().unwrap_or_default(),
+                    confirmed: false,
+                };
+                let previous = self.mappings.insert(key, mapping.clone());
+                MappingEvent::Mapped { mapping, previous }
+            }
+            TaskEvent::Failed {
+                protocol,
+                local_port,
+                error,
+            } => MappingEvent::Failed {
+                protocol,
+                local_port,
+                error,
+                lost: self.mappings.remove(&(protocol, local_port)),
+            },
+        }
+    }
+
+    /// Mark the mapping reached by autonat at `addr` as confirmed. Returns it unless it was
+    /// confirmed already.
+    pub(crate) fn confirm(&mut self, addr: &Multiaddr) -> Option<PortMapping> {
+        let addr = addr
+            .iter()
+            .filter(|p| !matches!(p, Protocol::P2p(_)))
+            .collect::<Multiaddr>();
+        let mapping = self
+            .mappings
+            .values_mut()
+            .find(|m| !m.holepunch && m.external_addr == addr)?;
+        if mapping.confirmed {
+            return None;
+        }
+        mapping.confirmed = true;
+        Some(mapping.clone())
+    }
+
+    /// The current mappings, by local port.
+    pub(crate) fn mappings(&self) -> Vec<PortMapping> {
+        let mut mappings = self.mappings.values().cloned().collect::<Vec<_>>();
+        mappings.sort_by_key(|m| (m.local_port, m.protocol == PortProtocol::Udp));
+        mappings
+    }
+
+    /// Remove the mappings from the router and stop the task.
+    pub(crate) async fn shutdown(&mut self) {
+        let (tx, rx) = oneshot::channel();
+        if self.requests.send(Request::Shutdown(tx)).is_err() {
+            return;
+        }
+        tokio::select! {
+            _ = rx => info!("port mappings removed"),
+            _ = Delay::new(SHUTDOWN_TIMEOUT) => warn!("port mappings not removed in time"),
+        }
+        self.mappings.clear();
+    }
+}
+
+/// The port a listener is bound to, if it is one the router can map.
+fn listen_port(addr: &Multiaddr) -> Option<(PortProtocol, u16)> {
+    match addr.iter().collect::<Vec<_>>()[..] {
+        [Protocol::Ip4(_), Protocol::Tcp(port)] => Some((PortProtocol::Tcp, port)),
+        [Protocol::Ip4(_), Protocol::Udp(port), Protocol::QuicV1] => {
+            Some((PortProtocol::Udp, port))
+        }
+        _ => None,
+    }
+}
+
+fn external_addr(protocol: PortProtocol, addr: SocketAddrV4) -> Multiaddr {
+    let ip = Multiaddr::from(*addr.ip());
+    match protocol {
+        PortProtocol::Tcp => ip.with(Protocol::Tcp(addr.port())),
+        PortProtocol::Udp => ip.with(Protocol::Udp(addr.port())).with(Protocol::QuicV1),
+    }
+}
+
+/// A port requested by the node.
+struct Port {
+    /// The address it is mapped to, with the gateway that mapped it and removes it on shutdown.
+    external: Option<(SocketAddrV4, Arc<Gateway>)>,
+    /// When to map or renew it.
+    due: Instant,
+    /// The last attempt failed, which was reported.
+    failed: bool,
+}
+
+struct Task {
+    config: PortMappingConfig,
+    gateway: Option<Arc<Gateway>>,
+    ports: HashMap<(PortProtocol, u16), Port>,
+    events: mpsc::UnboundedSender<TaskEvent>,
+}
+
+impl Task {
+    async fn run(mut self, mut requests: mpsc::UnboundedReceiver<Request>) {
+        loop {
+            let due = self.ports.values().map(|p| p.due).min();
+            let due = async {
+                match due {
+                    Some(due) => Delay::new(due.saturating_duration_since(Instant::now())).await,
+                    None => future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                request = requests.recv() => match request {
+                    Some(Request::Map { protocol, port }) => {
+                        self.ports.entry((protocol, port)).or_insert(Port {
+                            external: None,
+                            due: Instant::now(),
+                            failed: false,
+                        });
+                    }
+                    Some(Request::Shutdown(reply)) => {
+                        self.remove_all().await;
+                        let _ = reply.send(());
+                        return;
+                    }
+                    None => {
+                        self.remove_all().await;
+                        return;
+                    }
+                },
+                _ = due => self.refresh().await,
+            }
+        }
+    }
+
+    /// Map the ports that are due.
+    async fn refresh(&mut self) {
+        let now = Instant::now();
+        let due = self
+            .ports
+            .iter()
+            .filter(|(_, port)| port.due <= now)
+            .map(|(key, port)| (*key, port.external.as_ref().map(|(addr, _)| *addr)))
+            .collect::<Vec<_>>();
+
+        let gateway = match self.gateway.take() {
+            Some(gateway) => gateway,
+            None => match Gateway::discover(&self.config).await {
+                Ok(gateway) => {
+                    info!(method = %gateway.method(), "found a gateway for port mapping");
+                    Arc::new(gateway)
+                }
+                Err(e) => {
+                    warn!(err = %e, "no gateway for port mapping");
+                    for (key, _) in due {
+                        self.on_failure(key, e.clone());
+                    }
+                    return;
+                }
+            },
+        };
+
+        let mut failed = false;
+        for ((protocol, local_port), external) in due {
+            // the external port is kept on renewals, and the same as the local one if possible.
+            let external_port = external.map_or(local_port, |addr| addr.port());
+            let res = gateway
+                .map(
+                    protocol,
+                    local_port,
+                    external_port,
+                    self.config.lease_duration,
+                )
+                .await;
+            match res {
+                Ok((external, lease)) => {
+                    info!(%protocol, local_port, %external, ?lease, "port mapped");
+                    // permanent mappings are renewed in case the router forgot them.
+                    let lease = match lease.is_zero() {
+                        true => self.config.lease_duration,
+                        false => lease,
+                    };
+                    self.on_mapped((protocol, local_port), external, lease, &gateway);
+                }
+                Err(e) => {
+                    warn!(%protocol, local_port, err = %e, "port mapping failed");
+                    self.on_failure((protocol, local_port), e);
+                    failed = true;
+                }
+            }
+        }
+
+        // the gateway is searched anew on the next attempt, the router may have changed. The
+        // ports it mapped keep it to be removed on shutdown.
+        if !failed {
+            self.gateway = Some(gateway);
+        }
+    }
+
+    fn on_mapped(
+        &mut self,
+        key: (PortProtocol, u16),
+        external: SocketAddrV4,
+        lease: Duration,
+        gateway: &Arc<Gateway>,
+    ) {
+        let Some(port) = self.ports.get_mut(&key) else {
+            return;
+        };
+        port.due = Instant::now() + lease / 2;
+        port.failed = false;
+        let previous = port.external.replace((external, gateway.clone()));
+        if previous.map(|(addr, _)| addr) != Some(external) {
+            let _ = self.events.send(TaskEvent::Mapped {
+                protocol: key.0,
+                local_port: key.1,
+                external,
+                method: gateway.method(),
+            });
+        }
+    }
+
+    fn on_failure(&mut self, key: (PortProtocol, u16), error: String) {
+        let Some(port) = self.ports.get_mut(&key) else {
+            return;
+        };
+        port.due = Instant::now() + RETRY_INTERVAL;
+        let lost = port.external.take().is_some();
+        if lost || !port.failed {
+            let _ = self.events.send(TaskEvent::Failed {
+                protocol: key.0,
+                local_port: key.1,
+                error,
+            });
+        }
+        port.failed = true;
+    }
+
+    async fn remove_all(&mut self) {
+        for ((protocol, local_port), port) in self.ports.drain() {
+            let Some((external, gateway)) = port.external else {
+                continue;
+            };
+            match gateway.unmap(protocol, local_port, external.port()).await {
+                Ok(()) => info!(%protocol, local_port, %external, "port mapping removed"),
+                Err(e) => warn!(%protocol, local_port, err = %e, "port mapping not removed"),
+            }
+        }
+    }
+}
+
+enum Gateway {
+    Upnp {
+        gateway: aio::Gateway<Tokio>,
+        /// The address the gateway reaches this host at.
+        local_ip: Ipv4Addr,
+    },
+    NatPmp(nat_pmp::Client),
+}
+
+impl Gateway {
+    /// Look for a UPnP gateway, then for a NAT-PMP server, as enabled.
+    async fn discover(config: &PortMappingConfig) -> Result<Self, String> {
+        let mut errors = Vec::new();
+        if config.upnp {
+            match Gateway::discover_upnp(config.upnp_search_addr).await {
+                Ok(gateway) => return Ok(gateway),
+                Err(e) => errors.push(format!("upnp: {e}")),
+            }
+        }
+        if config.nat_pmp {
+            match Gateway::discover_nat_pmp(config.nat_pmp_gateway).await {
+                Ok(gateway) => return Ok(gateway),
+                Err(e) => errors.push(format!("nat-pmp: {e}")),
+            }
+        }
+        Err(errors.join(", "))
+    }
+
+    async fn discover_upnp(search_addr: Option<SocketAddr>) -> Result<Self, String> {
+        let mut options = SearchOptions {
+            timeout: Some(SEARCH_TIMEOUT),
+            ..Default::default()
+        };
+        if let Some(addr) = search_addr {
+            options.broadcast_address = addr;
+        }
+        let gateway = with_timeout(aio::tokio::search_gateway(options))
+            .await?
+            .map_err(|e| e.to_string())?;
+
+        let local_addr = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .and_then(|socket| {
+                socket.connect(gateway.addr)?;
+                socket.local_addr()
+            })
+            .map_err(|e| format!("no route to the gateway at {}: {e}", gateway.addr))?;
+        let IpAddr::V4(local_ip) = local_addr.ip() else {
+            return Err(format!("gateway at {} is not on IPv4", gateway.addr));
+        };
+        Ok(Gateway::Upnp { gateway, local_ip })
+    }
+
+    async fn discover_nat_pmp(server: Option<SocketAddr>) -> Result<Self, String> {
+        let server = match server {
+            Some(server) => server,
+            None => {
+                let gateway = nat_pmp::default_gateway().ok_or("no default gateway")?;
+                SocketAddr::from((gateway, nat_pmp::PORT))
+            }
+        };
+        let client = nat_pmp::Client::connect(server)
+            .await
+            .map_err(|e| e.to_string())?;
+        // most routers do not answer at all without NAT-PMP.
+        client.external_ip().await.map_err(|e| e.to_string())?;
+        Ok(Gateway::NatPmp(client))
+    }
+
+    fn method(&self) -> MappingMethod {
+        match self {
+            Gateway::Upnp { .. } => MappingMethod::Upnp,
+            Gateway::NatPmp(_) => MappingMethod::NatPmp,
+        }
+    }
+
+    /// Map `local_port` to `external_port` if possible, returns the mapped address and the
+    /// lease granted, zero if the mapping is permanent.
+    async fn map(
+        &self,
+        protocol: PortProtocol,
+        local_port: u16,
+        external_port: u16,
+        lease: Duration,
+    ) -> Result<(SocketAddrV4, Duration), String> {
+        let lease_secs = u32::try_from(lease.as_secs()).unwrap_or(u32::MAX);
+        match self {
+            Gateway::Upnp { gateway, local_ip } => {
+                let ip = match with_timeout(gateway.get_external_ip())
+                    .await?
+                    .map_err(|e| e.to_string())?
+                {
+                    IpAddr::V4(ip) => ip,
+                    ip => return Err(format!("external address {ip} is not IPv4")),
+                };
+                let local_addr = SocketAddr::from((*local_ip, local_port));
+                let add = |external_port, lease_secs| {
+                    with_timeout(gateway.add_port(
+                        protocol.igd(),
+                        external_port,
+                        local_addr,
+                        lease_secs,
+                        DESCRIPTION,
+                    ))
+                };
+
+                let (port, lease) = match add(external_port, lease_secs).await? {
+                    Ok(()) => (external_port, lease),
+                    Err(AddPortError::OnlyPermanentLeasesSupported) => {
+                        add(external_port, 0).await?.map_err(|e| e.to_string())?;
+                        (external_port, Duration::ZERO)
+                    }
+                    Err(AddPortError::PortInUse) => {
+                        let port = with_timeout(gateway.add_any_port(
+                            protocol.igd(),
+                            local_addr,
+                            lease_secs,
+                            DESCRIPTION,
+                        ))
+                        .await?
+                        .map_err(|e| e.to_string())?;
+                        (port, lease)
+                    }
+                    Err(e) => return Err(e.to_string()),
+                };
+                Ok((SocketAddrV4::new(ip, port), lease))
+            }
+            Gateway::NatPmp(client) => {
+                let ip = client.external_ip().await.map_err(|e| e.to_string())?;
+                let (port, lifetime) = client
+                    .map(protocol, local_port, external_port, lease_secs)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                Ok((
+                    SocketAddrV4::new(ip, port),
+                    Duration::from_secs(lifetime.into()),
+                ))
+            }
+        }
+    }
+
+    async fn unmap(
+        &self,
+        protocol: PortProtocol,
+        local_port: u16,
+        external_port: u16,
+    ) -> Result<(), String> {
+        match self {
+            Gateway::Upnp { gateway, .. } => {
+                with_timeout(gateway.remove_port(protocol.igd(), external_port))
+                    .await?
+                    .map_err(|e| e.to_string())
+            }
+            Gateway::NatPmp(client) => client
+                .unmap(protocol, local_port)
+                .await
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Fail a request to a UPnP gateway after [`REQUEST_TIMEOUT`], which its HTTP client lacks.
+async fn with_timeout<F: Future>(request: F) -> Result<F::Output, String> {
+    tokio::select! {
+        output = request => Ok(output),
+        _ = Delay::new(REQUEST_TIMEOUT) => Err("request timed out".to_string()),
+    }
+}
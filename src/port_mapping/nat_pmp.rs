@@ -0,0 +1,412 @@
+//! NAT-PMP, RFC 6886: the messages, shared with the stand-in gateway, and a client.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use futures_timer::Delay;
+use tokio::net::UdpSocket;
+
+use super::PortProtocol;
+
+/// The UDP port NAT-PMP servers listen on.
+pub(crate) const PORT: u16 = 5351;
+const VERSION: u8 = 0;
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP_UDP: u8 = 1;
+const OP_MAP_TCP: u8 = 2;
+/// Added to the opcode of a request in its response.
+const OP_RESPONSE: u8 = 128;
+/// Timeout of the first attempt of a request, doubled for every retransmission.
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+/// The RFC retransmits 9 times, which takes over a minute.
+const ATTEMPTS: u32 = 4;
+
+const RESULT_SUCCESS: u16 = 0;
+// the results only sent by a server, the stand-in gateway of the tests.
+#[cfg(any(test, feature = "testing"))]
+const RESULT_UNSUPPORTED_VERSION: u16 = 1;
+#[cfg(any(test, feature = "testing"))]
+pub(crate) const RESULT_NOT_AUTHORIZED: u16 = 2;
+#[cfg(any(test, feature = "testing"))]
+const RESULT_UNSUPPORTED_OPCODE: u16 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Request {
+    ExternalAddress,
+    /// Map `internal_port`, to `external_port` if possible. A lifetime of 0 removes the mapping.
+    Map {
+        protocol: PortProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Response {
+    ExternalAddress {
+        ip: Ipv4Addr,
+    },
+    Map {
+        protocol: PortProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    },
+    /// The request with opcode `op` failed with a non-zero result code.
+    Error {
+        op: u8,
+        result: u16,
+    },
+}
+
+impl Request {
+    pub(crate) fn op(&self) -> u8 {
+        match self {
+            Request::ExternalAddress => OP_EXTERNAL_ADDRESS,
+            Request::Map { protocol, .. } => map_op(*protocol),
+        }
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![VERSION, self.op()];
+        if let Request::Map {
+            internal_port,
+            external_port,
+            lifetime,
+            ..
+        } = self
+        {
+            buf.extend_from_slice(&[0, 0]);
+            buf.extend_from_slice(&internal_port.to_be_bytes());
+            buf.extend_from_slice(&external_port.to_be_bytes());
+            buf.extend_from_slice(&lifetime.to_be_bytes());
+        }
+        buf
+    }
+
+    /// The request in `buf`, or the result code to answer an unsupported one with.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn decode(buf: &[u8]) -> Option<Result<Self, (u8, u16)>> {
+        let [version, op, rest @ ..] = buf else {
+            return None;
+        };
+        if *version != VERSION {
+            return Some(Err((*op, RESULT_UNSUPPORTED_VERSION)));
+        }
+        let protocol = match *op {
+            OP_EXTERNAL_ADDRESS => return Some(Ok(Request::ExternalAddress)),
+            OP_MAP_UDP => PortProtocol::Udp,
+            OP_MAP_TCP => PortProtocol::Tcp,
+            op => return Some(Err((op, RESULT_UNSUPPORTED_OPCODE))),
+        };
+        let [_, _, i0, i1, e0, e1, l0, l1, l2, l3] = rest else {
+            return None;
+        };
+        Some(Ok(Request::Map {
+            protocol,
+            internal_port: u16::from_be_bytes([*i0, *i1]),
+            external_port: u16::from_be_bytes([*e0, *e1]),
+            lifetime: u32::from_be_bytes([*l0, *l1, *l2, *l3]),
+        }))
+    }
+}
+
+impl Response {
+    fn op(&self) -> u8 {
+        match self {
+            Response::ExternalAddress { .. } => OP_EXTERNAL_ADDRESS,
+            Response::Map { protocol, .. } => map_op(*protocol),
+            Response::Error { op, .. } => *op,
+        }
+    }
+
+    /// `epoch` is the number of seconds since the server started.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn encode(&self, epoch: u32) -> Vec<u8> {
+        let result = match self {
+            Response::Error { result, .. } => *result,
+            _ => RESULT_SUCCESS,
+        };
+        let mut buf = vec![VERSION, OP_RESPONSE + self.op()];
+        buf.extend_from_slice(&result.to_be_bytes());
+        buf.extend_from_slice(&epoch.to_be_bytes());
+        match self {
+            Response::ExternalAddress { ip } => buf.extend_from_slice(&ip.octets()),
+            Response::Map {
+                internal_port,
+                external_port,
+                lifetime,
+                ..
+            } => {
+                buf.extend_from_slice(&internal_port.to_be_bytes());
+                buf.extend_from_slice(&external_port.to_be_bytes());
+                buf.extend_from_slice(&lifetime.to_be_bytes());
+            }
+            Response::Error { .. } => {}
+        }
+        buf
+    }
+
+    pub(crate) fn decode(buf: &[u8]) -> Option<Self> {
+        let [VERSION, op, r0, r1, _, _, _, _, rest @ ..] = buf else {
+            return None;
+        };
+        let op = op.checked_sub(OP_RESPONSE)?;
+        let result = u16::from_be_bytes([*r0, *r1]);
+        if result != RESULT_SUCCESS {
+            return Some(Response::Error { op, result });
+        }
+
+        let protocol = match op {
+            OP_EXTERNAL_ADDRESS => {
+                let [a, b, c, d] = rest else {
+                    return None;
+                };
+                return Some(Response::ExternalAddress {
+                    ip: Ipv4Addr::new(*a, *b, *c, *d),
+                });
+            }
+            OP_MAP_UDP => PortProtocol::Udp,
+            OP_MAP_TCP => PortProtocol::Tcp,
+            _ => return None,
+        };
+        let [i0, i1, e0, e1, l0, l1, l2, l3] = rest else {
+            return None;
+        };
+        Some(Response::Map {
+            protocol,
+            internal_port: u16::from_be_bytes([*i0, *i1]),
+            external_port: u16::from_be_bytes([*e0, *e1]),
+            lifetime: u32::from_be_bytes([*l0, *l1, *l2, *l3]),
+        })
+    }
+}
+
+fn map_op(protocol: PortProtocol) -> u8 {
+    match protocol {
+        PortProtocol::Udp => OP_MAP_UDP,
+        PortProtocol::Tcp => OP_MAP_TCP,
+    }
+}
+
+fn result_error(result: u16) -> io::Error {
+    let reason = match result {
+        1 => "unsupported version",
+        2 => "not authorized",
+        3 => "network failure",
+        4 => "out of resources",
+        5 => "unsupported opcode",
+        _ => "unknown error",
+    };
+    io::Error::other(format!("NAT-PMP result {result}: {reason}"))
+}
+
+/// A client of the NAT-PMP server of a gateway.
+#[derive(Debug)]
+pub(crate) struct Client {
+    socket: UdpSocket,
+}
+
+impl Client {
+    pub(crate) async fn connect(gateway: SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.connect(gateway).await?;
+        Ok(Client { socket })
+    }
+
+    pub(crate) async fn external_ip(&self) -> io::Result<Ipv4Addr> {
+        match self.request(Request::ExternalAddress).await? {
+            Response::ExternalAddress { ip } => Ok(ip),
+            _ => Err(io::ErrorKind::InvalidData.into()),
+        }
+    }
+
+    /// Map `internal_port` to `external_port` if possible, returns the mapped external port and
+    /// the lifetime of the mapping in seconds.
+    pub(crate) async fn map(
+        &self,
+        protocol: PortProtocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    ) -> io::Result<(u16, u32)> {
+        let request = Request::Map {
+            protocol,
+            internal_port,
+            external_port,
+            lifetime,
+        };
+        match self.request(request).await? {
+            Response::Map {
+                internal_port: port,
+                external_port,
+                lifetime,
+                ..
+            } if port == internal_port => Ok((external_port, lifetime)),
+            _ => Err(io::ErrorKind::InvalidData.into()),
+        }
+    }
+
+    pub(crate) async fn unmap(&self, protocol: PortProtocol, internal_port: u16) -> io::Result<()> {
+        self.map(protocol, internal_port, 0, 0).await.map(|_| ())
+    }
+
+    /// Send `request` until it is answered, with the retransmissions of the RFC.
+    async fn request(&self, request: Request) -> io::Result<Response> {
+        let encoded = request.encode();
+        let mut timeout = INITIAL_TIMEOUT;
+        for _ in 0..ATTEMPTS {
+            self.socket.send(&encoded).await?;
+
+            let answer = async {
+                let mut buf = [0; 16];
+                loop {
+                    let n = self.socket.recv(&mut buf).await?;
+                    // a late answer to a previous request is skipped.
+                    match Response::decode(&buf[..n]) {
+                        Some(response) if response.op() == request.op() => {
+                            return io::Result::Ok(response)
+                        }
+                        _ => continue,
+                    }
+                }
+            };
+            tokio::select! {
+                response = answer => return match response? {
+                    Response::Error { result, .. } => Err(result_error(result)),
+                    response => Ok(response),
+                },
+                _ = Delay::new(timeout) => timeout *= 2,
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "no answer from the NAT-PMP server",
+        ))
+    }
+}
+
+/// The default IPv4 gateway of the host, from the routing table of Linux.
+pub(crate) fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    // Iface Destination Gateway ..., with the addresses in hex and host byte order.
+    routes.lines().skip(1).find_map(|line| {
+        let mut fields = line.split_whitespace().skip(1);
+        let (destination, gateway) = (fields.next()?, fields.next()?);
+        if destination != "00000000" {
+            return None;
+        }
+        let gateway = u32::from_str_radix(gateway, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_ne_bytes()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP_TCP: Request = Request::Map {
+        protocol: PortProtocol::Tcp,
+        internal_port: 4001,
+        external_port: 40001,
+        lifetime: 7200,
+    };
+
+    #[test]
+    fn requests_round_trip() {
+        let unmap = Request::Map {
+            protocol: PortProtocol::Udp,
+            internal_port: 4001,
+            external_port: 0,
+            lifetime: 0,
+        };
+        for request in [Request::ExternalAddress, MAP_TCP, unmap] {
+            assert_eq!(Request::decode(&request.encode()), Some(Ok(request)));
+        }
+        assert_eq!(
+            MAP_TCP.encode(),
+            [0, 2, 0, 0, 0x0f, 0xa1, 0x9c, 0x41, 0, 0, 0x1c, 0x20]
+        );
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        let responses = [
+            Response::ExternalAddress {
+                ip: Ipv4Addr::new(203, 0, 113, 7),
+            },
+            Response::Map {
+                protocol: PortProtocol::Udp,
+                internal_port: 4001,
+                external_port: 40001,
+                lifetime: 3600,
+            },
+            Response::Error {
+                op: OP_MAP_TCP,
+                result: RESULT_NOT_AUTHORIZED,
+            },
+        ];
+        for response in responses {
+            assert_eq!(Response::decode(&response.encode(42)), Some(response));
+        }
+    }
+
+    #[test]
+    fn short_buffers_are_rejected() {
+        let request = MAP_TCP.encode();
+        assert_eq!(Request::decode(&request[..1]), None);
+        assert_eq!(Request::decode(&request[..request.len() - 1]), None);
+
+        let response = Response::Map {
+            protocol: PortProtocol::Tcp,
+            internal_port: 4001,
+            external_port: 40001,
+            lifetime: 3600,
+        }
+        .encode(42);
+        for len in [0, 4, 8, response.len() - 1] {
+            assert_eq!(Response::decode(&response[..len]), None, "{len} bytes");
+        }
+    }
+
+    #[test]
+    fn unsupported_version_and_opcode_are_answered_with_an_error() {
+        let mut request = MAP_TCP.encode();
+        request[0] = 1;
+        assert_eq!(
+            Request::decode(&request),
+            Some(Err((OP_MAP_TCP, RESULT_UNSUPPORTED_VERSION)))
+        );
+        assert_eq!(
+            Request::decode(&[VERSION, 9]),
+            Some(Err((9, RESULT_UNSUPPORTED_OPCODE)))
+        );
+
+        let mut response = Response::ExternalAddress {
+            ip: Ipv4Addr::LOCALHOST,
+        }
+        .encode(42);
+        response[0] = 1;
+        assert_eq!(Response::decode(&response), None);
+    }
+
+    #[test]
+    fn a_request_is_not_a_response() {
+        assert_eq!(Response::decode(&MAP_TCP.encode()), None);
+    }
+
+    #[test]
+    fn error_result_is_decoded_without_a_body() {
+        let response = [VERSION, OP_RESPONSE + OP_MAP_UDP, 0, 3, 0, 0, 0, 42];
+        assert_eq!(
+            Response::decode(&response),
+            Some(Response::Error {
+                op: OP_MAP_UDP,
+                result: 3
+            })
+        );
+        assert!(result_error(3).to_string().contains("network failure"));
+    }
+}
@@ -0,0 +1,448 @@
+//! A stand-in home router for tests, answering UPnP IGD and NAT-PMP requests on the loopback
+//! interface and forwarding the ports it maps through the NAT of a [`SimHost`].
+//!
+//! Point `port_mapping.upnp_search_addr` at [`SimGateway::ssdp_addr`] or
+//! `port_mapping.nat_pmp_gateway` at [`SimGateway::nat_pmp_addr`]. Only TCP mappings are
+//! forwarded, as the simulated network carries nothing else, and mappings never expire.
+//!
+//! Only built with the `testing` feature.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+use super::nat_pmp::{self, Request, Response};
+use super::PortProtocol;
+use crate::transport::sim::SimHost;
+
+const DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const SERVICE_TYPE: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+const ROOT_DESC_PATH: &str = "/rootDesc.xml";
+const SCPD_PATH: &str = "/WANIPCn.xml";
+const CONTROL_PATH: &str = "/ctl/IPConn";
+
+const INVALID_ACTION: u16 = 401;
+const INVALID_ARGS: u16 = 402;
+const ACTION_NOT_AUTHORIZED: u16 = 606;
+const NO_SUCH_ENTRY: u16 = 714;
+const CONFLICT_IN_MAPPING_ENTRY: u16 = 718;
+
+/// The actions of the gateway with their input arguments, as listed in its service description.
+const ACTIONS: [(&str, &[&str]); 3] = [
+    ("GetExternalIPAddress", &[]),
+    (
+        "AddPortMapping",
+        &[
+            "NewRemoteHost",
+            "NewExternalPort",
+            "NewProtocol",
+            "NewInternalPort",
+            "NewInternalClient",
+            "NewEnabled",
+            "NewPortMappingDescription",
+            "NewLeaseDuration",
+        ],
+    ),
+    (
+        "DeletePortMapping",
+        &["NewRemoteHost", "NewExternalPort", "NewProtocol"],
+    ),
+];
+
+/// A port mapped by a [`SimGateway`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GatewayMapping {
+    pub protocol: PortProtocol,
+    pub external_port: u16,
+    pub internal_port: u16,
+}
+
+/// The router of a host behind a simulated NAT, see the [module docs](self).
+pub struct SimGateway {
+    ssdp_addr: SocketAddr,
+    nat_pmp_addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+struct State {
+    host: SimHost,
+    started: Instant,
+    /// Internal port by protocol and external port.
+    mappings: HashMap<(PortProtocol, u16), u16>,
+    /// Internal ports the gateway refuses to map.
+    refused: HashSet<u16>,
+}
+
+impl SimGateway {
+    pub async fn start(host: SimHost) -> io::Result<Self> {
+        let http = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let ssdp = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let nat_pmp = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let location = format!("http://{}{ROOT_DESC_PATH}", http.local_addr()?);
+        let (ssdp_addr, nat_pmp_addr) = (ssdp.local_addr()?, nat_pmp.local_addr()?);
+
+        let state = Arc::new(Mutex::new(State {
+            host,
+            started: Instant::now(),
+            mappings: HashMap::new(),
+            refused: HashSet::new(),
+        }));
+        let tasks = vec![
+            tokio::spawn(serve_ssdp(ssdp, location)),
+            tokio::spawn(serve_http(http, state.clone())),
+            tokio::spawn(serve_nat_pmp(nat_pmp, state.clone())),
+        ];
+
+        Ok(SimGateway {
+            ssdp_addr,
+            nat_pmp_addr,
+            state,
+            tasks,
+        })
+    }
+
+    /// Where to search for the gateway with UPnP.
+    pub fn ssdp_addr(&self) -> SocketAddr {
+        self.ssdp_addr
+    }
+
+    pub fn nat_pmp_addr(&self) -> SocketAddr {
+        self.nat_pmp_addr
+    }
+
+    /// Refuse to map `internal_port` from now on, as a router restricting its mappings does.
+    pub fn refuse_port(&self, internal_port: u16) {
+        lock(&self.state).refused.insert(internal_port);
+    }
+
+    /// The current mappings, by external port.
+    pub fn mappings(&self) -> Vec<GatewayMapping> {
+        let mut mappings = lock(&self.state)
+            .mappings
+            .iter()
+            .map(
+                |((protocol, external_port), internal_port)| GatewayMapping {
+                    protocol: *protocol,
+                    external_port: *external_port,
+                    internal_port: *internal_port,
+                },
+            )
+            .collect::<Vec<_>>();
+        mappings.sort_by_key(|m| (m.external_port, m.protocol == PortProtocol::Udp));
+        mappings
+    }
+}
+
+impl Drop for SimGateway {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl State {
+    /// Map `external_port` to `internal_port`, returns `false` if it is mapped to another port.
+    fn add(&mut self, protocol: PortProtocol, external_port: u16, internal_port: u16) -> bool {
+        if let Some(port) = self.mappings.get(&(protocol, external_port)) {
+            return *port == internal_port;
+        }
+        if protocol == PortProtocol::Tcp && !self.host.forward_port(external_port, internal_port) {
+            return false;
+        }
+        self.mappings
+            .insert((protocol, external_port), internal_port);
+        true
+    }
+
+    fn remove(&mut self, protocol: PortProtocol, external_port: u16) -> bool {
+        let removed = self.mappings.remove(&(protocol, external_port)).is_some();
+        if removed && protocol == PortProtocol::Tcp {
+            self.host.remove_forwarded_port(external_port);
+        }
+        removed
+    }
+}
+
+async fn serve_ssdp(socket: UdpSocket, location: String) {
+    let mut buf = [0; 1024];
+    while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+        if !buf[..n].starts_with(b"M-SEARCH") {
+            continue;
+        }
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nST: {DEVICE_TYPE}\r\nUSN: uuid:sim-gateway::{DEVICE_TYPE}\r\nLOCATION: {location}\r\n\r\n"
+        );
+        let _ = socket.send_to(response.as_bytes(), from).await;
+    }
+}
+
+async fn serve_http(listener: TcpListener, state: Arc<Mutex<State>>) {
+    while let Ok((stream, remote)) = listener.accept().await {
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(stream, &state).await {
+                debug!(%remote, err = ?e, "gateway request failed");
+            }
+        });
+    }
+}
+
+async fn handle_request(mut stream: TcpStream, state: &Mutex<State>) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    let head_len = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 || buf.len() > 8192 {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+
+    let head = String::from_utf8_lossy(&buf[..head_len]).into_owned();
+    let header = |name: &str| {
+        head.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    };
+    let content_length = header("content-length")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    while buf.len() < head_len + content_length {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let body = String::from_utf8_lossy(&buf[head_len..]);
+
+    let mut request_line = head.split_whitespace();
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some(ROOT_DESC_PATH)) => ("200 OK", root_description()),
+        (Some("GET"), Some(SCPD_PATH)) => ("200 OK", service_description()),
+        (Some("POST"), Some(CONTROL_PATH)) => {
+            // "urn:...:WANIPConnection:1#Action"
+            let action = header("soapaction")
+                .and_then(|v| v.trim_matches('"').split_once('#'))
+                .map(|(_, action)| action)
+                .unwrap_or_default();
+            match control(&mut lock(state), action, &body) {
+                Ok(body) => ("200 OK", body),
+                Err(code) => ("500 Internal Server Error", fault(code)),
+            }
+        }
+        _ => ("404 Not Found", String::new()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/xml\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Carry out a SOAP action, returns the envelope of its response or a UPnP error code.
+fn control(state: &mut State, action: &str, body: &str) -> Result<String, u16> {
+    let arg = |name: &str| {
+        let start = body.find(&format!("<{name}>"))? + name.len() + 2;
+        let end = start + body[start..].find(&format!("</{name}>"))?;
+        Some(body[start..end].trim())
+    };
+    let port = |name: &str| {
+        arg(name)
+            .and_then(|v| v.parse::<u16>().ok())
+            .ok_or(INVALID_ARGS)
+    };
+    let protocol = || match arg("NewProtocol") {
+        Some("TCP") => Ok(PortProtocol::Tcp),
+        Some("UDP") => Ok(PortProtocol::Udp),
+        _ => Err(INVALID_ARGS),
+    };
+
+    let args = match action {
+        "GetExternalIPAddress" => format!(
+            "<NewExternalIPAddress>{}</NewExternalIPAddress>",
+            state.host.public_ip()
+        ),
+        "AddPortMapping" => {
+            let (protocol, external_port) = (protocol()?, port("NewExternalPort")?);
+            let internal_port = port("NewInternalPort")?;
+            if state.refused.contains(&internal_port) {
+                return Err(ACTION_NOT_AUTHORIZED);
+            }
+            if !state.add(protocol, external_port, internal_port) {
+                return Err(CONFLICT_IN_MAPPING_ENTRY);
+            }
+            String::new()
+        }
+        "DeletePortMapping" => {
+            if !state.remove(protocol()?, port("NewExternalPort")?) {
+                return Err(NO_SUCH_ENTRY);
+            }
+            String::new()
+        }
+        _ => return Err(INVALID_ACTION),
+    };
+
+    Ok(envelope(&format!(
+        r#"<u:{action}Response xmlns:u="{SERVICE_TYPE}">{args}</u:{action}Response>"#
+    )))
+}
+
+fn envelope(body: &str) -> String {
+    format!(
+        r#"<?xml version="1.0"?>
+<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body>{body}</s:Body></s:Envelope>"#
+    )
+}
+
+fn fault(code: u16) -> String {
+    let description = match code {
+        INVALID_ACTION => "Invalid Action",
+        INVALID_ARGS => "Invalid Args",
+        ACTION_NOT_AUTHORIZED => "Action not authorized",
+        NO_SUCH_ENTRY => "NoSuchEntryInArray",
+        CONFLICT_IN_MAPPING_ENTRY => "ConflictInMappingEntry",
+        _ => "Action Failed",
+    };
+    envelope(&format!(
+        r#"<s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring><detail><UPnPError xmlns="urn:schemas-upnp-org:control-1-0"><errorCode>{code}</errorCode><errorDescription>{description}</errorDescription></UPnPError></detail></s:Fault>"#
+    ))
+}
+
+fn root_description() -> String {
+    format!(
+        r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+<specVersion><major>1</major><minor>0</minor></specVersion>
+<device>
+<deviceType>{DEVICE_TYPE}</deviceType>
+<friendlyName>Simulated gateway</friendlyName>
+<serviceList>
+<service>
+<serviceType>{SERVICE_TYPE}</serviceType>
+<serviceId>urn:upnp-org:serviceId:WANIPConn1</serviceId>
+<SCPDURL>{SCPD_PATH}</SCPDURL>
+<controlURL>{CONTROL_PATH}</controlURL>
+<eventSubURL>/evt/IPConn</eventSubURL>
+</service>
+</serviceList>
+</device>
+</root>"#
+    )
+}
+
+fn service_description() -> String {
+    let actions = ACTIONS
+        .iter()
+        .map(|(name, args)| {
+            let args = args
+                .iter()
+                .map(|arg| {
+                    format!("<argument><name>{arg}</name><direction>in</direction></argument>")
+                })
+                .collect::<String>();
+            format!("<action><name>{name}</name><argumentList>{args}</argumentList></action>")
+        })
+        .collect::<String>();
+    format!(
+        r#"<?xml version="1.0"?>
+<scpd xmlns="urn:schemas-upnp-org:service-1-0">
+<specVersion><major>1</major><minor>0</minor></specVersion>
+<actionList>{actions}</actionList>
+</scpd>"#
+    )
+}
+
+async fn serve_nat_pmp(socket: UdpSocket, state: Arc<Mutex<State>>) {
+    let mut buf = [0; 16];
+    while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+        let response = {
+            let mut state = lock(&state);
+            let response = match Request::decode(&buf[..n]) {
+                None => continue,
+                Some(Err((op, result))) => Response::Error { op, result },
+                Some(Ok(request)) => nat_pmp_response(&mut state, request),
+            };
+            let epoch = state.started.elapsed().as_secs() as u32;
+            response.encode(epoch)
+        };
+        let _ = socket.send_to(&response, from).await;
+    }
+}
+
+fn nat_pmp_response(state: &mut State, request: Request) -> Response {
+    match request {
+        Request::ExternalAddress => Response::ExternalAddress {
+            ip: state.host.public_ip(),
+        },
+        Request::Map { internal_port, .. } if state.refused.contains(&internal_port) => {
+            Response::Error {
+                op: request.op(),
+                result: nat_pmp::RESULT_NOT_AUTHORIZED,
+            }
+        }
+        Request::Map {
+            protocol,
+            internal_port,
+            external_port,
+            lifetime,
+        } => {
+            let mapped = state
+                .mappings
+                .iter()
+                .find(|((p, _), port)| *p == protocol && **port == internal_port)
+                .map(|((_, external), _)| *external);
+            if lifetime == 0 {
+                if let Some(external) = mapped {
+                    state.remove(protocol, external);
+                }
+                return Response::Map {
+                    protocol,
+                    internal_port,
+                    external_port: 0,
+                    lifetime: 0,
+                };
+            }
+
+            // the suggested port if it is free, else the next free one.
+            let external_port = match mapped {
+                Some(external) => external,
+                None => {
+                    let mut port = match external_port {
+                        0 => internal_port,
+                        port => port,
+                    };
+                    while !state.add(protocol, port, internal_port) {
+                        port = port.wrapping_add(1).max(1024);
+                    }
+                    port
+                }
+            };
+            Response::Map {
+                protocol,
+                internal_port,
+                external_port,
+                lifetime,
+            }
+        }
+    }
+}
@@ -0,0 +1,440 @@
+//! Choice of the relays a node listens through.
+//!
+//! Relays are learned from identify, or probed among the peers of the kademlia routing table,
+//! and reserved with in order of ping RTT. The relay client renews accepted reservations by
+//! itself, a reservation that is not accepted in time or whose listener closes is replaced and
+//! its relay retried later. A relay whose listener is removed on request is not listened
+//! through again.
+//!
+//! The known relays are also those peers are dialed through when no direct address is known.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use libp2p::{core::transport::ListenerId, multiaddr::Protocol, Multiaddr, PeerId};
+use tracing::{debug, info};
+
+/// How long a relay has to accept a reservation.
+const RESERVATION_TIMEOUT: Duration = Duration::from_secs(30);
+/// Delay before a failed relay is retried, doubled on every further failure.
+const RETRY_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30 * 60);
+/// Kademlia peers dialed at the same time to find out whether they are relays.
+const MAX_PROBES: usize = 2;
+/// Interval of the kademlia lookups for more peers while relays are missing.
+const LOOKUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What the node must do for the manager.
+#[derive(Debug)]
+pub(crate) enum RelayAction {
+    /// Listen on this `/p2p-circuit` address, then report the listener with
+    /// [`RelayManager::on_listen`].
+    Listen {
+        relay: PeerId,
+        addr: Multiaddr,
+    },
+    RemoveListener(ListenerId),
+    /// Dial a peer to learn its protocols through identify.
+    Probe {
+        peer: PeerId,
+        addrs: Vec<Multiaddr>,
+    },
+    /// Look up more peers with kademlia.
+    FindPeers,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    addrs: Vec<Multiaddr>,
+    rtt: Option<Duration>,
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Candidate {
+    fn circuit_addr(&self, relay: PeerId) -> Multiaddr {
+        let addr = self.addrs[0].clone();
+        let addr = match addr.iter().last() {
+            Some(Protocol::P2p(_)) => addr,
+            _ => addr.with(Protocol::P2p(relay)),
+        };
+        addr.with(Protocol::P2pCircuit)
+    }
+}
+
+#[derive(Debug)]
+struct Reservation {
+    relay: PeerId,
+    requested_at: Instant,
+    accepted: bool,
+}
+
+pub(crate) struct RelayManager {
+    /// Number of reservations to keep.
+    target: usize,
+    candidates: HashMap<PeerId, Candidate>,
+    reservations: HashMap<ListenerId, Reservation>,
+    /// Peers that are not known to be relays yet, with their addresses.
+    unprobed: VecDeque<(PeerId, Vec<Multiaddr>)>,
+    probing: HashMap<PeerId, Instant>,
+    probed: HashSet<PeerId>,
+    /// Relays whose listener was removed on request, not listened through again. Peers are still
+    /// dialed through them.
+    dismissed: HashSet<PeerId>,
+    /// Lookups are useless until kademlia knows a peer.
+    kad_peers: bool,
+    last_lookup: Option<Instant>,
+}
+
+impl RelayManager {
+    pub(crate) fn new(target: usize) -> Self {
+        RelayManager {
+            target,
+            candidates: Default::default(),
+            reservations: Default::default(),
+            unprobed: Default::default(),
+            probing: Default::default(),
+            probed: Default::default(),
+            dismissed: Default::default(),
+            kad_peers: false,
+            last_lookup: None,
+        }
+    }
+
+    /// A peer announced the relay hop protocol. `addrs` are tried in order.
+    pub(crate) fn add_relay(&mut self, peer: PeerId, addrs: Vec<Multiaddr>) {
+        self.probing.remove(&peer);
+        self.probed.insert(peer);
+
+        let addrs = addrs
+            .into_iter()
+            .filter(|a| !is_relayed(a))
+            .collect::<Vec<_>>();
+        if addrs.is_empty() {
+            return;
+        }
+        match self.candidates.get_mut(&peer) {
+            Some(candidate) => candidate.addrs = addrs,
+            None => {
+                info!(%peer, "new relay candidate");
+                self.candidates.insert(
+                    peer,
+                    Candidate {
+                        addrs,
+                        rtt: None,
+                        failures: 0,
+                        retry_at: None,
+                    },
+                );
+            }
+        }
+    }
+
+    /// A peer identified itself without the relay hop protocol.
+    pub(crate) fn not_a_relay(&mut self, peer: PeerId) {
+        self.probing.remove(&peer);
+        self.probed.insert(peer);
+    }
+
+    /// A peer of unknown protocols, e.g. from the kademlia routing table.
+    pub(crate) fn add_peer(&mut self, peer: PeerId, addrs: Vec<Multiaddr>) {
+        self.kad_peers = true;
+        let known = self.probed.contains(&peer)
+            || self.probing.contains_key(&peer)
+            || self.unprobed.iter().any(|(p, _)| *p == peer);
+        if !known {
+            self.unprobed.push_back((peer, addrs));
+        }
+    }
+
+    pub(crate) fn on_rtt(&mut self, peer: PeerId, rtt: Duration) {
+        if let Some(candidate) = self.candidates.get_mut(&peer) {
+            candidate.rtt = Some(rtt);
+        }
+    }
+
+    /// The listener requested by [`RelayAction::Listen`] was started, or failed to.
+    pub(crate) fn on_listen(&mut self, relay: PeerId, listener: Option<ListenerId>) {
+        match listener {
+            Some(id) => {
+                self.reservations.insert(
+                    id,
+                    Reservation {
+                        relay,
+                        requested_at: Instant::now(),
+                        accepted: false,
+                    },
+                );
+            }
+            None => self.failed(relay, Instant::now()),
+        }
+    }
+
+    pub(crate) fn on_reservation_accepted(&mut self, relay: PeerId) {
+        for reservation in self.reservations.values_mut() {
+            if reservation.relay == relay {
+                reservation.accepted = true;
+            }
+        }
+        if let Some(candidate) = self.candidates.get_mut(&relay) {
+            candidate.failures = 0;
+            candidate.retry_at = None;
+        }
+    }
+
+    /// The listener `id` is removed on request. Its relay is not listened through any more, and
+    /// not held responsible for the closed listener.
+    pub(crate) fn on_listener_removed(&mut self, id: ListenerId) {
+        if let Some(reservation) = self.reservations.remove(&id) {
+            info!(relay = %reservation.relay, "relayed listener removed");
+            self.dismissed.insert(reservation.relay);
+        }
+    }
+
+    /// A listener closed, its reservation expired or could not be made.
+    pub(crate) fn on_listener_closed(&mut self, id: ListenerId) {
+        if let Some(reservation) = self.reservations.remove(&id) {
+            info!(relay = %reservation.relay, "relayed listener closed");
+            self.failed(reservation.relay, Instant::now());
+        }
+    }
+
+    /// Back off from `relay`, starting `now`.
+    fn failed(&mut self, relay: PeerId, now: Instant) {
+        if let Some(candidate) = self.candidates.get_mut(&relay) {
+            let backoff = RETRY_BACKOFF
+                .saturating_mul(2u32.saturating_pow(candidate.failures))
+                .min(MAX_RETRY_BACKOFF);
+            candidate.failures += 1;
+            candidate.retry_at = Some(now + backoff);
+            debug!(%relay, ?backoff, "relay failed");
+        }
+    }
+
+    /// The `/p2p-circuit` addresses of the known relays, the fastest first.
+    pub(crate) fn circuit_addrs(&self) -> Vec<(PeerId, Multiaddr)> {
+        self.by_rtt()
+            .map(|(relay, candidate)| (*relay, candidate.circuit_addr(*relay)))
+            .collect()
+    }
+
+    /// Relays of unknown RTT come last.
+    fn by_rtt(&self) -> impl Iterator<Item = (&PeerId, &Candidate)> {
+        let mut candidates = self.candidates.iter().collect::<Vec<_>>();
+        candidates.sort_by_key(|(_, c)| (c.rtt.is_none(), c.rtt, c.failures));
+        candidates.into_iter()
+    }
+
+    /// What to do to get back to the target number of reservations.
+    pub(crate) fn poll(&mut self, now: Instant) -> Vec<RelayAction> {
+        let mut actions = Vec::new();
+
+        let expired = self
+            .reservations
+            .iter()
+            .filter(|(_, r)| {
+                !r.accepted && now.duration_since(r.requested_at) > RESERVATION_TIMEOUT
+            })
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in expired {
+            if let Some(reservation) = self.reservations.remove(&id) {
+                info!(relay = %reservation.relay, "reservation timed out");
+                self.failed(reservation.relay, now);
+                actions.push(RelayAction::RemoveListener(id));
+            }
+        }
+        self.probing
+            .retain(|_, since| now.duration_since(*since) <= RESERVATION_TIMEOUT);
+
+        let in_use = self
+            .reservations
+            .values()
+            .map(|r| r.relay)
+            .collect::<HashSet<_>>();
+        let available = self
+            .by_rtt()
+            .filter(|(peer, _)| !in_use.contains(peer) && !self.dismissed.contains(peer))
+            .filter(|(_, c)| c.retry_at.is_none_or(|t| t <= now));
+
+        let missing = self.target.saturating_sub(self.reservations.len());
+        for (relay, candidate) in available.take(missing) {
+            actions.push(RelayAction::Listen {
+                relay: *relay,
+                addr: candidate.circuit_addr(*relay),
+            });
+        }
+
+        let listens = actions
+            .iter()
+            .filter(|a| matches!(a, RelayAction::Listen { .. }))
+            .count();
+        if listens < missing {
+            while self.probing.len() < MAX_PROBES {
+                let Some((peer, addrs)) = self.unprobed.pop_front() else {
+                    break;
+                };
+                self.probing.insert(peer, now);
+                actions.push(RelayAction::Probe { peer, addrs });
+            }
+
+            let lookup_due = self
+                .last_lookup
+                .is_none_or(|t| now.duration_since(t) >= LOOKUP_INTERVAL);
+            if self.unprobed.is_empty() && self.kad_peers && lookup_due {
+                self.last_lookup = Some(now);
+                actions.push(RelayAction::FindPeers);
+            }
+        }
+
+        actions
+    }
+}
+
+pub(crate) fn is_relayed(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| p == Protocol::P2pCircuit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relay_addr() -> Multiaddr {
+        "/ip4/198.51.100.1/tcp/4001".parse().unwrap()
+    }
+
+    fn listens(actions: &[RelayAction]) -> Vec<PeerId> {
+        actions
+            .iter()
+            .filter_map(|a| match a {
+                RelayAction::Listen { relay, .. } => Some(*relay),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn probes(actions: &[RelayAction]) -> Vec<PeerId> {
+        actions
+            .iter()
+            .filter_map(|a| match a {
+                RelayAction::Probe { peer, .. } => Some(*peer),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn finds_peers(actions: &[RelayAction]) -> bool {
+        actions.iter().any(|a| matches!(a, RelayAction::FindPeers))
+    }
+
+    #[test]
+    fn reservation_timeout_removes_the_listener_and_backs_off() {
+        let start = Instant::now();
+        let relay = PeerId::random();
+        let mut manager = RelayManager::new(1);
+        manager.add_relay(relay, vec![relay_addr()]);
+
+        let actions = manager.poll(start);
+        assert_eq!(listens(&actions), [relay]);
+        let RelayAction::Listen { addr, .. } = &actions[0] else {
+            unreachable!()
+        };
+        assert!(is_relayed(addr));
+        let listener = ListenerId::next();
+        manager.on_listen(relay, Some(listener));
+        assert!(manager.poll(start).is_empty());
+
+        let timed_out = start + RESERVATION_TIMEOUT + Duration::from_secs(1);
+        let actions = manager.poll(timed_out);
+        assert!(matches!(
+            actions[..],
+            [RelayAction::RemoveListener(id)] if id == listener
+        ));
+
+        let retry = timed_out + RETRY_BACKOFF;
+        assert!(listens(&manager.poll(retry - Duration::from_secs(1))).is_empty());
+        assert_eq!(listens(&manager.poll(retry)), [relay]);
+
+        // the second failure backs off twice as long.
+        manager.on_listen(relay, Some(ListenerId::next()));
+        let timed_out = retry + RESERVATION_TIMEOUT + Duration::from_secs(1);
+        manager.poll(timed_out);
+        let retry = timed_out + 2 * RETRY_BACKOFF;
+        assert!(listens(&manager.poll(retry - Duration::from_secs(1))).is_empty());
+        assert_eq!(listens(&manager.poll(retry)), [relay]);
+    }
+
+    #[test]
+    fn removed_listener_is_not_replaced_through_its_relay() {
+        let start = Instant::now();
+        let (removed, other) = (PeerId::random(), PeerId::random());
+        let mut manager = RelayManager::new(1);
+        manager.add_relay(removed, vec![relay_addr()]);
+        manager.on_rtt(removed, Duration::from_millis(10));
+
+        assert_eq!(listens(&manager.poll(start)), [removed]);
+        let listener = ListenerId::next();
+        manager.on_listen(removed, Some(listener));
+        manager.on_reservation_accepted(removed);
+
+        manager.on_listener_removed(listener);
+        manager.on_listener_closed(listener);
+        let later = start + MAX_RETRY_BACKOFF;
+        assert!(listens(&manager.poll(later)).is_empty());
+        assert_eq!(manager.circuit_addrs().len(), 1);
+
+        manager.add_relay(other, vec![relay_addr()]);
+        assert_eq!(listens(&manager.poll(later)), [other]);
+    }
+
+    #[test]
+    fn accepted_reservation_does_not_time_out() {
+        let start = Instant::now();
+        let relay = PeerId::random();
+        let mut manager = RelayManager::new(1);
+        manager.add_relay(relay, vec![relay_addr()]);
+        manager.poll(start);
+        manager.on_listen(relay, Some(ListenerId::next()));
+        manager.on_reservation_accepted(relay);
+
+        assert!(manager.poll(start + 2 * RESERVATION_TIMEOUT).is_empty());
+    }
+
+    #[test]
+    fn peers_are_probed_then_looked_up_while_relays_are_missing() {
+        let start = Instant::now();
+        let mut manager = RelayManager::new(1);
+        assert!(manager.poll(start).is_empty());
+
+        let peers = [PeerId::random(), PeerId::random(), PeerId::random()];
+        for peer in peers {
+            manager.add_peer(peer, vec![relay_addr()]);
+        }
+        let actions = manager.poll(start);
+        assert_eq!(probes(&actions), peers[..MAX_PROBES]);
+        assert!(!finds_peers(&actions));
+
+        // a slot is free again, the last peer is probed and no one is left to probe.
+        manager.not_a_relay(peers[0]);
+        let actions = manager.poll(start);
+        assert_eq!(probes(&actions), [peers[2]]);
+        assert!(finds_peers(&actions));
+
+        assert!(manager.poll(start).is_empty());
+        assert!(finds_peers(&manager.poll(start + LOOKUP_INTERVAL)));
+    }
+
+    #[test]
+    fn no_probes_once_the_target_is_reached() {
+        let start = Instant::now();
+        let relay = PeerId::random();
+        let mut manager = RelayManager::new(1);
+        manager.add_peer(PeerId::random(), vec![relay_addr()]);
+        manager.add_relay(relay, vec![relay_addr()]);
+
+        let actions = manager.poll(start);
+        assert_eq!(listens(&actions), [relay]);
+        assert!(probes(&actions).is_empty());
+        assert!(!finds_peers(&actions));
+    }
+}
@@ -1,74 +1,236 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::IpAddr;
+use std::path::Path;
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{ready, Context, Poll, Waker};
 
-use tracing::info;
+use tracing::{debug, info, warn};
 
 use libp2p::{
-    core::{
-        address_translation,
-        transport::{ListenerId, TransportEvent},
-    },
+    core::transport::{ListenerId, TransportEvent},
     multiaddr::Protocol,
     tcp::{tokio::Transport as TokioTcpTransport, Config},
+    websocket::tls,
     Multiaddr, Transport, TransportError,
 };
+use libp2p_webrtc::tokio as webrtc;
 
-pub fn is_holepunch_direct_addr(addr: &Multiaddr) -> bool {
-    addr.iter().any(|p| p == Protocol::P2pWebRtcDirect)
+use crate::identity::{self, invalid_data};
+
+#[cfg(feature = "testing")]
+pub mod sim;
+
+pub fn is_quic_addr(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| p == Protocol::QuicV1)
 }
 
-fn direct_addr_2_normal(addr: Multiaddr) -> Multiaddr {
-    addr.into_iter()
-        .filter(|p| !matches!(p, Protocol::P2pWebRtcDirect))
-        .collect()
+/// Load the certificate chain and private key of a `/wss` listener from PEM files.
+pub fn websocket_tls(cert: &Path, key: &Path) -> io::Result<tls::Config> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no certificate in {}", cert.display()),
+        ));
+    }
+
+    let mut reader = BufReader::new(File::open(key)?);
+    let key_der = loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(
+                rustls_pemfile::Item::PKCS8Key(der)
+                | rustls_pemfile::Item::RSAKey(der)
+                | rustls_pemfile::Item::ECKey(der),
+            ) => break der,
+            Some(_) => continue,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("no private key in {}", key.display()),
+                ))
+            }
+        }
+    };
+
+    tls::Config::new(
+        tls::PrivateKey::new(key_der),
+        certs.into_iter().map(tls::Certificate::new),
+    )
+    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
 
-pub struct HolePunchTransport {
-    listened: HashMap<ListenerId, Multiaddr>,
-    inner: TokioTcpTransport,
+/// Load the certificate of the WebRTC-direct listener, or generate it and store it at `path`.
+///
+/// Without a path a new certificate is used on every start.
+pub fn webrtc_certificate(path: Option<&Path>) -> io::Result<webrtc::Certificate> {
+    match path {
+        Some(path) if path.exists() => {
+            let pem = String::from_utf8(identity::read(path)?).map_err(invalid_data)?;
+            webrtc::Certificate::from_pem(&pem).map_err(invalid_data)
+        }
+        path => {
+            let cert =
+                webrtc::Certificate::generate(&mut rand::thread_rng()).map_err(invalid_data)?;
+            match path {
+                Some(path) => identity::write_secret(path, cert.serialize_pem().as_bytes())?,
+                None => {
+                    warn!("using an ephemeral webrtc certificate, its certhash changes on restart")
+                }
+            }
+            Ok(cert)
+        }
+    }
 }
 
-impl HolePunchTransport {
-    pub fn new(cfg: Config) -> Self {
-        HolePunchTransport {
-            listened: Default::default(),
-            inner: TokioTcpTransport::new(cfg.port_reuse(true)),
+/// External IPs whose observations are kept.
+const MAX_OBSERVED_IPS: usize = 16;
+/// Observed ports kept per external IP.
+const MAX_OBSERVED_PORTS: usize = 8;
+
+/// The hole punch listeners and the addresses about to be hole punched.
+///
+/// Shared by [`HolePunchTransport`] and the behaviours that must tell its addresses apart from
+/// those of the other listeners, which use the same plain `/tcp` addresses.
+#[derive(Debug, Clone, Default)]
+pub struct HolePunchAddrs {
+    inner: Arc<Mutex<HolePunchState>>,
+}
+
+#[derive(Debug, Default)]
+struct HolePunchState {
+    /// Addresses the swarm is about to listen on for hole punching. The other TCP transports
+    /// take the same plain `/tcp` addresses otherwise.
+    claimed: HashSet<Multiaddr>,
+    /// Loopback and private addresses are no candidates.
+    public: bool,
+    listeners: HashMap<ListenerId, HolePunchListener>,
+    dial_targets: HashSet<Multiaddr>,
+    /// The ports this node was observed with, by external IP, the most recent IP first.
+    observed: VecDeque<(IpAddr, VecDeque<u16>)>,
+}
+
+#[derive(Debug, Clone)]
+struct HolePunchListener {
+    ipv6: bool,
+    /// Zero until the listener reports its address when it was asked for any port.
+    port: u16,
+    /// The addresses of the listener, one per interface when listening on all of them.
+    addrs: Vec<Multiaddr>,
+}
+
+impl HolePunchAddrs {
+    /// Make the listener the swarm starts next on `addr` a hole punch listener, see
+    /// [`is_listener`](Self::is_listener) for the id the swarm returns.
+    pub fn claim_listen_addr(&self, addr: &Multiaddr) {
+        self.state().claimed.insert(addr.clone());
+    }
+
+    /// Whether `id` is a hole punch listener.
+    pub fn is_listener(&self, id: ListenerId) -> bool {
+        self.state().listeners.contains_key(&id)
+    }
+
+    /// Set whether the node runs on a public network, where loopback and private addresses are
+    /// no candidates for a direct connection.
+    pub fn public(self, public: bool) -> Self {
+        self.state().public = public;
+        self
+    }
+
+    /// Whether an observed address may be a candidate for a direct connection, which in public
+    /// mode rules out loopback and private addresses.
+    pub fn allows_candidate(&self, addr: &Multiaddr) -> bool {
+        !self.state().public || ip(addr).is_none_or(is_global)
+    }
+
+    /// Whether `addr` is a plain TCP address on the port of a hole punch listener of the same IP
+    /// version.
+    pub fn is_holepunch_addr(&self, addr: &Multiaddr) -> bool {
+        let Some((ipv6, port)) = plain_tcp(addr) else {
+            return false;
+        };
+        self.state()
+            .listeners
+            .values()
+            .any(|l| l.ipv6 == ipv6 && l.port == port)
+    }
+
+    /// Dial the TCP addresses among `addrs` from a hole punch listener.
+    pub fn add_dial_targets(&self, addrs: &[Multiaddr]) {
+        let mut state = self.state();
+        for addr in addrs.iter().filter(|a| plain_tcp(a).is_some()) {
+            state.dial_targets.insert(without_p2p(addr));
         }
     }
+
+    fn take_dial_target(&self, addr: &Multiaddr) -> bool {
+        self.state().dial_targets.remove(&without_p2p(addr))
+    }
+
+    fn state(&self) -> MutexGuard<'_, HolePunchState> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+pub struct HolePunchTransport<T = TokioTcpTransport> {
+    addrs: HolePunchAddrs,
+    inner: T,
+}
+
+impl HolePunchTransport {
+    pub fn new(cfg: Config, addrs: HolePunchAddrs) -> Self {
+        Self::with_transport(TokioTcpTransport::new(cfg.port_reuse(true)), addrs)
+    }
+}
+
+impl<T> HolePunchTransport<T> {
+    /// Hole punch over `inner`, which must dial from the port of its listener.
+    pub fn with_transport(inner: T, addrs: HolePunchAddrs) -> Self {
+        HolePunchTransport { addrs, inner }
+    }
 }
 
-impl Transport for HolePunchTransport {
-    type Output = <TokioTcpTransport as Transport>::Output;
-    type Error = <TokioTcpTransport as Transport>::Error;
-    type ListenerUpgrade = <TokioTcpTransport as Transport>::ListenerUpgrade;
-    type Dial = <TokioTcpTransport as Transport>::Dial;
+impl<T: Transport + Unpin> Transport for HolePunchTransport<T> {
+    type Output = T::Output;
+    type Error = T::Error;
+    type ListenerUpgrade = T::ListenerUpgrade;
+    type Dial = T::Dial;
 
     fn listen_on(
         &mut self,
         id: ListenerId,
         addr: Multiaddr,
     ) -> Result<(), TransportError<Self::Error>> {
-        if is_holepunch_direct_addr(&addr) {
-            info!(?id, ?addr, "listen on");
-            let normal = direct_addr_2_normal(addr);
-            self.inner.listen_on(id, normal.clone()).inspect(|_| {
-                self.listened.insert(id, normal);
-            })
-        } else {
-            Err(TransportError::MultiaddrNotSupported(addr))
+        if !self.addrs.state().claimed.remove(&addr) {
+            return Err(TransportError::MultiaddrNotSupported(addr));
         }
+        let Some((ipv6, port)) = plain_tcp(&addr) else {
+            return Err(TransportError::MultiaddrNotSupported(addr));
+        };
+
+        info!(?id, ?addr, "listen on");
+        self.inner.listen_on(id, addr).inspect(|_| {
+            let listener = HolePunchListener {
+                ipv6,
+                port,
+                addrs: Vec::new(),
+            };
+            self.addrs.state().listeners.insert(id, listener);
+        })
     }
 
     fn remove_listener(&mut self, id: ListenerId) -> bool {
-        self.listened.remove(&id);
+        self.addrs.state().listeners.remove(&id);
         self.inner.remove_listener(id)
     }
 
     fn dial(&mut self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
-        if is_holepunch_direct_addr(&addr) {
+        if self.addrs.take_dial_target(&addr) {
             info!(?addr, "dial");
-            self.inner.dial(direct_addr_2_normal(addr))
+            self.inner.dial(addr)
         } else {
             Err(TransportError::MultiaddrNotSupported(addr))
         }
@@ -78,9 +240,9 @@ impl Transport for HolePunchTransport {
         &mut self,
         addr: Multiaddr,
     ) -> Result<Self::Dial, TransportError<Self::Error>> {
-        if is_holepunch_direct_addr(&addr) {
+        if self.addrs.take_dial_target(&addr) {
             info!(?addr, "dial as listener");
-            self.inner.dial_as_listener(direct_addr_2_normal(addr))
+            self.inner.dial_as_listener(addr)
         } else {
             Err(TransportError::MultiaddrNotSupported(addr))
         }
@@ -90,21 +252,242 @@ impl Transport for HolePunchTransport {
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
-        Pin::new(&mut self.inner).poll(cx)
+        let event = ready!(Pin::new(&mut self.inner).poll(cx));
+        match &event {
+            TransportEvent::NewAddress {
+                listener_id,
+                listen_addr,
+            } => {
+                if let (Some(listener), Some((_, port))) = (
+                    self.addrs.state().listeners.get_mut(listener_id),
+                    plain_tcp(listen_addr),
+                ) {
+                    listener.port = port;
+                    listener.addrs.push(without_p2p(listen_addr));
+                }
+            }
+            TransportEvent::AddressExpired {
+                listener_id,
+                listen_addr,
+            } => {
+                if let Some(listener) = self.addrs.state().listeners.get_mut(listener_id) {
+                    let expired = without_p2p(listen_addr);
+                    listener.addrs.retain(|a| *a != expired);
+                }
+            }
+            TransportEvent::ListenerClosed { listener_id, .. } => {
+                self.addrs.state().listeners.remove(listener_id);
+            }
+            _ => {}
+        }
+        Poll::Ready(event)
     }
 
+    /// Translate a TCP address observed by a peer to the port of the hole punch listener whose
+    /// address `listen` is, assuming that the NAT keeps the port the same as for the connection
+    /// observed.
+    ///
+    /// Only a listener of the same IP version can be reached at the observed IP, and only one on
+    /// the loopback interface at a loopback IP. A NAT may map ports to several external IPs,
+    /// so once observations disagree an IP is only used if it was observed on several
+    /// connections, or with the port of the listener itself.
     fn address_translation(&self, listen: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
-        let port = listen.iter().skip(1).next();
+        let (_, observed_port) = plain_tcp(observed)?;
+        let observed_ip = ip(observed)?;
+        let listen_ip = ip(listen)?;
+        if observed_ip.is_ipv6() != listen_ip.is_ipv6()
+            || observed_ip.is_loopback() != listen_ip.is_loopback()
+        {
+            return None;
+        }
 
-        self.listened
+        let mut state = self.addrs.state();
+        if state.public && !is_global(observed_ip) {
+            return None;
+        }
+        let listen = without_p2p(listen);
+        let port = state
+            .listeners
             .values()
-            .any(|addr| {
-                let addr_port = addr.iter().skip(1).next();
-                addr_port.is_some() && addr_port == port
-            })
-            .then(|| address_translation(listen, observed))
-            .flatten()
-            .map(|addr| addr.with(Protocol::P2pWebRtcDirect))
-            .inspect(|addr| info!(?addr, "translated"))
+            .find(|l| l.addrs.contains(&listen))?
+            .port;
+
+        state.observe(observed_ip, observed_port);
+        if observed_port != port && !state.is_confirmed(observed_ip) {
+            debug!(?observed, "external IP not confirmed");
+            return None;
+        }
+
+        let translated = Multiaddr::from(observed_ip).with(Protocol::Tcp(port));
+        info!(addr = ?translated, "translated");
+        Some(translated)
+    }
+}
+
+/// Reports a removed listener as closed right away and drops its remaining events.
+///
+/// The relay client transport does not wake a removed listener up, so it would only report
+/// the listener closed once the relay renews its reservation.
+pub struct RemovableListeners<T> {
+    inner: T,
+    removed: HashSet<ListenerId>,
+    closed: VecDeque<ListenerId>,
+    waker: Option<Waker>,
+}
+
+impl<T> RemovableListeners<T> {
+    pub fn new(inner: T) -> Self {
+        RemovableListeners {
+            inner,
+            removed: Default::default(),
+            closed: Default::default(),
+            waker: None,
+        }
+    }
+}
+
+impl<T: Transport + Unpin> Transport for RemovableListeners<T> {
+    type Output = T::Output;
+    type Error = T::Error;
+    type ListenerUpgrade = T::ListenerUpgrade;
+    type Dial = T::Dial;
+
+    fn listen_on(
+        &mut self,
+        id: ListenerId,
+        addr: Multiaddr,
+    ) -> Result<(), TransportError<Self::Error>> {
+        self.inner.listen_on(id, addr)
+    }
+
+    fn remove_listener(&mut self, id: ListenerId) -> bool {
+        if !self.inner.remove_listener(id) {
+            return false;
+        }
+        self.removed.insert(id);
+        self.closed.push_back(id);
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+        true
+    }
+
+    fn dial(&mut self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        self.inner.dial(addr)
+    }
+
+    fn dial_as_listener(
+        &mut self,
+        addr: Multiaddr,
+    ) -> Result<Self::Dial, TransportError<Self::Error>> {
+        self.inner.dial_as_listener(addr)
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        if let Some(listener_id) = self.closed.pop_front() {
+            return Poll::Ready(TransportEvent::ListenerClosed {
+                listener_id,
+                reason: Ok(()),
+            });
+        }
+        self.waker = Some(cx.waker().clone());
+
+        loop {
+            let event = ready!(Pin::new(&mut self.inner).poll(cx));
+            let listener_id = match &event {
+                TransportEvent::NewAddress { listener_id, .. }
+                | TransportEvent::AddressExpired { listener_id, .. }
+                | TransportEvent::Incoming { listener_id, .. }
+                | TransportEvent::ListenerClosed { listener_id, .. }
+                | TransportEvent::ListenerError { listener_id, .. } => *listener_id,
+            };
+            if !self.removed.contains(&listener_id) {
+                return Poll::Ready(event);
+            }
+            if matches!(event, TransportEvent::ListenerClosed { .. }) {
+                self.removed.remove(&listener_id);
+            }
+        }
+    }
+
+    fn address_translation(&self, listen: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        self.inner.address_translation(listen, observed)
+    }
+}
+
+/// The IP version and port of a `/ip4|ip6/.../tcp/...` address, optionally ending with `/p2p`.
+fn plain_tcp(addr: &Multiaddr) -> Option<(bool, u16)> {
+    let mut iter = addr.iter();
+    let ipv6 = match iter.next()? {
+        Protocol::Ip4(_) => false,
+        Protocol::Ip6(_) => true,
+        _ => return None,
+    };
+    let port = match iter.next()? {
+        Protocol::Tcp(port) => port,
+        _ => return None,
+    };
+    match iter.next() {
+        None | Some(Protocol::P2p(_)) => Some((ipv6, port)),
+        Some(_) => None,
+    }
+}
+
+impl HolePunchState {
+    fn observe(&mut self, ip: IpAddr, port: u16) {
+        let mut ports = match self.observed.iter().position(|(i, _)| *i == ip) {
+            Some(index) => self.observed.remove(index).expect("observed IP").1,
+            None => VecDeque::new(),
+        };
+        if !ports.contains(&port) {
+            ports.push_front(port);
+            ports.truncate(MAX_OBSERVED_PORTS);
+        }
+        self.observed.push_front((ip, ports));
+        self.observed.truncate(MAX_OBSERVED_IPS);
+    }
+
+    /// Whether `ip` is the only external IP observed, or was observed on several connections,
+    /// which each have their own port.
+    fn is_confirmed(&self, ip: IpAddr) -> bool {
+        let ports = self
+            .observed
+            .iter()
+            .find(|(i, _)| *i == ip)
+            .map_or(0, |(_, ports)| ports.len());
+        self.observed.len() == 1 || ports > 1
+    }
+}
+
+fn ip(addr: &Multiaddr) -> Option<IpAddr> {
+    match addr.iter().next()? {
+        Protocol::Ip4(ip) => Some(ip.into()),
+        Protocol::Ip6(ip) => Some(ip.into()),
+        _ => None,
+    }
+}
+
+/// Whether an IP may be reachable from the internet, i.e. is not loopback, private, link-local
+/// or unspecified.
+fn is_global(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified())
+        }
+        IpAddr::V6(ip) => {
+            !(ip.is_loopback()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+                || ip.is_unspecified())
+        }
     }
 }
+
+fn without_p2p(addr: &Multiaddr) -> Multiaddr {
+    addr.iter()
+        .filter(|p| !matches!(p, Protocol::P2p(_)))
+        .collect()
+}
@@ -0,0 +1,563 @@
+//! An in-process IPv4 network with NATs, to exercise hole punching without real routers.
+//!
+//! Every host of a [`SimNetwork`] is either public or behind a NAT of its own, and dials and
+//! listens on `/ip4/.../tcp/...` addresses with a [`SimTransport`]. A dial dropped by a NAT is
+//! retried until it passes or times out, like the SYN retransmissions of TCP, and two dials
+//! crossing each other between the same public endpoints become one connection, like a TCP
+//! simultaneous open.
+//!
+//! Only built with the `testing` feature.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use futures::channel::{mpsc, oneshot};
+use futures::future::{self, BoxFuture, Either, Ready};
+use futures::{AsyncRead, AsyncWrite, FutureExt, StreamExt};
+use futures_timer::Delay;
+use libp2p::{
+    core::transport::{ListenerId, TransportEvent},
+    multiaddr::Protocol,
+    Multiaddr, Transport, TransportError,
+};
+use tokio::io::{DuplexStream, ReadBuf};
+
+/// How long a dial dropped by a NAT keeps being retried.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Interval of the retries of a dropped dial.
+const SYN_INTERVAL: Duration = Duration::from_millis(50);
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+/// External ports not preserved by a NAT are allocated from here on.
+const FIRST_NAT_PORT: u16 = 20000;
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// How a NAT maps outbound connections and filters inbound ones, as in RFC 3489.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NatType {
+    /// One mapping per local port, open to everyone.
+    FullCone,
+    /// One mapping per local port, open to the IPs the host sent to.
+    RestrictedCone,
+    /// One mapping per local port, open to the IPs and ports the host sent to.
+    PortRestricted,
+    /// One mapping per local port and destination, open to that destination only.
+    Symmetric,
+}
+
+impl NatType {
+    pub const ALL: [NatType; 4] = [
+        NatType::FullCone,
+        NatType::RestrictedCone,
+        NatType::PortRestricted,
+        NatType::Symmetric,
+    ];
+}
+
+/// The simulated network, cheap to clone.
+#[derive(Debug, Clone, Default)]
+pub struct SimNetwork {
+    state: Arc<Mutex<NetState>>,
+}
+
+#[derive(Debug, Default)]
+struct NetState {
+    /// By public IP.
+    hosts: HashMap<Ipv4Addr, Host>,
+    /// Dials still in progress, by public source and destination endpoints.
+    dials: HashMap<(SocketAddrV4, SocketAddrV4), oneshot::Sender<SimStream>>,
+}
+
+#[derive(Debug)]
+struct Host {
+    nat: Option<Nat>,
+    listeners: HashMap<u16, mpsc::UnboundedSender<(SimStream, SocketAddrV4)>>,
+    next_port: u16,
+}
+
+#[derive(Debug)]
+struct Nat {
+    kind: NatType,
+    /// External port by local port, and destination for a symmetric NAT.
+    mappings: HashMap<(u16, Option<SocketAddrV4>), u16>,
+    /// External ports and the endpoints sent to through them.
+    sent: HashSet<(u16, SocketAddrV4)>,
+    /// Local port by external port, open to everyone as set up by a port mapping.
+    forwards: HashMap<u16, u16>,
+    next_port: u16,
+}
+
+enum Delivery {
+    Connected(SimStream),
+    Refused,
+    /// Dropped by a NAT or sent to an unknown IP.
+    Dropped,
+}
+
+impl SimNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a host reachable at its own IP.
+    pub fn public_host(&self) -> SimHost {
+        self.add_host(None)
+    }
+
+    /// Add a host behind a NAT of the given type, with a private IP.
+    pub fn host_behind_nat(&self, kind: NatType) -> SimHost {
+        self.add_host(Some(Nat {
+            kind,
+            mappings: Default::default(),
+            sent: Default::default(),
+            forwards: Default::default(),
+            next_port: FIRST_NAT_PORT,
+        }))
+    }
+
+    fn add_host(&self, nat: Option<Nat>) -> SimHost {
+        let mut state = self.state();
+        let n = state.hosts.len() as u32 + 1;
+        // 198.18.0.0/15 is reserved for benchmarks, so never mistaken for a real host.
+        let public_ip = Ipv4Addr::from(u32::from(Ipv4Addr::new(198, 18, 0, 0)) + n);
+        let ip = match nat {
+            Some(_) => Ipv4Addr::from(u32::from(Ipv4Addr::new(10, 0, 0, 0)) + (n << 8) + 2),
+            None => public_ip,
+        };
+
+        state.hosts.insert(
+            public_ip,
+            Host {
+                nat,
+                listeners: Default::default(),
+                next_port: FIRST_EPHEMERAL_PORT,
+            },
+        );
+        SimHost {
+            net: self.clone(),
+            public_ip,
+            ip,
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, NetState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    async fn connect(
+        self,
+        public_ip: Ipv4Addr,
+        local_port: u16,
+        dest: SocketAddrV4,
+    ) -> io::Result<SimStream> {
+        let (src, mut crossed) = {
+            let mut state = self.state();
+            let src = state.send(public_ip, local_port, dest);
+            let (tx, rx) = oneshot::channel();
+            state.dials.insert((src, dest), tx);
+            (src, rx)
+        };
+
+        let deadline = Instant::now() + CONNECT_TIMEOUT;
+        loop {
+            let delivery = {
+                let mut state = self.state();
+                if let Ok(Some(stream)) = crossed.try_recv() {
+                    return Ok(stream);
+                }
+                let delivery = state.deliver(src, dest);
+                if !matches!(delivery, Delivery::Dropped) || Instant::now() >= deadline {
+                    state.dials.remove(&(src, dest));
+                }
+                delivery
+            };
+
+            match delivery {
+                Delivery::Connected(stream) => return Ok(stream),
+                Delivery::Refused => return Err(io::ErrorKind::ConnectionRefused.into()),
+                Delivery::Dropped if Instant::now() >= deadline => {
+                    return Err(io::ErrorKind::TimedOut.into())
+                }
+                Delivery::Dropped => {}
+            }
+
+            if let Either::Left((Ok(stream), _)) =
+                future::select(&mut crossed, Delay::new(SYN_INTERVAL)).await
+            {
+                return Ok(stream);
+            }
+        }
+    }
+}
+
+impl NetState {
+    fn host(&mut self, public_ip: Ipv4Addr) -> &mut Host {
+        self.hosts.get_mut(&public_ip).expect("host of the network")
+    }
+
+    /// Send from a local port of a host to `dest`, returns the public source endpoint.
+    fn send(&mut self, public_ip: Ipv4Addr, local_port: u16, dest: SocketAddrV4) -> SocketAddrV4 {
+        let Some(nat) = self.host(public_ip).nat.as_mut() else {
+            return SocketAddrV4::new(public_ip, local_port);
+        };
+
+        let key = match nat.kind {
+            NatType::Symmetric => (local_port, Some(dest)),
+            _ => (local_port, None),
+        };
+        let forwarded = nat
+            .forwards
+            .iter()
+            .find_map(|(external, local)| (*local == local_port).then_some(*external));
+        let port = match forwarded.or_else(|| nat.mappings.get(&key).copied()) {
+            Some(port) => port,
+            None => {
+                // cone NATs keep the local port when they can.
+                let taken = |port| {
+                    nat.mappings.values().any(|p| *p == port) || nat.forwards.contains_key(&port)
+                };
+                let port = if nat.kind != NatType::Symmetric && !taken(local_port) {
+                    local_port
+                } else {
+                    while taken(nat.next_port) {
+                        nat.next_port += 1;
+                    }
+                    nat.next_port
+                };
+                nat.mappings.insert(key, port);
+                port
+            }
+        };
+        nat.sent.insert((port, dest));
+
+        SocketAddrV4::new(public_ip, port)
+    }
+
+    fn deliver(&mut self, src: SocketAddrV4, dest: SocketAddrV4) -> Delivery {
+        let Some(host) = self.hosts.get_mut(dest.ip()) else {
+            return Delivery::Dropped;
+        };
+
+        let local_port = match host.nat.as_ref() {
+            None => dest.port(),
+            Some(nat) if nat.forwards.contains_key(&dest.port()) => nat.forwards[&dest.port()],
+            Some(nat) => {
+                let Some(&(local_port, _)) = nat
+                    .mappings
+                    .iter()
+                    .find_map(|(key, port)| (*port == dest.port()).then_some(key))
+                else {
+                    return Delivery::Dropped;
+                };
+
+                let allowed = match nat.kind {
+                    NatType::FullCone => true,
+                    NatType::RestrictedCone => nat
+                        .sent
+                        .iter()
+                        .any(|(port, to)| *port == dest.port() && to.ip() == src.ip()),
+                    NatType::PortRestricted | NatType::Symmetric => {
+                        nat.sent.contains(&(dest.port(), src))
+                    }
+                };
+                if !allowed {
+                    return Delivery::Dropped;
+                }
+                local_port
+            }
+        };
+
+        let (a, b) = tokio::io::duplex(PIPE_CAPACITY);
+        let (a, b) = (SimStream(a), SimStream(b));
+
+        // the destination is dialing the source at the same time.
+        let b = match self.dials.remove(&(dest, src)) {
+            Some(crossed) => match crossed.send(b) {
+                Ok(()) => return Delivery::Connected(a),
+                Err(b) => b,
+            },
+            None => b,
+        };
+
+        let host = self.host(*dest.ip());
+        match host.listeners.get(&local_port) {
+            Some(listener) if listener.unbounded_send((b, src)).is_ok() => Delivery::Connected(a),
+            _ => Delivery::Refused,
+        }
+    }
+}
+
+impl Host {
+    fn ephemeral_port(&mut self) -> u16 {
+        while self.listeners.contains_key(&self.next_port) {
+            self.next_port += 1;
+        }
+        self.next_port += 1;
+        self.next_port - 1
+    }
+}
+
+/// A host of a [`SimNetwork`].
+#[derive(Debug, Clone)]
+pub struct SimHost {
+    net: SimNetwork,
+    public_ip: Ipv4Addr,
+    ip: Ipv4Addr,
+}
+
+impl SimHost {
+    /// The IP of the host itself, private if it is behind a NAT.
+    pub fn ip(&self) -> Ipv4Addr {
+        self.ip
+    }
+
+    /// The IP the host is seen with by the other hosts.
+    pub fn public_ip(&self) -> Ipv4Addr {
+        self.public_ip
+    }
+
+    /// Open `external_port` of the NAT of the host to everyone, forwarding it to `local_port`
+    /// as a port mapping of a router does. Returns `false` if the host is not behind a NAT or
+    /// the external port is taken.
+    pub fn forward_port(&self, external_port: u16, local_port: u16) -> bool {
+        let mut state = self.net.state();
+        let Some(nat) = state.host(self.public_ip).nat.as_mut() else {
+            return false;
+        };
+        let taken = nat
+            .forwards
+            .get(&external_port)
+            .is_some_and(|p| *p != local_port)
+            || nat.mappings.values().any(|p| *p == external_port);
+        if !taken {
+            nat.forwards.insert(external_port, local_port);
+        }
+        !taken
+    }
+
+    /// Close a port opened with [`forward_port`](Self::forward_port).
+    pub fn remove_forwarded_port(&self, external_port: u16) {
+        if let Some(nat) = self.net.state().host(self.public_ip).nat.as_mut() {
+            nat.forwards.remove(&external_port);
+        }
+    }
+}
+
+/// A connection of a [`SimNetwork`].
+#[derive(Debug)]
+pub struct SimStream(DuplexStream);
+
+impl AsyncRead for SimStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut buf = ReadBuf::new(buf);
+        tokio::io::AsyncRead::poll_read(Pin::new(&mut self.0), cx, &mut buf)
+            .map_ok(|()| buf.filled().len())
+    }
+}
+
+impl AsyncWrite for SimStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        tokio::io::AsyncWrite::poll_write(Pin::new(&mut self.0), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        tokio::io::AsyncWrite::poll_flush(Pin::new(&mut self.0), cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        tokio::io::AsyncWrite::poll_shutdown(Pin::new(&mut self.0), cx)
+    }
+}
+
+struct SimListener {
+    id: ListenerId,
+    port: u16,
+    addr: Multiaddr,
+    incoming: mpsc::UnboundedReceiver<(SimStream, SocketAddrV4)>,
+}
+
+/// TCP over a [`SimNetwork`], for one of its hosts.
+pub struct SimTransport {
+    host: SimHost,
+    port_reuse: bool,
+    listeners: Vec<SimListener>,
+    pending_events: VecDeque<TransportEvent<Ready<io::Result<SimStream>>, io::Error>>,
+    waker: Option<Waker>,
+}
+
+impl SimTransport {
+    pub fn new(host: SimHost) -> Self {
+        SimTransport {
+            host,
+            port_reuse: false,
+            listeners: Vec::new(),
+            pending_events: VecDeque::new(),
+            waker: None,
+        }
+    }
+
+    /// Dial from the port of a listener instead of an ephemeral one, as TCP with port reuse.
+    pub fn port_reuse(mut self, enabled: bool) -> Self {
+        self.port_reuse = enabled;
+        self
+    }
+
+    fn push_event(&mut self, event: TransportEvent<Ready<io::Result<SimStream>>, io::Error>) {
+        self.pending_events.push_back(event);
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Transport for SimTransport {
+    type Output = SimStream;
+    type Error = io::Error;
+    type ListenerUpgrade = Ready<io::Result<SimStream>>;
+    type Dial = BoxFuture<'static, io::Result<SimStream>>;
+
+    fn listen_on(
+        &mut self,
+        id: ListenerId,
+        addr: Multiaddr,
+    ) -> Result<(), TransportError<Self::Error>> {
+        let Some((ip, port)) = ip4_tcp(&addr) else {
+            return Err(TransportError::MultiaddrNotSupported(addr));
+        };
+        if !ip.is_unspecified() && ip != self.host.ip {
+            return Err(TransportError::MultiaddrNotSupported(addr));
+        }
+
+        let (tx, incoming) = mpsc::unbounded();
+        let port = {
+            let mut state = self.host.net.state();
+            let host = state.host(self.host.public_ip);
+            let port = match port {
+                0 => host.ephemeral_port(),
+                port if host.listeners.contains_key(&port) => {
+                    return Err(TransportError::Other(io::ErrorKind::AddrInUse.into()))
+                }
+                port => port,
+            };
+            host.listeners.insert(port, tx);
+            port
+        };
+
+        let addr = Multiaddr::from(self.host.ip).with(Protocol::Tcp(port));
+        self.listeners.push(SimListener {
+            id,
+            port,
+            addr: addr.clone(),
+            incoming,
+        });
+        self.push_event(TransportEvent::NewAddress {
+            listener_id: id,
+            listen_addr: addr,
+        });
+        Ok(())
+    }
+
+    fn remove_listener(&mut self, id: ListenerId) -> bool {
+        let Some(index) = self.listeners.iter().position(|l| l.id == id) else {
+            return false;
+        };
+
+        let listener = self.listeners.remove(index);
+        self.host
+            .net
+            .state()
+            .host(self.host.public_ip)
+            .listeners
+            .remove(&listener.port);
+        self.push_event(TransportEvent::ListenerClosed {
+            listener_id: id,
+            reason: Ok(()),
+        });
+        true
+    }
+
+    fn dial(&mut self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        let Some((ip, port)) = ip4_tcp(&addr) else {
+            return Err(TransportError::MultiaddrNotSupported(addr));
+        };
+
+        let local_port = match self.listeners.first().filter(|_| self.port_reuse) {
+            Some(listener) => listener.port,
+            None => self
+                .host
+                .net
+                .state()
+                .host(self.host.public_ip)
+                .ephemeral_port(),
+        };
+        Ok(self
+            .host
+            .net
+            .clone()
+            .connect(self.host.public_ip, local_port, SocketAddrV4::new(ip, port))
+            .boxed())
+    }
+
+    fn dial_as_listener(
+        &mut self,
+        addr: Multiaddr,
+    ) -> Result<Self::Dial, TransportError<Self::Error>> {
+        self.dial(addr)
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        if let Some(event) = self.pending_events.pop_front() {
+            return Poll::Ready(event);
+        }
+
+        for listener in self.listeners.iter_mut() {
+            if let Poll::Ready(Some((stream, src))) = listener.incoming.poll_next_unpin(cx) {
+                return Poll::Ready(TransportEvent::Incoming {
+                    listener_id: listener.id,
+                    upgrade: future::ready(Ok(stream)),
+                    local_addr: listener.addr.clone(),
+                    send_back_addr: Multiaddr::from(*src.ip()).with(Protocol::Tcp(src.port())),
+                });
+            }
+        }
+
+        self.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    fn address_translation(&self, listen: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        let (_, port) = ip4_tcp(listen)?;
+        let (ip, _) = ip4_tcp(observed)?;
+        match self.port_reuse {
+            true => Some(observed.clone()),
+            false => Some(Multiaddr::from(ip).with(Protocol::Tcp(port))),
+        }
+    }
+}
+
+/// The IP and port of a `/ip4/.../tcp/...` address, optionally ending with `/p2p`.
+fn ip4_tcp(addr: &Multiaddr) -> Option<(Ipv4Addr, u16)> {
+    let mut iter = addr.iter();
+    let (Some(Protocol::Ip4(ip)), Some(Protocol::Tcp(port))) = (iter.next(), iter.next()) else {
+        return None;
+    };
+    match iter.next() {
+        None | Some(Protocol::P2p(_)) => Some((ip, port)),
+        Some(_) => None,
+    }
+}
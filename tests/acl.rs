@@ -0,0 +1,79 @@
+//! The access rules of a relay server.
+
+// the rules are reloaded on SIGHUP.
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+use std::process::Command;
+use std::time::Duration;
+
+use libp2p_relay_demo::{NodeConfig, NodeEvent};
+
+use common::TestNode;
+
+/// A node that only knows `relay`, once it identified it.
+async fn dialer(relay: &TestNode) -> TestNode {
+    let mut dialer = common::spawn(NodeConfig::default(), false).await;
+    dialer.handle.dial(relay.addr.clone()).await.unwrap();
+    let relay_id = relay.peer_id;
+    dialer
+        .wait_for(|event| match event {
+            NodeEvent::Identified { peer_id, .. } if *peer_id == relay_id => Some(()),
+            _ => None,
+        })
+        .await;
+    dialer
+}
+
+#[tokio::test]
+async fn reloaded_destination_rules_apply_to_reserved_peers() {
+    let dir = tempfile::tempdir().unwrap();
+    let acl = dir.path().join("acl.toml");
+    fs::write(&acl, "").unwrap();
+
+    let mut config = NodeConfig::default();
+    config.relay.service = true;
+    config.relay.acl = Some(acl.clone());
+    let relay = common::spawn(config, true).await;
+
+    let mut config = NodeConfig::default();
+    config.relay.listen_relayed = true;
+    let mut target = common::spawn(config, false).await;
+    target.handle.dial(relay.addr.clone()).await.unwrap();
+    target
+        .wait_for(|event| match event {
+            NodeEvent::ReservationAccepted { .. } => Some(()),
+            _ => None,
+        })
+        .await;
+
+    let (_, endpoint) = dialer(&relay)
+        .await
+        .handle
+        .dial_peer(target.peer_id)
+        .await
+        .unwrap();
+    assert!(endpoint.is_relayed());
+
+    // the target keeps its reservation, but can't be reached through it anymore.
+    fs::write(
+        &acl,
+        format!("[destination]\ndeny_peers = [\"{}\"]\n", target.peer_id),
+    )
+    .unwrap();
+    let status = Command::new("kill")
+        .args(["-HUP", &std::process::id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    assert!(dialer(&relay)
+        .await
+        .handle
+        .dial_peer(target.peer_id)
+        .await
+        .is_err());
+}
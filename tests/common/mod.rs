@@ -0,0 +1,119 @@
+//! Nodes running on the memory transport or a simulated network, all within the test process.
+
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+use libp2p::{identity::Keypair, multiaddr::Protocol, Multiaddr, PeerId};
+use libp2p_relay_demo::transport::sim::SimHost;
+use libp2p_relay_demo::{
+    NodeConfig, NodeEvent, NodeHandle, RelayNode, RelayNodeBuilder, TimedEvent,
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// How long to wait for an event before failing the test.
+pub const TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Every node of a simulated network has its own IP, so they all listen on the same port.
+pub const SIM_PORT: u16 = 4001;
+
+pub struct TestNode {
+    pub handle: NodeHandle,
+    pub peer_id: PeerId,
+    /// The listen address, ending with `/p2p/<peer_id>`.
+    pub addr: Multiaddr,
+    /// Subscribed before the node started listening.
+    pub events: broadcast::Receiver<TimedEvent>,
+}
+
+/// A `/memory` address not used by any other node of the test process.
+pub fn memory_addr() -> Multiaddr {
+    Multiaddr::empty().with(Protocol::Memory(rand::random::<u64>().max(1)))
+}
+
+/// Spawn a node with the given configuration on a new `/memory` address.
+///
+/// A `public` node announces that address as external, as a relay server or a kademlia server
+/// must.
+pub async fn spawn(mut config: NodeConfig, public: bool) -> TestNode {
+    let addr = memory_addr();
+    config.network.memory = true;
+    config.network.listen_addrs = vec![addr.clone()];
+    if public {
+        config.network.external_addrs = vec![addr.clone()];
+    }
+
+    start(
+        RelayNode::builder(Keypair::generate_ed25519()).config(config),
+        addr,
+    )
+    .await
+}
+
+/// Spawn a node on a host of a simulated network, listening on [`SIM_PORT`].
+///
+/// A host with a public IP announces its address as external.
+pub async fn spawn_simulated(host: SimHost, mut config: NodeConfig) -> TestNode {
+    let addr = Multiaddr::from(host.public_ip()).with(Protocol::Tcp(SIM_PORT));
+    config.network.listen_port = SIM_PORT;
+    if host.ip() == host.public_ip() {
+        config.network.external_addrs = vec![addr.clone()];
+    }
+
+    let builder = RelayNode::builder(Keypair::generate_ed25519())
+        .config(config)
+        .simulated(host);
+    start(builder, addr).await
+}
+
+/// Start the node built by `builder`, which listens on `addr` among others.
+pub async fn start(builder: RelayNodeBuilder, addr: Multiaddr) -> TestNode {
+    let mut node = builder.build().expect("build node");
+    let handle = node.handle();
+    let events = handle.subscribe();
+    node.wait_for_listeners().await;
+    tokio::spawn(node.run());
+
+    let peer_id = handle.local_peer_id();
+    TestNode {
+        handle,
+        peer_id,
+        addr: addr.with(Protocol::P2p(peer_id)),
+        events,
+    }
+}
+
+impl TestNode {
+    /// The addresses the node reported listening on since the last call, without waiting.
+    pub fn listen_addrs(&mut self) -> Vec<Multiaddr> {
+        let mut addrs = Vec::new();
+        while let Ok(timed) = self.events.try_recv() {
+            if let NodeEvent::NewListenAddr { address, .. } = timed.event {
+                addrs.push(address);
+            }
+        }
+        addrs
+    }
+
+    /// Wait for the first event for which `f` returns a value.
+    pub async fn wait_for<T>(&mut self, mut f: impl FnMut(&NodeEvent) -> Option<T>) -> T {
+        let events = &mut self.events;
+        let wait = async move {
+            loop {
+                match events.recv().await {
+                    Ok(timed) => {
+                        if let Some(value) = f(&timed.event) {
+                            return value;
+                        }
+                    }
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => panic!("node stopped"),
+                }
+            }
+        };
+
+        tokio::time::timeout(TIMEOUT, wait)
+            .await
+            .expect("timed out waiting for an event")
+    }
+}
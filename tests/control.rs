@@ -0,0 +1,58 @@
+//! The control socket of a node.
+
+#![cfg(unix)]
+
+mod common;
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use libp2p_relay_demo::{control, NodeConfig};
+
+/// A socket path in a new directory, removed with its content when dropped.
+fn temp_socket() -> (tempfile::TempDir, PathBuf) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("node.sock");
+    (dir, path)
+}
+
+#[tokio::test]
+async fn bind_keeps_a_file_that_is_not_a_socket() {
+    let (_dir, path) = temp_socket();
+    fs::write(&path, "keep me").unwrap();
+
+    assert!(control::bind(&path).is_err());
+    assert_eq!(fs::read_to_string(&path).unwrap(), "keep me");
+}
+
+#[tokio::test]
+async fn socket_is_private_and_removed_once_the_node_stops() {
+    let (_dir, path) = temp_socket();
+    let node = common::spawn(NodeConfig::default(), false).await;
+
+    let listener = control::bind(&path).unwrap();
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let server = tokio::spawn(control::serve(listener, node.handle.clone()));
+    control::call(&path, "peers", serde_json::json!({}))
+        .await
+        .unwrap();
+
+    node.handle.shutdown().await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("control server stopped")
+        .unwrap();
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn stale_socket_is_replaced() {
+    let (_dir, path) = temp_socket();
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+    control::bind(&path).unwrap();
+}
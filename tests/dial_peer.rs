@@ -0,0 +1,79 @@
+mod common;
+
+use libp2p::PeerId;
+use libp2p_relay_demo::{NodeConfig, NodeEvent};
+
+use common::TestNode;
+
+async fn kad_node(public: bool) -> TestNode {
+    let mut config = NodeConfig::default();
+    config.kad.enabled = true;
+    common::spawn(config, public).await
+}
+
+async fn wait_for_identify(node: &mut TestNode, peer: PeerId) {
+    node.wait_for(|event| match event {
+        NodeEvent::Identified { peer_id, .. } if *peer_id == peer => Some(()),
+        _ => None,
+    })
+    .await;
+}
+
+async fn wait_for_routing(node: &mut TestNode, peer: PeerId) {
+    node.wait_for(|event| match event {
+        NodeEvent::KadRoutingUpdated { peer: updated } if *updated == peer => Some(()),
+        _ => None,
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn through_kad() {
+    let server = kad_node(true).await;
+    let mut target = kad_node(true).await;
+    let mut dialer = kad_node(false).await;
+
+    target.handle.dial(server.addr.clone()).await.unwrap();
+    wait_for_routing(&mut target, server.peer_id).await;
+    dialer.handle.dial(server.addr.clone()).await.unwrap();
+    wait_for_routing(&mut dialer, server.peer_id).await;
+
+    let (_, endpoint) = dialer.handle.dial_peer(target.peer_id).await.unwrap();
+    assert!(!endpoint.is_relayed());
+}
+
+#[tokio::test]
+async fn through_a_relay() {
+    let mut config = NodeConfig::default();
+    config.relay.service = true;
+    let relay = common::spawn(config, true).await;
+
+    let mut config = NodeConfig::default();
+    config.relay.listen_relayed = true;
+    let mut target = common::spawn(config, false).await;
+    target.handle.dial(relay.addr.clone()).await.unwrap();
+    target
+        .wait_for(|event| match event {
+            NodeEvent::ReservationAccepted { .. } => Some(()),
+            _ => None,
+        })
+        .await;
+
+    // the dialer only knows the relay.
+    let mut dialer = common::spawn(NodeConfig::default(), false).await;
+    dialer.handle.dial(relay.addr.clone()).await.unwrap();
+    wait_for_identify(&mut dialer, relay.peer_id).await;
+
+    let (_, endpoint) = dialer.handle.dial_peer(target.peer_id).await.unwrap();
+    assert!(endpoint.is_relayed());
+
+    // an established connection is returned right away.
+    let (_, again) = dialer.handle.dial_peer(target.peer_id).await.unwrap();
+    assert_eq!(again, endpoint);
+}
+
+#[tokio::test]
+async fn unreachable() {
+    let dialer = common::spawn(NodeConfig::default(), false).await;
+    assert!(dialer.handle.dial_peer(PeerId::random()).await.is_err());
+}
@@ -0,0 +1,35 @@
+//! The JSON event stream.
+
+mod common;
+
+use std::time::{Duration, SystemTime};
+
+use libp2p_relay_demo::events::{self, EventRecord, JsonEvent};
+use libp2p_relay_demo::NodeConfig;
+
+#[tokio::test]
+async fn records_are_stamped_when_the_node_emits_them() {
+    let common::TestNode { handle, events, .. } = common::spawn(NodeConfig::default(), false).await;
+    let emitted_before = SystemTime::now();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    handle.shutdown().await.unwrap();
+    drop(handle);
+    let mut out = Vec::new();
+    events::write_json(events, &mut out).await.unwrap();
+
+    let records = String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<EventRecord>(line).unwrap())
+        .collect::<Vec<_>>();
+    let listen = records
+        .iter()
+        .find(|r| matches!(r.event, JsonEvent::NewListenAddr { .. }))
+        .expect("listen address event");
+    let time = humantime::parse_rfc3339(&listen.timestamp).unwrap();
+    assert!(
+        time <= emitted_before,
+        "{time:?} is after {emitted_before:?}"
+    );
+}
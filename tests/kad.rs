@@ -0,0 +1,55 @@
+mod common;
+
+use libp2p_relay_demo::{Error, NodeConfig, NodeEvent};
+
+use common::TestNode;
+
+async fn kad_node() -> TestNode {
+    let mut config = NodeConfig::default();
+    config.kad.enabled = true;
+    common::spawn(config, true).await
+}
+
+/// Connect `node` to `server` and wait until the server is in its routing table.
+async fn join(node: &mut TestNode, server: &TestNode) {
+    node.handle.dial(server.addr.clone()).await.unwrap();
+
+    let server_id = server.peer_id;
+    node.wait_for(|event| match event {
+        NodeEvent::KadRoutingUpdated { peer } if *peer == server_id => Some(()),
+        _ => None,
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn put_and_get_through_a_server() {
+    let server = kad_node().await;
+    let mut writer = kad_node().await;
+    let mut reader = kad_node().await;
+    join(&mut writer, &server).await;
+    join(&mut reader, &server).await;
+
+    writer
+        .handle
+        .kad_put("greeting", b"hello".to_vec(), None)
+        .await
+        .unwrap();
+
+    // the reader only knows the server, which got the record from the writer.
+    let value = reader.handle.kad_get("greeting").await.unwrap();
+    assert_eq!(value, b"hello");
+
+    let missing = reader.handle.kad_get("missing").await;
+    assert!(matches!(missing, Err(Error::Kad(_))), "{missing:?}");
+}
+
+#[tokio::test]
+async fn disabled() {
+    let node = common::spawn(NodeConfig::default(), false).await;
+
+    let put = node.handle.kad_put("key", b"value".to_vec(), None).await;
+    assert!(matches!(put, Err(Error::KadDisabled)), "{put:?}");
+    let get = node.handle.kad_get("key").await;
+    assert!(matches!(get, Err(Error::KadDisabled)), "{get:?}");
+}
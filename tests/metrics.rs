@@ -0,0 +1,34 @@
+//! The Prometheus metrics endpoint of a node.
+
+mod common;
+
+use std::net::SocketAddr;
+
+use libp2p_relay_demo::NodeConfig;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+#[tokio::test]
+async fn metrics_are_served_once_the_node_runs() {
+    let addr: SocketAddr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut config = NodeConfig::default();
+    config.metrics.addr = Some(addr);
+    let _node = common::spawn(config, false).await;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    tokio::time::timeout(common::TIMEOUT, stream.read_to_string(&mut response))
+        .await
+        .expect("timed out reading the metrics")
+        .unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+    assert!(response.contains("relay_demo_relay_reservations_active"));
+}
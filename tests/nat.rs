@@ -0,0 +1,312 @@
+//! DCUtR hole punching between two clients behind simulated NATs.
+
+mod common;
+
+use std::time::Duration;
+
+use futures::future;
+use libp2p::multiaddr::Protocol;
+use libp2p_relay_demo::config::DcutrConfig;
+use libp2p_relay_demo::transport::sim::{NatType, SimNetwork};
+use libp2p_relay_demo::{NatMapping, NodeConfig, NodeEvent};
+
+use common::TestNode;
+
+const DCUTR_PORT: u16 = 4002;
+/// Hole punches attempted per NAT type.
+const ATTEMPTS: usize = 3;
+
+async fn wait_for_reservation(client: &mut TestNode, relay: &TestNode) {
+    let relay_id = relay.peer_id;
+    client
+        .wait_for(|event| match event {
+            NodeEvent::ReservationAccepted { relay_peer_id, .. } if *relay_peer_id == relay_id => {
+                Some(())
+            }
+            _ => None,
+        })
+        .await;
+}
+
+/// A relay and two clients behind a NAT of type `nat`, both holding a reservation with it.
+async fn relayed_clients(nat: NatType, dcutr: DcutrConfig) -> (TestNode, TestNode, TestNode) {
+    let net = SimNetwork::new();
+
+    let mut config = NodeConfig::default();
+    config.relay.service = true;
+    let relay = common::spawn_simulated(net.public_host(), config).await;
+
+    let mut config = NodeConfig::default();
+    config.relay.listen_relayed = true;
+    config.network.dcutr_port = Some(DCUTR_PORT);
+    config.dcutr = dcutr;
+    let mut listener = common::spawn_simulated(net.host_behind_nat(nat), config.clone()).await;
+    let mut dialer = common::spawn_simulated(net.host_behind_nat(nat), config).await;
+
+    listener.handle.dial(relay.addr.clone()).await.unwrap();
+    dialer.handle.dial(relay.addr.clone()).await.unwrap();
+    wait_for_reservation(&mut listener, &relay).await;
+    wait_for_reservation(&mut dialer, &relay).await;
+
+    (relay, listener, dialer)
+}
+
+/// Whether `node` upgraded its relayed connection with `remote` to a direct one.
+///
+/// The side reached through the relay drives the upgrade and reports its outcome.
+async fn wait_for_hole_punch(node: &mut TestNode, remote: &TestNode) -> bool {
+    let remote_id = remote.peer_id;
+    node.wait_for(|event| match event {
+        NodeEvent::HolePunch {
+            remote_peer_id,
+            result,
+        } if *remote_peer_id == remote_id => Some(result.is_ok()),
+        _ => None,
+    })
+    .await
+}
+
+/// Wait for `client` to hold a reservation with `relay` and to have classified its NAT.
+async fn wait_for_reservation_and_mapping(client: &mut TestNode, relay: &TestNode) -> NatMapping {
+    let relay_id = relay.peer_id;
+    let mut reserved = false;
+    let mut mapping = None;
+    client
+        .wait_for(|event| {
+            match event {
+                NodeEvent::ReservationAccepted { relay_peer_id, .. }
+                    if *relay_peer_id == relay_id =>
+                {
+                    reserved = true
+                }
+                NodeEvent::NatMappingChanged { new, .. } => mapping = Some(*new),
+                _ => {}
+            }
+            mapping.filter(|_| reserved)
+        })
+        .await
+}
+
+fn circuit_to(relay: &TestNode, peer: &TestNode) -> libp2p::Multiaddr {
+    relay
+        .addr
+        .clone()
+        .with(Protocol::P2pCircuit)
+        .with(Protocol::P2p(peer.peer_id))
+}
+
+/// Connect two clients behind a NAT of type `nat` through a relay, return whether the
+/// connection was upgraded to a direct one.
+async fn hole_punch(nat: NatType) -> bool {
+    let (relay, mut listener, dialer) = relayed_clients(nat, DcutrConfig::default()).await;
+
+    dialer
+        .handle
+        .dial(circuit_to(&relay, &listener))
+        .await
+        .unwrap();
+
+    wait_for_hole_punch(&mut listener, &dialer).await
+}
+
+#[tokio::test]
+async fn hole_punch_success_rate() {
+    let runs = NatType::ALL
+        .into_iter()
+        .flat_map(|nat| std::iter::repeat_n(nat, ATTEMPTS))
+        .map(|nat| async move { (nat, hole_punch(nat).await) });
+    let results = future::join_all(runs).await;
+
+    let mut rates = Vec::new();
+    let mut failed = false;
+    for nat in NatType::ALL {
+        let succeeded = results
+            .iter()
+            .filter(|(n, success)| *n == nat && *success)
+            .count();
+
+        // both sides predict the other's port, which a symmetric NAT changes per destination.
+        let expected = match nat {
+            NatType::Symmetric => 0,
+            _ => ATTEMPTS,
+        };
+        failed |= succeeded != expected;
+        rates.push(format!(
+            "{nat:?}: {succeeded}/{ATTEMPTS}, expected {expected}"
+        ));
+    }
+
+    eprintln!("hole punch success rates:\n  {}", rates.join("\n  "));
+    assert!(!failed, "a hole punch success rate is not the expected one");
+}
+
+#[tokio::test]
+async fn dial_by_peer_id_upgrades_the_relayed_connection() {
+    let (_relay, mut listener, dialer) =
+        relayed_clients(NatType::FullCone, DcutrConfig::default()).await;
+
+    // the dialer knows the listener by its peer id only.
+    let (_, endpoint) = dialer.handle.dial_peer(listener.peer_id).await.unwrap();
+    assert!(endpoint.is_relayed());
+
+    assert!(wait_for_hole_punch(&mut listener, &dialer).await);
+}
+
+#[tokio::test]
+async fn failed_hole_punch_keeps_the_relayed_connection_and_is_retried() {
+    let dcutr = DcutrConfig {
+        retries: 1,
+        retry_backoff: Duration::from_millis(10),
+        ..Default::default()
+    };
+    let (relay, mut listener, mut dialer) = relayed_clients(NatType::Symmetric, dcutr).await;
+
+    dialer
+        .handle
+        .dial(circuit_to(&relay, &listener))
+        .await
+        .unwrap();
+    assert!(!wait_for_hole_punch(&mut listener, &dialer).await);
+
+    let connections = listener.handle.connections().await.unwrap();
+    assert!(connections[&dialer.peer_id]
+        .values()
+        .any(|endpoint| endpoint.is_relayed()));
+
+    // the retry is a relayed connection the other way round, upgraded by the dialer.
+    assert!(!wait_for_hole_punch(&mut dialer, &listener).await);
+}
+
+#[tokio::test]
+async fn failed_hole_punch_is_not_retried_through_a_relay_without_reservation() {
+    let net = SimNetwork::new();
+    let mut config = NodeConfig::default();
+    config.relay.service = true;
+    let relay = common::spawn_simulated(net.public_host(), config).await;
+
+    let mut config = NodeConfig::default();
+    config.network.dcutr_port = Some(DCUTR_PORT);
+    config.dcutr = DcutrConfig {
+        retries: 1,
+        retry_backoff: Duration::from_millis(10),
+        ..Default::default()
+    };
+    // only the listener holds a reservation.
+    let mut dialer =
+        common::spawn_simulated(net.host_behind_nat(NatType::Symmetric), config.clone()).await;
+    config.relay.listen_relayed = true;
+    let mut listener =
+        common::spawn_simulated(net.host_behind_nat(NatType::Symmetric), config).await;
+
+    listener.handle.dial(relay.addr.clone()).await.unwrap();
+    wait_for_reservation(&mut listener, &relay).await;
+
+    dialer
+        .handle
+        .dial(circuit_to(&relay, &listener))
+        .await
+        .unwrap();
+    assert!(!wait_for_hole_punch(&mut listener, &dialer).await);
+
+    // a retry would be a relayed connection to the dialer, upgraded by the dialer.
+    let retried = tokio::time::timeout(
+        Duration::from_secs(2),
+        wait_for_hole_punch(&mut dialer, &listener),
+    )
+    .await;
+    assert!(retried.is_err());
+
+    let connections = listener.handle.connections().await.unwrap();
+    assert!(connections[&dialer.peer_id]
+        .values()
+        .any(|endpoint| endpoint.is_relayed()));
+}
+
+#[tokio::test]
+async fn failed_hole_punch_report() {
+    let dcutr = DcutrConfig {
+        retries: 0,
+        ..Default::default()
+    };
+    let (relay, mut listener, dialer) = relayed_clients(NatType::Symmetric, dcutr).await;
+
+    dialer
+        .handle
+        .dial(circuit_to(&relay, &listener))
+        .await
+        .unwrap();
+    let dialer_id = dialer.peer_id;
+    let report = listener
+        .wait_for(|event| match event {
+            NodeEvent::HolePunchReport(report) if report.remote_peer_id == dialer_id => {
+                Some(report.clone())
+            }
+            _ => None,
+        })
+        .await;
+
+    assert!(matches!(report.outcome, Some(Err(_))));
+    assert!(!report.local_addrs.is_empty());
+    assert!(!report.remote_addrs.is_empty());
+    // DCUtR dials up to three times before giving up.
+    assert_eq!(report.dials.len(), 3);
+    for dial in &report.dials {
+        assert!(dial.as_listener);
+        assert!(dial.duration.is_some());
+        assert!(dial.error.is_some());
+    }
+
+    let reports = listener
+        .handle
+        .hole_punch_reports(Some(dialer_id))
+        .await
+        .unwrap();
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].dials.len(), 3);
+}
+
+#[tokio::test]
+async fn symmetric_nat_is_punched_through_at_predicted_ports() {
+    let net = SimNetwork::new();
+
+    let mut config = NodeConfig::default();
+    config.relay.service = true;
+    let relay = common::spawn_simulated(net.public_host(), config).await;
+    // telling the NATs apart takes the observations of two peers.
+    let observer = common::spawn_simulated(net.public_host(), NodeConfig::default()).await;
+
+    let mut config = NodeConfig::default();
+    config.relay.listen_relayed = true;
+    config.network.dcutr_port = Some(DCUTR_PORT);
+    config.network.tcp_port_reuse = true;
+    let mut symmetric =
+        common::spawn_simulated(net.host_behind_nat(NatType::Symmetric), config.clone()).await;
+    let mut cone =
+        common::spawn_simulated(net.host_behind_nat(NatType::PortRestricted), config).await;
+
+    for client in [&symmetric, &cone] {
+        client.handle.dial(relay.addr.clone()).await.unwrap();
+        client.handle.dial(observer.addr.clone()).await.unwrap();
+    }
+    assert_eq!(
+        wait_for_reservation_and_mapping(&mut symmetric, &relay).await,
+        NatMapping::Symmetric
+    );
+    assert_eq!(
+        wait_for_reservation_and_mapping(&mut cone, &relay).await,
+        NatMapping::Cone
+    );
+    assert_eq!(
+        symmetric.handle.nat_mapping().await.unwrap(),
+        NatMapping::Symmetric
+    );
+
+    // the port restricted NAT only lets in the ports its host dialed, which must include the one
+    // the symmetric NAT maps the hole punch to.
+    symmetric
+        .handle
+        .dial(circuit_to(&relay, &cone))
+        .await
+        .unwrap();
+    assert!(wait_for_hole_punch(&mut cone, &symmetric).await);
+}
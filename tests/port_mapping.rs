@@ -0,0 +1,134 @@
+//! UPnP and NAT-PMP port mappings requested from a stand-in router of a simulated NAT.
+
+mod common;
+
+use std::time::Duration;
+
+use libp2p::{multiaddr::Protocol, Multiaddr};
+use libp2p_relay_demo::config::{AutonatConfig, PortMappingConfig};
+use libp2p_relay_demo::port_mapping::sim::{GatewayMapping, SimGateway};
+use libp2p_relay_demo::transport::sim::{NatType, SimNetwork};
+use libp2p_relay_demo::{MappingMethod, NodeConfig, NodeEvent, PortProtocol};
+
+use common::SIM_PORT;
+
+const DCUTR_PORT: u16 = 4002;
+
+fn tcp_mapping(port: u16) -> GatewayMapping {
+    GatewayMapping {
+        protocol: PortProtocol::Tcp,
+        external_port: port,
+        internal_port: port,
+    }
+}
+
+/// A client behind a port restricted NAT maps its ports through `port_mapping`, configured for
+/// its router, and is reached by the autonat probes of a public server.
+async fn map_ports(
+    port_mapping: impl FnOnce(&SimGateway) -> PortMappingConfig,
+    method: MappingMethod,
+) {
+    let net = SimNetwork::new();
+    let server = common::spawn_simulated(net.public_host(), NodeConfig::default()).await;
+
+    let host = net.host_behind_nat(NatType::PortRestricted);
+    let public_ip = host.public_ip();
+    let gateway = SimGateway::start(host.clone()).await.unwrap();
+
+    let mut config = NodeConfig::default();
+    config.network.dcutr_port = Some(DCUTR_PORT);
+    config.port_mapping = port_mapping(&gateway);
+    config.autonat = AutonatConfig {
+        boot_delay: Duration::from_secs(1),
+        retry_interval: Duration::from_secs(1),
+        throttle_server_period: Duration::from_secs(1),
+        ..Default::default()
+    };
+    let mut client = common::spawn_simulated(host, config).await;
+
+    client.handle.dial(server.addr.clone()).await.unwrap();
+
+    let expected = Multiaddr::from(public_ip).with(Protocol::Tcp(SIM_PORT));
+    let confirmed = client
+        .wait_for(|event| match event {
+            NodeEvent::PortMappingConfirmed(mapping) => Some(mapping.clone()),
+            _ => None,
+        })
+        .await;
+    assert_eq!(confirmed.external_addr, expected);
+    assert_eq!(confirmed.method, method);
+    assert!(!confirmed.holepunch);
+
+    let mut mappings = gateway.mappings();
+    mappings.sort_by_key(|m| m.external_port);
+    assert_eq!(mappings, [tcp_mapping(SIM_PORT), tcp_mapping(DCUTR_PORT)]);
+
+    let mapped = client.handle.port_mappings().await.unwrap();
+    assert!(mapped
+        .iter()
+        .any(|m| m.holepunch && m.local_port == DCUTR_PORT));
+
+    client.handle.shutdown().await.unwrap();
+    assert!(gateway.mappings().is_empty());
+}
+
+#[tokio::test]
+async fn upnp_mapping_is_confirmed_and_removed_on_shutdown() {
+    map_ports(
+        |gateway| PortMappingConfig {
+            upnp: true,
+            upnp_search_addr: Some(gateway.ssdp_addr()),
+            ..Default::default()
+        },
+        MappingMethod::Upnp,
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn nat_pmp_mapping_is_confirmed_and_removed_on_shutdown() {
+    map_ports(
+        |gateway| PortMappingConfig {
+            nat_pmp: true,
+            nat_pmp_gateway: Some(gateway.nat_pmp_addr()),
+            ..Default::default()
+        },
+        MappingMethod::NatPmp,
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn mapped_port_is_removed_when_another_one_failed() {
+    let net = SimNetwork::new();
+    let host = net.host_behind_nat(NatType::PortRestricted);
+    let gateway = SimGateway::start(host.clone()).await.unwrap();
+    gateway.refuse_port(DCUTR_PORT);
+
+    let mut config = NodeConfig::default();
+    config.network.dcutr_port = Some(DCUTR_PORT);
+    config.port_mapping = PortMappingConfig {
+        upnp: true,
+        upnp_search_addr: Some(gateway.ssdp_addr()),
+        ..Default::default()
+    };
+    let mut client = common::spawn_simulated(host, config).await;
+
+    let (mut mapped, mut failed) = (false, false);
+    client
+        .wait_for(|event| {
+            match event {
+                NodeEvent::PortMapped(mapping) if mapping.local_port == SIM_PORT => mapped = true,
+                NodeEvent::PortMappingFailed { local_port, .. } if *local_port == DCUTR_PORT => {
+                    failed = true
+                }
+                _ => {}
+            }
+            (mapped && failed).then_some(())
+        })
+        .await;
+    assert_eq!(gateway.mappings(), [tcp_mapping(SIM_PORT)]);
+
+    client.handle.shutdown().await.unwrap();
+    assert!(gateway.mappings().is_empty());
+}
@@ -0,0 +1,63 @@
+//! QUIC connections between nodes on the loopback interface.
+
+mod common;
+
+use libp2p::{identity::Keypair, multiaddr::Protocol, Multiaddr};
+use libp2p_relay_demo::{NodeConfig, NodeEvent, RelayNode};
+
+use common::TestNode;
+
+async fn spawn(quic: bool) -> TestNode {
+    let mut config = NodeConfig::default();
+    config.network.ipv6 = false;
+    config.network.quic = quic;
+    common::start(
+        RelayNode::builder(Keypair::generate_ed25519()).config(config),
+        Multiaddr::empty(),
+    )
+    .await
+}
+
+fn is_quic(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| p == Protocol::QuicV1)
+}
+
+#[tokio::test]
+async fn quic_is_only_listened_on_when_enabled() {
+    let mut node = spawn(false).await;
+    let addrs = node.listen_addrs();
+    assert!(!addrs.is_empty());
+    assert!(!addrs.iter().any(is_quic), "{addrs:?}");
+
+    let mut node = spawn(true).await;
+    let addrs = node.listen_addrs();
+    assert!(addrs.iter().any(is_quic), "{addrs:?}");
+}
+
+#[tokio::test]
+async fn dial_quic() {
+    let mut server = spawn(true).await;
+    let addr = server
+        .listen_addrs()
+        .into_iter()
+        .find(|a| is_quic(a) && a.iter().next() == Some(Protocol::Ip4([127, 0, 0, 1].into())))
+        .expect("loopback QUIC address");
+
+    let mut client = spawn(true).await;
+    client
+        .handle
+        .dial(addr.with(Protocol::P2p(server.peer_id)))
+        .await
+        .unwrap();
+
+    let server_id = server.peer_id;
+    let endpoint = client
+        .wait_for(|event| match event {
+            NodeEvent::ConnectionEstablished {
+                peer_id, endpoint, ..
+            } if *peer_id == server_id => Some(endpoint.clone()),
+            _ => None,
+        })
+        .await;
+    assert!(is_quic(endpoint.get_remote_address()));
+}
@@ -0,0 +1,198 @@
+mod common;
+
+use std::time::Duration;
+
+use libp2p::multiaddr::Protocol;
+use libp2p_relay_demo::{NodeConfig, NodeEvent, ReservationState};
+
+use common::TestNode;
+
+async fn relay() -> TestNode {
+    let mut config = NodeConfig::default();
+    config.relay.service = true;
+    common::spawn(config, true).await
+}
+
+/// A client that listens through every relay it connects to.
+async fn client() -> TestNode {
+    let mut config = NodeConfig::default();
+    config.relay.listen_relayed = true;
+    common::spawn(config, false).await
+}
+
+/// Connect `client` to `relay` and wait until both agree on the reservation.
+async fn reserve(relay: &mut TestNode, client: &mut TestNode) {
+    client.handle.dial(relay.addr.clone()).await.unwrap();
+
+    let relay_id = relay.peer_id;
+    let renewal = client
+        .wait_for(|event| match event {
+            NodeEvent::ReservationAccepted {
+                relay_peer_id,
+                renewal,
+                ..
+            } if *relay_peer_id == relay_id => Some(*renewal),
+            _ => None,
+        })
+        .await;
+    assert!(!renewal);
+
+    let client_id = client.peer_id;
+    let renewed = relay
+        .wait_for(|event| match event {
+            NodeEvent::ReservationGranted { peer_id, renewed } if *peer_id == client_id => {
+                Some(*renewed)
+            }
+            _ => None,
+        })
+        .await;
+    assert!(!renewed);
+}
+
+#[tokio::test]
+async fn reservation() {
+    let mut relay = relay().await;
+    let mut client = client().await;
+
+    reserve(&mut relay, &mut client).await;
+
+    let relayed = relay.addr.clone().with(Protocol::P2pCircuit);
+    client
+        .wait_for(|event| match event {
+            NodeEvent::NewListenAddr { address, .. }
+                if address.to_string().starts_with(&relayed.to_string()) =>
+            {
+                Some(())
+            }
+            _ => None,
+        })
+        .await;
+}
+
+#[tokio::test]
+async fn circuit_between_relayed_clients() {
+    let mut relay = relay().await;
+    let mut a = client().await;
+    let mut b = client().await;
+    reserve(&mut relay, &mut a).await;
+    reserve(&mut relay, &mut b).await;
+
+    let circuit = relay
+        .addr
+        .clone()
+        .with(Protocol::P2pCircuit)
+        .with(Protocol::P2p(a.peer_id));
+    b.handle.dial(circuit).await.unwrap();
+
+    let a_id = a.peer_id;
+    let connection_id = b
+        .wait_for(|event| match event {
+            NodeEvent::ConnectionEstablished {
+                peer_id,
+                connection_id,
+                endpoint,
+            } if *peer_id == a_id => Some((endpoint.is_relayed(), *connection_id)),
+            _ => None,
+        })
+        .await;
+    let (relayed, connection_id) = connection_id;
+    assert!(relayed, "b reached a directly");
+
+    let b_id = b.peer_id;
+    let relayed = a
+        .wait_for(|event| match event {
+            NodeEvent::ConnectionEstablished {
+                peer_id, endpoint, ..
+            } if *peer_id == b_id => Some(endpoint.is_relayed()),
+            _ => None,
+        })
+        .await;
+    assert!(relayed, "a was reached directly");
+
+    let connections = b.handle.connections().await.unwrap();
+    assert!(connections[&a_id].contains_key(&connection_id));
+
+    // closing the circuit on one end closes it on the other one.
+    assert!(b.handle.close_connection(connection_id).await.unwrap());
+    b.wait_for(|event| match event {
+        NodeEvent::ConnectionClosed {
+            connection_id: closed,
+            ..
+        } if *closed == connection_id => Some(()),
+        _ => None,
+    })
+    .await;
+    a.wait_for(|event| match event {
+        NodeEvent::ConnectionClosed {
+            peer_id, endpoint, ..
+        } if *peer_id == b_id && endpoint.is_relayed() => Some(()),
+        _ => None,
+    })
+    .await;
+
+    let connections = b.handle.connections().await.unwrap();
+    assert!(!connections.contains_key(&a_id));
+    assert!(!b.handle.close_connection(connection_id).await.unwrap());
+}
+
+#[tokio::test]
+async fn remove_relayed_listener() {
+    let mut relay = relay().await;
+    // listens through the relay on request only.
+    let mut client = common::spawn(NodeConfig::default(), false).await;
+
+    let listener_id = client
+        .handle
+        .listen(relay.addr.clone().with(Protocol::P2pCircuit))
+        .await
+        .unwrap();
+    let accepted = client
+        .wait_for(|event| match event {
+            NodeEvent::ReservationAccepted {
+                listener_id: Some(id),
+                ..
+            } => Some(*id),
+            _ => None,
+        })
+        .await;
+    assert_eq!(accepted, listener_id);
+
+    let listeners = client.handle.relayed_listeners().await.unwrap();
+    assert_eq!(listeners.len(), 1);
+    assert_eq!(listeners[0].listener_id, listener_id);
+    assert_eq!(listeners[0].relay_peer_id, relay.peer_id);
+    assert_eq!(listeners[0].state, ReservationState::Accepted);
+    assert_eq!(listeners[0].renewals, 0);
+
+    assert!(client.handle.remove_listener(listener_id).await.unwrap());
+    client
+        .wait_for(|event| match event {
+            NodeEvent::ListenerClosed {
+                listener_id: closed,
+                error: None,
+                ..
+            } if *closed == listener_id => Some(()),
+            _ => None,
+        })
+        .await;
+    assert!(client.handle.relayed_listeners().await.unwrap().is_empty());
+
+    // a listener of the relay manager is not replaced once removed.
+    let mut managed = self::client().await;
+    reserve(&mut relay, &mut managed).await;
+    let listeners = managed.handle.relayed_listeners().await.unwrap();
+    assert_eq!(listeners.len(), 1);
+    let listener_id = listeners[0].listener_id;
+
+    assert!(managed.handle.remove_listener(listener_id).await.unwrap());
+    // past the backoff of a failed relay and the next tick of the manager.
+    tokio::time::sleep(Duration::from_secs(40)).await;
+    assert!(managed.handle.relayed_listeners().await.unwrap().is_empty());
+    while let Ok(timed) = managed.events.try_recv() {
+        assert!(
+            !matches!(timed.event, NodeEvent::ReservationAccepted { .. }),
+            "{:?}",
+            timed.event
+        );
+    }
+}
@@ -0,0 +1,73 @@
+mod common;
+
+use libp2p::PeerId;
+use libp2p_relay_demo::{NodeConfig, NodeEvent};
+
+use common::TestNode;
+
+async fn relay(kad: bool) -> TestNode {
+    let mut config = NodeConfig::default();
+    config.relay.service = true;
+    config.kad.enabled = kad;
+    common::spawn(config, true).await
+}
+
+async fn client(relayed_listeners: usize, kad: bool) -> TestNode {
+    let mut config = NodeConfig::default();
+    config.relay.listen_relayed = true;
+    config.relay.relayed_listeners = relayed_listeners;
+    config.kad.enabled = kad;
+    common::spawn(config, false).await
+}
+
+async fn next_reservation(client: &mut TestNode) -> PeerId {
+    client
+        .wait_for(|event| match event {
+            NodeEvent::ReservationAccepted {
+                relay_peer_id,
+                renewal: false,
+                ..
+            } => Some(*relay_peer_id),
+            _ => None,
+        })
+        .await
+}
+
+#[tokio::test]
+async fn replaces_a_lost_relay() {
+    let mut relays = vec![relay(false).await, relay(false).await];
+    let mut client = client(1, false).await;
+    for relay in &relays {
+        client.handle.dial(relay.addr.clone()).await.unwrap();
+    }
+
+    let first = next_reservation(&mut client).await;
+
+    // a node stops once its last handle is dropped.
+    relays.retain(|r| r.peer_id != first);
+    let second = next_reservation(&mut client).await;
+    assert_ne!(first, second);
+    assert_eq!(second, relays[0].peer_id);
+}
+
+#[tokio::test]
+async fn finds_relays_through_kad() {
+    let mut server_config = NodeConfig::default();
+    server_config.kad.enabled = true;
+    let server = common::spawn(server_config, true).await;
+
+    let mut relay = relay(true).await;
+    relay.handle.dial(server.addr.clone()).await.unwrap();
+    let server_id = server.peer_id;
+    relay
+        .wait_for(|event| match event {
+            NodeEvent::KadRoutingUpdated { peer } if *peer == server_id => Some(()),
+            _ => None,
+        })
+        .await;
+
+    let mut client = client(1, true).await;
+    client.handle.dial(server.addr.clone()).await.unwrap();
+
+    assert_eq!(next_reservation(&mut client).await, relay.peer_id);
+}
@@ -0,0 +1,256 @@
+//! Translation of the addresses observed by peers to the hole punch listener.
+
+use std::collections::VecDeque;
+use std::net::Ipv6Addr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::{fmt, io};
+
+use futures::future;
+use libp2p::core::transport::{ListenerId, TransportError, TransportEvent};
+use libp2p::{multiaddr::Protocol, Multiaddr, Transport};
+use libp2p_relay_demo::transport::sim::{NatType, SimNetwork, SimTransport};
+use libp2p_relay_demo::transport::{HolePunchAddrs, HolePunchTransport};
+
+const DCUTR_PORT: u16 = 4002;
+
+type Trans = HolePunchTransport<SimTransport>;
+
+/// A hole punch transport of a host behind a NAT, with its listen address.
+async fn listening(public: bool) -> (Trans, Multiaddr) {
+    let host = SimNetwork::new().host_behind_nat(NatType::FullCone);
+    let addrs = HolePunchAddrs::default().public(public);
+    let mut trans = HolePunchTransport::with_transport(
+        SimTransport::new(host.clone()).port_reuse(true),
+        addrs.clone(),
+    );
+
+    let addr = Multiaddr::from(host.ip()).with(Protocol::Tcp(DCUTR_PORT));
+    addrs.claim_listen_addr(&addr);
+    let id = ListenerId::next();
+    trans.listen_on(id, addr).unwrap();
+    assert!(addrs.is_listener(id));
+    let listen_addr = future::poll_fn(|cx| Pin::new(&mut trans).poll(cx))
+        .await
+        .into_new_address()
+        .expect("listen address");
+
+    (trans, listen_addr)
+}
+
+/// A transport that listens on any address without binding it, and never connects.
+///
+/// The simulated network has no IPv6.
+#[derive(Default)]
+struct Unbound {
+    events: VecDeque<TransportEvent<future::Pending<io::Result<()>>, io::Error>>,
+}
+
+impl fmt::Debug for Unbound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Unbound")
+    }
+}
+
+impl Transport for Unbound {
+    type Output = ();
+    type Error = io::Error;
+    type ListenerUpgrade = future::Pending<io::Result<()>>;
+    type Dial = future::Pending<io::Result<()>>;
+
+    fn listen_on(
+        &mut self,
+        listener_id: ListenerId,
+        listen_addr: Multiaddr,
+    ) -> Result<(), TransportError<io::Error>> {
+        self.events.push_back(TransportEvent::NewAddress {
+            listener_id,
+            listen_addr,
+        });
+        Ok(())
+    }
+
+    fn remove_listener(&mut self, _: ListenerId) -> bool {
+        false
+    }
+
+    fn dial(&mut self, addr: Multiaddr) -> Result<Self::Dial, TransportError<io::Error>> {
+        Err(TransportError::MultiaddrNotSupported(addr))
+    }
+
+    fn dial_as_listener(
+        &mut self,
+        addr: Multiaddr,
+    ) -> Result<Self::Dial, TransportError<io::Error>> {
+        Err(TransportError::MultiaddrNotSupported(addr))
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<TransportEvent<Self::ListenerUpgrade, io::Error>> {
+        match self.events.pop_front() {
+            Some(event) => Poll::Ready(event),
+            None => Poll::Pending,
+        }
+    }
+
+    fn address_translation(&self, _: &Multiaddr, _: &Multiaddr) -> Option<Multiaddr> {
+        None
+    }
+}
+
+/// A hole punch transport listening on a global IPv6 address, with its listen address.
+async fn listening_ipv6(public: bool) -> (HolePunchTransport<Unbound>, Multiaddr) {
+    let addrs = HolePunchAddrs::default().public(public);
+    let mut trans = HolePunchTransport::with_transport(Unbound::default(), addrs.clone());
+
+    let addr = tcp6("2001:db8::2", DCUTR_PORT);
+    addrs.claim_listen_addr(&addr);
+    trans.listen_on(ListenerId::next(), addr).unwrap();
+    let listen_addr = future::poll_fn(|cx| Pin::new(&mut trans).poll(cx))
+        .await
+        .into_new_address()
+        .expect("listen address");
+
+    (trans, listen_addr)
+}
+
+fn tcp(ip: [u8; 4], port: u16) -> Multiaddr {
+    Multiaddr::from(std::net::Ipv4Addr::from(ip)).with(Protocol::Tcp(port))
+}
+
+fn tcp6(ip: &str, port: u16) -> Multiaddr {
+    Multiaddr::from(ip.parse::<Ipv6Addr>().unwrap()).with(Protocol::Tcp(port))
+}
+
+#[tokio::test]
+async fn only_tcp_through_the_listener() {
+    let (trans, listen) = listening(false).await;
+
+    assert_eq!(
+        trans.address_translation(&listen, &tcp([198, 51, 100, 1], 50000)),
+        Some(tcp([198, 51, 100, 1], DCUTR_PORT))
+    );
+
+    let quic = Multiaddr::from(std::net::Ipv4Addr::new(198, 51, 100, 1))
+        .with(Protocol::Udp(50000))
+        .with(Protocol::QuicV1);
+    assert_eq!(trans.address_translation(&listen, &quic), None);
+
+    // another listener on the same port, e.g. on another interface.
+    let other = tcp([192, 168, 1, 2], DCUTR_PORT);
+    assert_eq!(
+        trans.address_translation(&other, &tcp([198, 51, 100, 1], 50000)),
+        None
+    );
+
+    let loopback = tcp([127, 0, 0, 1], 50000);
+    assert_eq!(trans.address_translation(&listen, &loopback), None);
+
+    let ipv6 = tcp6("2001:db8::1", 50000);
+    assert_eq!(trans.address_translation(&listen, &ipv6), None);
+}
+
+#[tokio::test]
+async fn conflicting_ips_need_several_observations() {
+    let (trans, listen) = listening(false).await;
+    let translated = |ip, port| trans.address_translation(&listen, &tcp(ip, port));
+
+    assert!(translated([198, 51, 100, 1], 50000).is_some());
+    // a second external IP is not trusted from a single connection.
+    assert_eq!(translated([203, 0, 113, 7], 50001), None);
+    assert_eq!(
+        translated([203, 0, 113, 7], 50002),
+        Some(tcp([203, 0, 113, 7], DCUTR_PORT))
+    );
+    // unless its port is the one of the listener, which the NAT kept.
+    assert_eq!(
+        translated([192, 0, 2, 9], DCUTR_PORT),
+        Some(tcp([192, 0, 2, 9], DCUTR_PORT))
+    );
+}
+
+#[tokio::test]
+async fn conflicting_ipv6_addresses_need_several_observations() {
+    let (trans, listen) = listening_ipv6(false).await;
+    let translated = |ip, port| trans.address_translation(&listen, &tcp6(ip, port));
+
+    assert_eq!(
+        translated("2001:db8::1", 50000),
+        Some(tcp6("2001:db8::1", DCUTR_PORT))
+    );
+    assert_eq!(translated("2001:db8:1::7", 50001), None);
+    assert_eq!(
+        translated("2001:db8:1::7", 50002),
+        Some(tcp6("2001:db8:1::7", DCUTR_PORT))
+    );
+}
+
+#[tokio::test]
+async fn public_mode_ignores_private_addresses() {
+    let (trans, listen) = listening(true).await;
+
+    assert_eq!(
+        trans.address_translation(&listen, &tcp([10, 0, 0, 5], 50000)),
+        None
+    );
+    assert!(trans
+        .address_translation(&listen, &tcp([198, 51, 100, 1], 50000))
+        .is_some());
+
+    let (trans, listen) = listening(false).await;
+    assert!(trans
+        .address_translation(&listen, &tcp([10, 0, 0, 5], 50000))
+        .is_some());
+}
+
+#[tokio::test]
+async fn public_mode_ignores_private_ipv6_addresses() {
+    let (trans, listen) = listening_ipv6(true).await;
+
+    // unique local, fc00::/7, and link-local, fe80::/10.
+    for private in ["fd12:3456::5", "fc00::5", "fe80::5", "febf::5"] {
+        assert_eq!(
+            trans.address_translation(&listen, &tcp6(private, 50000)),
+            None,
+            "{private}"
+        );
+    }
+    assert!(trans
+        .address_translation(&listen, &tcp6("2001:db8::1", 50000))
+        .is_some());
+
+    for private in ["fd12:3456::5", "fe80::5"] {
+        let (trans, listen) = listening_ipv6(false).await;
+        assert!(
+            trans
+                .address_translation(&listen, &tcp6(private, 50000))
+                .is_some(),
+            "{private}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn only_claimed_addresses_are_listened_on() {
+    let host = SimNetwork::new().host_behind_nat(NatType::FullCone);
+    let addrs = HolePunchAddrs::default();
+    let mut trans = HolePunchTransport::with_transport(
+        SimTransport::new(host.clone()).port_reuse(true),
+        addrs.clone(),
+    );
+    let holepunch = Multiaddr::from(host.ip()).with(Protocol::Tcp(DCUTR_PORT));
+    let other = Multiaddr::from(host.ip()).with(Protocol::Tcp(DCUTR_PORT + 1));
+
+    let id = ListenerId::next();
+    assert!(trans.listen_on(id, holepunch.clone()).is_err());
+    assert!(!addrs.is_listener(id));
+
+    addrs.claim_listen_addr(&holepunch);
+    let id = ListenerId::next();
+    assert!(trans.listen_on(id, other).is_err());
+    assert!(!addrs.is_listener(id));
+    trans.listen_on(id, holepunch).unwrap();
+    assert!(addrs.is_listener(id));
+}
@@ -0,0 +1,57 @@
+//! WebRTC-direct connections between nodes on the loopback interface.
+
+mod common;
+
+use libp2p::{identity::Keypair, multiaddr::Protocol, Multiaddr};
+use libp2p_relay_demo::{NodeConfig, NodeEvent, RelayNode};
+
+use common::TestNode;
+
+async fn spawn() -> TestNode {
+    let mut config = NodeConfig::default();
+    config.network.ipv6 = false;
+    config.webrtc.port = Some(0);
+    common::start(
+        RelayNode::builder(Keypair::generate_ed25519()).config(config),
+        Multiaddr::empty(),
+    )
+    .await
+}
+
+#[tokio::test]
+async fn dial_webrtc_direct() {
+    let mut server = spawn().await;
+    let addr = server
+        .listen_addrs()
+        .into_iter()
+        .find(|a| {
+            a.iter().any(|p| p == Protocol::WebRTCDirect)
+                && a.iter().next() == Some(Protocol::Ip4([127, 0, 0, 1].into()))
+        })
+        .expect("loopback WebRTC address");
+    assert!(
+        matches!(addr.iter().last(), Some(Protocol::Certhash(_))),
+        "{addr}"
+    );
+
+    let mut client = spawn().await;
+    client
+        .handle
+        .dial(addr.with(Protocol::P2p(server.peer_id)))
+        .await
+        .unwrap();
+
+    let server_id = server.peer_id;
+    let endpoint = client
+        .wait_for(|event| match event {
+            NodeEvent::ConnectionEstablished {
+                peer_id, endpoint, ..
+            } if *peer_id == server_id => Some(endpoint.clone()),
+            _ => None,
+        })
+        .await;
+    assert!(endpoint
+        .get_remote_address()
+        .iter()
+        .any(|p| p == Protocol::WebRTCDirect));
+}
@@ -0,0 +1,180 @@
+//! WebSocket connections between nodes on the loopback interface.
+
+mod common;
+
+use std::net::Ipv4Addr;
+
+use libp2p::{identity::Keypair, multiaddr::Protocol, Multiaddr};
+use libp2p_relay_demo::{NodeConfig, NodeEvent, RelayNode, TimedEvent};
+
+/// A TCP port no listener is bound to.
+fn free_port() -> u16 {
+    std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn loopback_config() -> NodeConfig {
+    let mut config = NodeConfig::default();
+    config.network.ipv6 = false;
+    config
+}
+
+#[tokio::test]
+async fn dial_websocket_by_host_name() {
+    let port = free_port();
+    let mut config = loopback_config();
+    config.websocket.port = Some(port);
+    let server = RelayNode::builder(Keypair::generate_ed25519())
+        .config(config)
+        .spawn()
+        .await
+        .unwrap();
+
+    let client = RelayNode::builder(Keypair::generate_ed25519())
+        .config(loopback_config())
+        .spawn()
+        .await
+        .unwrap();
+    let mut events = client.subscribe();
+
+    let addr = Multiaddr::empty()
+        .with(Protocol::Dns("localhost".into()))
+        .with(Protocol::Tcp(port))
+        .with(Protocol::Ws("/".into()))
+        .with(Protocol::P2p(server.local_peer_id()));
+    client.dial(addr).await.unwrap();
+
+    let server_id = server.local_peer_id();
+    let connected = async {
+        loop {
+            match events.recv().await {
+                Ok(TimedEvent {
+                    event:
+                        NodeEvent::ConnectionEstablished {
+                            peer_id, endpoint, ..
+                        },
+                    ..
+                }) if peer_id == server_id => return endpoint,
+                Ok(_) => {}
+                Err(e) => panic!("node events: {e}"),
+            }
+        }
+    };
+    let endpoint = tokio::time::timeout(common::TIMEOUT, connected)
+        .await
+        .expect("timed out waiting for the connection");
+    assert!(endpoint
+        .get_remote_address()
+        .iter()
+        .any(|p| matches!(p, Protocol::Ws(_))));
+}
+
+#[tokio::test]
+async fn circuit_through_a_websocket_relay() {
+    let port = free_port();
+    let ws_addr = Multiaddr::from(Ipv4Addr::LOCALHOST)
+        .with(Protocol::Tcp(port))
+        .with(Protocol::Ws("/".into()));
+    let mut config = loopback_config();
+    config.websocket.port = Some(port);
+    config.relay.service = true;
+    config.network.external_addrs = vec![ws_addr.clone()];
+    let mut relay = common::start(
+        RelayNode::builder(Keypair::generate_ed25519()).config(config),
+        ws_addr,
+    )
+    .await;
+
+    let mut config = loopback_config();
+    config.relay.listen_relayed = true;
+    let mut client = common::start(
+        RelayNode::builder(Keypair::generate_ed25519()).config(config),
+        Multiaddr::empty(),
+    )
+    .await;
+    client.handle.dial(relay.addr.clone()).await.unwrap();
+
+    let relay_id = relay.peer_id;
+    client
+        .wait_for(|event| match event {
+            NodeEvent::ReservationAccepted { relay_peer_id, .. } if *relay_peer_id == relay_id => {
+                Some(())
+            }
+            _ => None,
+        })
+        .await;
+    let client_id = client.peer_id;
+    relay
+        .wait_for(|event| match event {
+            NodeEvent::ReservationGranted { peer_id, .. } if *peer_id == client_id => Some(()),
+            _ => None,
+        })
+        .await;
+
+    let mut dialer = common::start(
+        RelayNode::builder(Keypair::generate_ed25519()).config(loopback_config()),
+        Multiaddr::empty(),
+    )
+    .await;
+    let circuit = relay
+        .addr
+        .clone()
+        .with(Protocol::P2pCircuit)
+        .with(Protocol::P2p(client_id));
+    dialer.handle.dial(circuit).await.unwrap();
+
+    let endpoint = dialer
+        .wait_for(|event| match event {
+            NodeEvent::ConnectionEstablished {
+                peer_id, endpoint, ..
+            } if *peer_id == client_id => Some(endpoint.clone()),
+            _ => None,
+        })
+        .await;
+    assert!(endpoint.is_relayed());
+    assert!(endpoint
+        .get_remote_address()
+        .iter()
+        .any(|p| matches!(p, Protocol::Ws(_))));
+}
+
+#[tokio::test]
+async fn websocket_tls_listener() {
+    let dir = tempfile::tempdir().unwrap();
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let cert_path = dir.path().join("cert.pem");
+    let key_path = dir.path().join("key.pem");
+    std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+    std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+
+    let port = free_port();
+    let mut config = loopback_config();
+    config.websocket.tls_port = Some(port);
+    config.websocket.cert = Some(cert_path);
+    config.websocket.key = Some(key_path);
+    let mut node = common::start(
+        RelayNode::builder(Keypair::generate_ed25519()).config(config),
+        Multiaddr::empty(),
+    )
+    .await;
+
+    let address = node
+        .wait_for(|event| match event {
+            NodeEvent::NewListenAddr { address, .. }
+                if address
+                    .iter()
+                    .any(|p| matches!(p, Protocol::Tcp(p) if p == port)) =>
+            {
+                Some(address.clone())
+            }
+            _ => None,
+        })
+        .await;
+    assert!(
+        address.iter().any(|p| matches!(p, Protocol::Wss(_))),
+        "{address}"
+    );
+}